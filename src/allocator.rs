@@ -0,0 +1,224 @@
+//! Kernel heap: a first-fit free-list allocator over a static region
+//!
+//! Free blocks are kept in address order and merged with their neighbours
+//! when freed, so the heap doesn't fragment into pieces too small to use.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::constants::heap::HEAP_SIZE;
+use crate::{logln, println};
+
+#[repr(C, align(16))]
+struct HeapRegion([u8; HEAP_SIZE]);
+
+static mut HEAP: HeapRegion = HeapRegion([0; HEAP_SIZE]);
+
+/// Header written at the start of every free block
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Smallest block we can track; allocations are rounded up to this
+const MIN_BLOCK: usize = mem::size_of::<FreeBlock>();
+
+struct FreeList {
+    head: *mut FreeBlock,
+}
+
+// The list only points into HEAP, which is only touched through the Mutex
+unsafe impl Send for FreeList {}
+
+pub struct Allocator {
+    free: Mutex<FreeList>,
+}
+
+#[global_allocator]
+static ALLOCATOR: Allocator = Allocator {
+    free: Mutex::new(FreeList { head: ptr::null_mut() }),
+};
+
+/// Bytes handed out (after rounding to block size) and the most ever
+/// outstanding at once. Atomics so the shell can read them while an
+/// interrupt handler allocates.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub total: usize,
+    pub allocated: usize,
+    pub peak: usize,
+}
+
+pub fn stats() -> HeapStats {
+    HeapStats {
+        total: HEAP_SIZE,
+        allocated: ALLOCATED.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed),
+    }
+}
+
+/// Hand the heap region to the allocator; must run before anything allocates
+pub fn init() {
+    let start = ptr::addr_of_mut!(HEAP) as usize;
+    unsafe { ALLOCATOR.free.lock().insert(start, HEAP_SIZE) };
+    logln!("DEBUG: Heap initialized ({} KiB)", HEAP_SIZE / 1024);
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Block size and alignment actually used for `layout`, so every block
+/// (used or free) can hold a `FreeBlock` header
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let align = layout.align().max(mem::align_of::<FreeBlock>());
+    let size = align_up(layout.size().max(MIN_BLOCK), mem::align_of::<FreeBlock>());
+    (size, align)
+}
+
+/// Where in the free block `start..end` an allocation would go, if it fits.
+/// Leftovers on either side must be empty or big enough to stay on the list.
+fn fit(start: usize, end: usize, size: usize, align: usize) -> Option<usize> {
+    let mut alloc_start = align_up(start, align);
+    if alloc_start != start && alloc_start - start < MIN_BLOCK {
+        alloc_start = align_up(start + MIN_BLOCK, align);
+    }
+    let alloc_end = alloc_start.checked_add(size)?;
+    if alloc_end > end {
+        return None;
+    }
+    let tail = end - alloc_end;
+    if tail != 0 && tail < MIN_BLOCK {
+        return None;
+    }
+    Some(alloc_start)
+}
+
+impl FreeList {
+    /// Return `addr..addr + size` to the list, merging with adjacent blocks
+    unsafe fn insert(&mut self, addr: usize, mut size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        if !next.is_null() && addr + size == next as usize {
+            size += (*next).size;
+            next = (*next).next;
+        }
+        if !prev.is_null() && prev as usize + (*prev).size == addr {
+            (*prev).size += size;
+            (*prev).next = next;
+            return;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+        if prev.is_null() {
+            self.head = block;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// Carve `size` bytes aligned to `align` out of the first block that fits
+    unsafe fn allocate(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null() {
+            let start = current as usize;
+            let end = start + (*current).size;
+            let next = (*current).next;
+
+            if let Some(alloc_start) = fit(start, end, size, align) {
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if alloc_start > start {
+                    self.insert(start, alloc_start - start);
+                }
+                let alloc_end = alloc_start + size;
+                if alloc_end < end {
+                    self.insert(alloc_end, end - alloc_end);
+                }
+                return alloc_start as *mut u8;
+            }
+
+            prev = current;
+            current = next;
+        }
+        ptr::null_mut()
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        let ptr = self.free.lock().allocate(size, align);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        self.free.lock().insert(ptr as usize, size);
+        ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    println!("ALLOCATION ERROR: {:?}", layout);
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use crate::constants::heap::HEAP_SIZE;
+
+    #[test_case]
+    fn box_allocation() {
+        let a = Box::new(41);
+        let b = Box::new(13);
+        assert_eq!(*a, 41);
+        assert_eq!(*b, 13);
+    }
+
+    #[test_case]
+    fn large_vec() {
+        let n = 1000;
+        let mut vec = Vec::new();
+        for i in 0..n {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+
+    #[test_case]
+    fn freed_memory_is_reused() {
+        // Twice what the heap holds in total, so this only passes if frees
+        // are merged back into usable blocks
+        for i in 0..2 * HEAP_SIZE / core::mem::size_of::<usize>() {
+            let x = Box::new(i);
+            assert_eq!(*x, i);
+        }
+        let big = Vec::<u8>::with_capacity(HEAP_SIZE / 2);
+        assert_eq!(big.capacity(), HEAP_SIZE / 2);
+    }
+}
@@ -0,0 +1,131 @@
+//! ATA PIO driver for the master drive on the primary channel (28-bit LBA)
+
+use alloc::format;
+use x86_64::instructions::port::Port;
+use crate::constants::ata::*;
+use crate::shell::{register_command, HexLine, RegisterError, ShellError};
+
+/// Status polls before giving up on the drive
+const TIMEOUT_POLLS: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtaError {
+    /// Nothing is attached (the status register floats)
+    NoDrive,
+    /// The drive stayed busy or never asked for data
+    Timeout,
+    /// The drive set ERR or DF; holds the error register
+    DeviceError(u8),
+    /// `buf` can't hold `count` sectors
+    BufferTooSmall,
+    /// The address doesn't fit in 28 bits
+    LbaOutOfRange,
+}
+
+fn register(offset: u16) -> Port<u8> {
+    Port::new(PRIMARY_IO_BASE + offset)
+}
+
+fn status() -> u8 {
+    unsafe { register(REG_STATUS).read() }
+}
+
+/// Give the drive the 400ns it needs after a drive select or command by
+/// reading the alternate status register a few times
+fn delay_400ns() {
+    let mut alt_status: Port<u8> = Port::new(PRIMARY_CONTROL);
+    for _ in 0..4 {
+        unsafe { alt_status.read() };
+    }
+}
+
+/// Wait for BSY to clear, then for DRQ (data ready) or an error
+fn wait_for_data() -> Result<(), AtaError> {
+    for _ in 0..TIMEOUT_POLLS {
+        let status = status();
+        if status == 0xFF {
+            return Err(AtaError::NoDrive);
+        }
+        if status & STATUS_BSY != 0 {
+            core::hint::spin_loop();
+            continue;
+        }
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            let error = unsafe { register(REG_ERROR).read() };
+            return Err(AtaError::DeviceError(error));
+        }
+        if status & STATUS_DRQ != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(AtaError::Timeout)
+}
+
+/// Read `count` sectors starting at `lba` into the front of `buf`
+pub fn read_sectors(lba: u32, count: u8, buf: &mut [u8]) -> Result<(), AtaError> {
+    if lba > MAX_LBA || lba + count as u32 > MAX_LBA + 1 {
+        return Err(AtaError::LbaOutOfRange);
+    }
+    if buf.len() < count as usize * SECTOR_SIZE {
+        return Err(AtaError::BufferTooSmall);
+    }
+    if count == 0 {
+        // The drive would take a count of 0 to mean 256
+        return Ok(());
+    }
+
+    unsafe {
+        register(REG_DRIVE_HEAD).write(DRIVE_MASTER_LBA | ((lba >> 24) & 0x0F) as u8);
+    }
+    delay_400ns();
+    if status() == 0xFF || status() == 0 {
+        return Err(AtaError::NoDrive);
+    }
+
+    unsafe {
+        register(REG_SECTOR_COUNT).write(count);
+        register(REG_LBA_LOW).write(lba as u8);
+        register(REG_LBA_MID).write((lba >> 8) as u8);
+        register(REG_LBA_HIGH).write((lba >> 16) as u8);
+        register(REG_COMMAND).write(CMD_READ_SECTORS);
+    }
+    delay_400ns();
+
+    let mut data: Port<u16> = Port::new(PRIMARY_IO_BASE + REG_DATA);
+    for sector in buf.chunks_exact_mut(SECTOR_SIZE).take(count as usize) {
+        wait_for_data()?;
+        for word in sector.chunks_exact_mut(2) {
+            let value = unsafe { data.read() };
+            word.copy_from_slice(&value.to_le_bytes());
+        }
+        delay_400ns();
+    }
+    Ok(())
+}
+
+/// Register the disk commands with the shell
+pub fn init() -> Result<(), RegisterError> {
+    register_command("readsec", "Hexdump a disk sector", "readsec <lba>", cmd_readsec)
+}
+
+fn cmd_readsec(args: &[&str]) -> Result<(), ShellError> {
+    let lba = match args.first().map(|arg| arg.parse::<u32>()) {
+        None => 0,
+        Some(Ok(lba)) => lba,
+        Some(Err(_)) => return Err(ShellError::Usage),
+    };
+
+    let mut sector = [0u8; SECTOR_SIZE];
+    if let Err(err) = read_sectors(lba, 1, &mut sector) {
+        return Err(ShellError::Failed(format!("{:?}", err)));
+    }
+
+    let mut pager = crate::pager::Pager::new();
+    for (i, chunk) in sector.chunks(16).enumerate() {
+        if !pager.line(format_args!("{}", HexLine { offset: i * 16, bytes: chunk })) {
+            break;
+        }
+    }
+    Ok(())
+}
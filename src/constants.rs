@@ -1,5 +1,13 @@
 /// System-wide constants to avoid magic numbers
 
+/// OS identity, shared by the boot banner and `uname`/`version`
+pub mod system {
+    pub const OS_NAME: &str = "DxOS";
+    pub const VERSION: &str = "0.2";
+    /// Target spec the kernel is built for (x86_64-blog_os.json)
+    pub const BUILD_TARGET: &str = "x86_64-blog_os";
+}
+
 /// VGA text mode constants
 pub mod vga {
     /// VGA text buffer physical address
@@ -9,6 +17,12 @@ pub mod vga {
     pub const BUFFER_HEIGHT: usize = 25;
     pub const BUFFER_WIDTH: usize = 80;
 
+    /// Bottom row reserved for the status bar, kept out of the scroll region
+    pub const STATUS_ROW: usize = BUFFER_HEIGHT - 1;
+
+    /// Number of virtual consoles (switched with Alt+F1..F4)
+    pub const NUM_CONSOLES: usize = 4;
+
     /// VGA control ports
     pub const COMMAND_PORT: u16 = 0x3D4;
     pub const DATA_PORT: u16 = 0x3D5;
@@ -18,6 +32,29 @@ pub mod vga {
     pub const CURSOR_END_REG: u8 = 0x0B;
     pub const CURSOR_LOCATION_HIGH: u8 = 0x0E;
     pub const CURSOR_LOCATION_LOW: u8 = 0x0F;
+
+    /// Cursor start register bit that hides the cursor
+    pub const CURSOR_DISABLE: u8 = 0x20;
+    /// First and last scanline of the cursor (of the 16-line font) for an
+    /// underline and a full block
+    pub const CURSOR_UNDERLINE: (u8, u8) = (14, 15);
+    pub const CURSOR_BLOCK: (u8, u8) = (0, 15);
+
+    /// Software cursor: the attribute bits flipped at the cursor cell, and
+    /// how long it stays shown and then hidden
+    pub const SOFT_CURSOR_XOR: u8 = 0x77;
+    pub const BLINK_INTERVAL_MS: u64 = 500;
+
+    /// DAC (color lookup) ports: write the entry index, then R, G, B
+    pub const DAC_WRITE_INDEX_PORT: u16 = 0x3C8;
+    pub const DAC_DATA_PORT: u16 = 0x3C9;
+
+    /// DAC entry used by each of the 16 text attributes, per the default
+    /// EGA-compatible attribute controller palette the BIOS programs
+    pub const ATTRIBUTE_TO_DAC: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+        0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F,
+    ];
 }
 
 /// PS/2 Keyboard controller constants
@@ -30,9 +67,359 @@ pub mod keyboard {
 
     /// Status register bit flags
     pub const STATUS_OUTPUT_BUFFER_FULL: u8 = 0x01;
+    pub const STATUS_INPUT_BUFFER_FULL: u8 = 0x02;
+    /// Set together with OUTPUT_BUFFER_FULL when the byte came from the mouse
+    pub const STATUS_AUX_DATA: u8 = 0x20;
+
+    /// Controller commands (written to the command port)
+    pub const CMD_READ_CONFIG: u8 = 0x20;
+    pub const CMD_WRITE_CONFIG: u8 = 0x60;
+    pub const CMD_ENABLE_AUX: u8 = 0xA8;
+    pub const CMD_SELF_TEST: u8 = 0xAA;
+    pub const CMD_ENABLE_PORT1: u8 = 0xAE;
+    /// Controller self-test reply when the test passed
+    pub const SELF_TEST_PASSED: u8 = 0x55;
+    /// Interface tests of the first and second (aux) port, which reply
+    /// PORT_TEST_PASSED or a code for the line that is stuck
+    pub const CMD_TEST_PORT1: u8 = 0xAB;
+    pub const CMD_TEST_AUX: u8 = 0xA9;
+    pub const PORT_TEST_PASSED: u8 = 0x00;
+    /// The status register reads as all ones when there is no controller
+    pub const STATUS_NO_CONTROLLER: u8 = 0xFF;
+    /// The next data byte goes to the auxiliary (mouse) device
+    pub const CMD_WRITE_AUX: u8 = 0xD4;
+
+    /// Controller configuration byte bits
+    pub const CONFIG_AUX_IRQ: u8 = 0x02;
+    pub const CONFIG_AUX_CLOCK_DISABLED: u8 = 0x20;
+    /// Controller translates keyboard scancodes from set 2 to set 1
+    pub const CONFIG_TRANSLATION: u8 = 0x40;
+
+    /// Device acknowledgement and resend-request bytes
+    pub const RESPONSE_ACK: u8 = 0xFA;
+    pub const RESPONSE_RESEND: u8 = 0xFE;
+
+    /// Keyboard command: set LEDs, followed by a mask of the LED bits
+    pub const CMD_SET_LEDS: u8 = 0xED;
+    pub const LED_SCROLL_LOCK: u8 = 0x01;
+    pub const LED_NUM_LOCK: u8 = 0x02;
+    pub const LED_CAPS_LOCK: u8 = 0x04;
+    /// Keyboard command: reset and run the power-on self-test, which
+    /// answers ACK and then BAT_PASSED
+    pub const CMD_RESET_KEYBOARD: u8 = 0xFF;
+    pub const BAT_PASSED: u8 = 0xAA;
+    /// Read timeouts to sit through while the keyboard runs its self-test,
+    /// which can take several hundred milliseconds
+    pub const RESET_WAIT_ATTEMPTS: usize = 50;
+    /// Keyboard command: select scancode set, followed by the set number.
+    /// Set 2 is what the controller's translation expects.
+    pub const CMD_SET_SCANCODE_SET: u8 = 0xF0;
+    pub const KEYBOARD_SCANCODE_SET: u8 = 2;
+    /// Stale bytes read while flushing the controller before giving up
+    pub const FLUSH_LIMIT: usize = 16;
+
+    /// Device commands for probing what is attached to a port: identify
+    /// replies ACK and then up to two ID bytes, and scanning is kept off
+    /// meanwhile so keys can't be mistaken for the ID
+    pub const CMD_IDENTIFY: u8 = 0xF2;
+    pub const CMD_ENABLE_SCANNING: u8 = 0xF4;
+    pub const CMD_DISABLE_SCANNING: u8 = 0xF5;
+    /// First ID byte of MF2 keyboards (AB 83 and relatives) and of
+    /// keyboards in IBM's 122-key family; AT keyboards send no ID at all
+    pub const ID_MF2_KEYBOARD: u8 = 0xAB;
+    pub const ID_122_KEY_KEYBOARD: u8 = 0xAC;
+    /// ID of a standard mouse, a wheel mouse and a five-button mouse
+    pub const ID_MICE: [u8; 3] = [0x00, 0x03, 0x04];
+
+    /// Keyboard command: set typematic delay and repeat rate, followed by
+    /// a byte with the delay code in bits 5-6 and the rate code in bits 0-4
+    pub const CMD_SET_TYPEMATIC: u8 = 0xF3;
+    pub const TYPEMATIC_DELAY_SHIFT: u8 = 5;
+    /// Delay code n waits (n + 1) * 250 ms before repeating, n in 0..=3
+    pub const TYPEMATIC_DELAY_STEP_MS: u32 = 250;
+    pub const TYPEMATIC_DELAY_MAX: u8 = 3;
+    /// Rate code bits 0-2 (A) and 3-4 (B) give a repeat period of
+    /// (8 + A) * 2^B * 4.17 ms, from 30 repeats/s at 0 to 2 at 0x1F
+    pub const TYPEMATIC_RATE_MAX: u8 = 0x1F;
+    pub const TYPEMATIC_PERIOD_UNIT_US: u32 = 4170;
+    pub const TYPEMATIC_MIN_CPS: u32 = 2;
+    pub const TYPEMATIC_MAX_CPS: u32 = 30;
+    /// Software key repeat used in polling mode, where the hardware's
+    /// repeats are easily missed; `kbrate` changes it along with the
+    /// hardware rate
+    pub const SOFT_REPEAT_DELAY_MS: u32 = 500;
+    pub const SOFT_REPEAT_CPS: u32 = 10;
+    /// Times a keyboard command is resent before giving up
+    pub const COMMAND_RETRIES: usize = 3;
+    /// Uptime by which a keyboard that has sent nothing gets a warning
+    pub const NO_INPUT_WARNING_MS: u64 = 15_000;
+    /// Key bytes tolerated while waiting for a command's ACK
+    pub const MAX_BYTES_BEFORE_ACK: usize = 16;
 
     /// Command to reset CPU via keyboard controller
     pub const CMD_RESET_CPU: u8 = 0xFE;
+    /// Port 0x80 reads (about 1 us each) to wait for that reset before
+    /// falling back to a triple fault
+    pub const RESET_WAIT_READS: usize = 100_000;
+
+    /// Pause/Break sends no break code, just one fixed sequence starting
+    /// with this prefix: E1 1D 45 E1 9D C5 in set 1, E1 14 77 E1 F0 14 F0 77
+    /// in set 2
+    pub const PAUSE_PREFIX: u8 = 0xE1;
+    pub const PAUSE_SEQUENCE_LEN_SET1: u8 = 6;
+    pub const PAUSE_SEQUENCE_LEN_SET2: u8 = 8;
+
+    /// Slots in the key handler table filled by `keyboard::subscribe`
+    pub const MAX_KEY_HANDLERS: usize = 8;
+
+    /// Keys the `remap` command can translate at once
+    pub const MAX_REMAPS: usize = 16;
+
+    /// Port 0x80 reads (about 1 us each) between polls while waiting for a key
+    pub const POLL_PAUSE_READS: usize = 100;
+
+    /// Capacity of the interrupt-to-main-loop scancode queue. Each key is
+    /// usually two or more scancodes (press and release), so this needs
+    /// room for a burst of typing while a long command is printing.
+    pub const SCANCODE_QUEUE_SIZE: usize = 256;
+}
+
+/// 16550 UART (serial port) constants
+pub mod serial {
+    /// I/O base of the first serial port
+    pub const COM1_BASE: u16 = 0x3F8;
+
+    /// Register offsets from the port base
+    pub const REG_DATA: u16 = 0;
+    pub const REG_INT_ENABLE: u16 = 1;
+    pub const REG_FIFO_CTRL: u16 = 2;
+    pub const REG_LINE_CTRL: u16 = 3;
+    pub const REG_MODEM_CTRL: u16 = 4;
+    pub const REG_LINE_STATUS: u16 = 5;
+
+    /// With DLAB set, offsets 0/1 hold the low/high baud divisor bytes
+    pub const REG_DIVISOR_LOW: u16 = 0;
+    pub const REG_DIVISOR_HIGH: u16 = 1;
+
+    /// Line control: divisor latch access bit
+    pub const LINE_DLAB: u8 = 0x80;
+    /// Line control: 8 data bits, no parity, one stop bit
+    pub const LINE_8N1: u8 = 0x03;
+    /// FIFO control: enable, clear both FIFOs, 14-byte threshold
+    pub const FIFO_ENABLE_CLEAR_14: u8 = 0xC7;
+    /// Modem control: DTR + RTS + OUT2
+    pub const MODEM_DTR_RTS_OUT2: u8 = 0x0B;
+    /// Line status: transmit holding register empty
+    pub const LINE_STATUS_THR_EMPTY: u8 = 0x20;
+    /// Line status: a received byte is waiting in the data register
+    pub const LINE_STATUS_DATA_READY: u8 = 0x01;
+    /// Line status read back when no UART answers at the base address
+    pub const LINE_STATUS_NO_UART: u8 = 0xFF;
+
+    /// Baud divisor against the 115200 Hz base clock (3 = 38400 baud)
+    pub const BAUD_DIVISOR: u16 = 3;
+
+    /// IRQ line of COM1
+    pub const IRQ: u8 = 4;
+    /// Interrupt enable: raise the IRQ when received data is available
+    pub const INT_RECEIVED_DATA: u8 = 0x01;
+    /// Received bytes held for the main loop; a pasted line fits
+    pub const INPUT_QUEUE_SIZE: usize = 256;
+}
+
+/// PS/2 mouse constants
+pub mod mouse {
+    /// Device commands (sent through the controller's write-aux command)
+    pub const CMD_SET_DEFAULTS: u8 = 0xF6;
+    pub const CMD_ENABLE_REPORTING: u8 = 0xF4;
+
+    /// First packet byte flags
+    pub const PACKET_BUTTONS: u8 = 0x07;
+    pub const PACKET_ALWAYS_ONE: u8 = 0x08;
+    pub const PACKET_X_SIGN: u8 = 0x10;
+    pub const PACKET_Y_SIGN: u8 = 0x20;
+    pub const PACKET_X_OVERFLOW: u8 = 0x40;
+    pub const PACKET_Y_OVERFLOW: u8 = 0x80;
+
+    /// Mouse counts per text cell of cursor movement
+    pub const COUNTS_PER_CELL: i32 = 8;
+
+    /// Packets buffered for `mouse::poll_event`; newer ones are dropped
+    /// while it is full
+    pub const EVENT_QUEUE_SIZE: usize = 32;
+
+    /// IRQ line of the auxiliary PS/2 port
+    pub const IRQ: u8 = 12;
+}
+
+/// CMOS real-time clock constants
+pub mod rtc {
+    /// CMOS register select port (bit 7 set disables NMI)
+    pub const ADDRESS_PORT: u16 = 0x70;
+    /// CMOS data port for the selected register
+    pub const DATA_PORT: u16 = 0x71;
+
+    /// OR'd into the register index to keep NMI masked during the access
+    pub const NMI_DISABLE: u8 = 0x80;
+
+    /// Time and date registers
+    pub const REG_SECONDS: u8 = 0x00;
+    pub const REG_MINUTES: u8 = 0x02;
+    pub const REG_HOURS: u8 = 0x04;
+    pub const REG_DAY: u8 = 0x07;
+    pub const REG_MONTH: u8 = 0x08;
+    pub const REG_YEAR: u8 = 0x09;
+    /// Century register at the usual ACPI FADT location; not every RTC has one
+    pub const REG_CENTURY: u8 = 0x32;
+    /// Century register values accepted as real, anything else means 20xx
+    pub const CENTURY_RANGE: core::ops::RangeInclusive<u8> = 19..=29;
+
+    /// Status registers
+    pub const REG_STATUS_A: u8 = 0x0A;
+    pub const REG_STATUS_B: u8 = 0x0B;
+    pub const REG_STATUS_D: u8 = 0x0D;
+
+    /// Status A: update in progress
+    pub const STATUS_A_UPDATING: u8 = 0x80;
+    /// Status B: hours are 24-hour (otherwise 12-hour with PM in bit 7)
+    pub const STATUS_B_24_HOUR: u8 = 0x02;
+    /// Status B: values are binary (otherwise BCD)
+    pub const STATUS_B_BINARY: u8 = 0x04;
+    /// PM flag in the hours register in 12-hour mode
+    pub const HOUR_PM: u8 = 0x80;
+}
+
+/// 8253/8254 programmable interval timer and PC speaker constants
+pub mod pit {
+    /// Input clock of all PIT channels in Hz
+    pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+    pub const CHANNEL0_PORT: u16 = 0x40;
+    pub const CHANNEL2_PORT: u16 = 0x42;
+    pub const COMMAND_PORT: u16 = 0x43;
+
+    /// Command bytes: channel, lobyte/hibyte access, mode, binary counting
+    pub const CMD_CHANNEL0_RATE: u8 = 0x34; // channel 0, mode 2 (rate generator)
+    pub const CMD_CHANNEL2_SQUARE: u8 = 0xB6; // channel 2, mode 3 (square wave)
+
+    /// Timer interrupts per second once `time::init` has programmed channel 0
+    pub const TICK_HZ: u32 = 100;
+    /// Ticks the time-stamp counter is measured over to find its rate
+    pub const TSC_CALIBRATION_TICKS: u64 = 10;
+    /// Port 0x80 reads (about 1 us each) per millisecond when sleeping
+    /// without timer ticks
+    pub const IO_WAIT_READS_PER_MS: u64 = 1000;
+
+    /// System control port B; bit 0 gates channel 2, bit 1 enables the speaker
+    pub const SPEAKER_PORT: u16 = 0x61;
+    pub const SPEAKER_GATE_BITS: u8 = 0x03;
+}
+
+/// ATA (IDE) disk constants, primary channel
+pub mod ata {
+    /// Base of the command block registers and the control register
+    pub const PRIMARY_IO_BASE: u16 = 0x1F0;
+    pub const PRIMARY_CONTROL: u16 = 0x3F6;
+
+    /// Register offsets from the I/O base
+    pub const REG_DATA: u16 = 0;
+    pub const REG_ERROR: u16 = 1;
+    pub const REG_SECTOR_COUNT: u16 = 2;
+    pub const REG_LBA_LOW: u16 = 3;
+    pub const REG_LBA_MID: u16 = 4;
+    pub const REG_LBA_HIGH: u16 = 5;
+    pub const REG_DRIVE_HEAD: u16 = 6;
+    pub const REG_STATUS: u16 = 7;
+    pub const REG_COMMAND: u16 = 7;
+
+    /// Status register bits
+    pub const STATUS_ERR: u8 = 0x01;
+    pub const STATUS_DRQ: u8 = 0x08;
+    pub const STATUS_DF: u8 = 0x20;
+    pub const STATUS_BSY: u8 = 0x80;
+
+    /// Drive/head value for the master drive in LBA mode; the top four
+    /// LBA bits go in the low nibble
+    pub const DRIVE_MASTER_LBA: u8 = 0xE0;
+
+    pub const CMD_READ_SECTORS: u8 = 0x20;
+
+    pub const SECTOR_SIZE: usize = 512;
+    /// Largest address reachable with 28-bit LBA
+    pub const MAX_LBA: u32 = 0x0FFF_FFFF;
+}
+
+/// PCI configuration space constants (mechanism #1)
+pub mod pci {
+    /// Takes the bus/device/function/register to access, with bit 31 set
+    pub const CONFIG_ADDRESS: u16 = 0xCF8;
+    /// Reads and writes the dword selected through `CONFIG_ADDRESS`
+    pub const CONFIG_DATA: u16 = 0xCFC;
+    pub const ENABLE: u32 = 0x8000_0000;
+
+    pub const MAX_BUS: u8 = 255;
+    pub const DEVICES_PER_BUS: u8 = 32;
+    pub const FUNCTIONS_PER_DEVICE: u8 = 8;
+
+    /// Config space offsets (dword aligned)
+    pub const REG_ID: u8 = 0x00;
+    pub const REG_CLASS: u8 = 0x08;
+    pub const REG_HEADER: u8 = 0x0C;
+
+    /// Vendor ID read back when no function answers
+    pub const NO_VENDOR: u16 = 0xFFFF;
+    /// Header type bit 7: the device implements functions 1-7
+    pub const HEADER_MULTI_FUNCTION: u8 = 0x80;
+}
+
+/// Virtual memory constants
+pub mod memory {
+    pub const PAGE_SIZE: usize = 4096;
+
+    /// Start of the boot stack region, as configured in
+    /// `[package.metadata.bootloader]`. The bootloader leaves its first page
+    /// unmapped as a guard and the stack proper starts one page above.
+    pub const KERNEL_STACK_ADDRESS: u64 = 0xFFFF_FF80_0000_0000;
+}
+
+/// User mode (ring 3) constants
+pub mod usermode {
+    /// Software interrupt used for system calls
+    pub const SYSCALL_VECTOR: u8 = 0x80;
+
+    /// System call numbers, passed in RAX
+    pub const SYS_EXIT: u64 = 0;
+    pub const SYS_WRITE: u64 = 1;
+
+    pub const USER_STACK_SIZE: usize = 4096 * 4;
+}
+
+/// Kernel heap constants
+pub mod heap {
+    /// Size of the static region backing the global allocator
+    pub const HEAP_SIZE: usize = 100 * 1024;
+}
+
+/// In-memory filesystem limits
+pub mod ramfs {
+    pub const MAX_FILES: usize = 16;
+    pub const MAX_NAME_LEN: usize = 32;
+    /// Bytes of file contents, across all files
+    pub const MAX_TOTAL_SIZE: usize = 32 * 1024;
+}
+
+/// QEMU-specific devices
+pub mod qemu {
+    /// I/O port of the `isa-debug-exit` device (see Cargo.toml test-args)
+    #[cfg(test)]
+    pub const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+    /// ACPI PM1a control port on QEMU's q35/piix4 machines; writing
+    /// `SHUTDOWN_VALUE` (SLP_EN with sleep type 5) powers off
+    pub const ACPI_SHUTDOWN_PORT: u16 = 0x604;
+    /// Same, for older QEMU and Bochs
+    pub const BOCHS_SHUTDOWN_PORT: u16 = 0xB004;
+    pub const SHUTDOWN_VALUE: u16 = 0x2000;
 }
 
 /// Interrupt constants
@@ -41,4 +428,15 @@ pub mod interrupts {
     /// We remap PIC interrupts to start at 32 to avoid conflicts with CPU exceptions
     pub const PIC_1_OFFSET: u8 = 32;
     pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+    /// PIC command and data (mask) ports
+    pub const PIC_1_COMMAND: u16 = 0x20;
+    pub const PIC_1_DATA: u16 = 0x21;
+    pub const PIC_2_COMMAND: u16 = 0xA0;
+    pub const PIC_2_DATA: u16 = 0xA1;
+
+    /// OCW3 command: next read of the command port returns the ISR
+    pub const PIC_READ_ISR: u8 = 0x0B;
+    /// Non-specific end-of-interrupt command
+    pub const PIC_EOI: u8 = 0x20;
 }
@@ -0,0 +1,159 @@
+//! CPU identification through the `cpuid` instruction, and register
+//! snapshots for debugging
+
+use core::arch::asm;
+use core::arch::x86_64::{CpuidResult, __cpuid, _rdtsc};
+
+/// Which register of leaf 1 a feature bit lives in
+#[derive(Clone, Copy)]
+pub enum FeatureRegister {
+    Ecx,
+    Edx,
+}
+
+/// A named feature flag from cpuid leaf 1
+pub struct Feature {
+    pub name: &'static str,
+    pub register: FeatureRegister,
+    pub bit: u32,
+}
+
+/// Leaf 1 feature bits shown by `cpuid`; add entries here to show more
+pub const FEATURES: &[Feature] = &[
+    Feature { name: "FPU", register: FeatureRegister::Edx, bit: 0 },
+    Feature { name: "TSC", register: FeatureRegister::Edx, bit: 4 },
+    Feature { name: "APIC", register: FeatureRegister::Edx, bit: 9 },
+    Feature { name: "SSE", register: FeatureRegister::Edx, bit: 25 },
+    Feature { name: "SSE2", register: FeatureRegister::Edx, bit: 26 },
+    Feature { name: "SSE3", register: FeatureRegister::Ecx, bit: 0 },
+    Feature { name: "SSE4.1", register: FeatureRegister::Ecx, bit: 19 },
+    Feature { name: "SSE4.2", register: FeatureRegister::Ecx, bit: 20 },
+    Feature { name: "RDRAND", register: FeatureRegister::Ecx, bit: 30 },
+];
+
+/// Cycles counted by the time-stamp counter since reset
+pub fn rdtsc() -> u64 {
+    // Only reads a counter, which every x86_64 CPU has
+    unsafe { _rdtsc() }
+}
+
+/// Highest basic leaf the CPU supports
+pub fn max_leaf() -> u32 {
+    __cpuid(0).eax
+}
+
+/// Run `cpuid` for `leaf`, or `None` if the CPU doesn't support it
+pub fn cpuid(leaf: u32) -> Option<CpuidResult> {
+    if leaf > max_leaf() {
+        return None;
+    }
+    Some(__cpuid(leaf))
+}
+
+/// The 12-byte vendor string from leaf 0 (EBX, EDX, ECX in that order)
+pub fn vendor() -> [u8; 12] {
+    let result = __cpuid(0);
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&result.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&result.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&result.ecx.to_le_bytes());
+    vendor
+}
+
+/// Whether `feature` is set in a leaf 1 result
+pub fn has_feature(leaf1: &CpuidResult, feature: &Feature) -> bool {
+    let value = match feature.register {
+        FeatureRegister::Ecx => leaf1.ecx,
+        FeatureRegister::Edx => leaf1.edx,
+    };
+    value & (1 << feature.bit) != 0
+}
+
+/// General-purpose registers, RSP, RIP and RFLAGS as `capture` saw them
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rsp: u64,
+    pub rip: u64,
+    pub rflags: u64,
+}
+
+impl Registers {
+    /// Snapshot the registers from inside this function. The values are
+    /// approximate as far as the caller is concerned: the compiler has
+    /// reused most registers by then, one of them holds the address of the
+    /// snapshot, RSP is this function's stack pointer and RIP points into
+    /// the capture code. RBX and R12-R15 are callee-saved, so they are the
+    /// most likely to still hold the caller's values.
+    #[inline(never)]
+    pub fn capture() -> Registers {
+        let mut regs = Registers::default();
+        let regs_ptr = core::ptr::addr_of_mut!(regs);
+        unsafe {
+            asm!(
+                "mov [{regs} + 0x00], rax",
+                "mov [{regs} + 0x08], rbx",
+                "mov [{regs} + 0x10], rcx",
+                "mov [{regs} + 0x18], rdx",
+                "mov [{regs} + 0x20], rsi",
+                "mov [{regs} + 0x28], rdi",
+                "mov [{regs} + 0x30], rbp",
+                "mov [{regs} + 0x38], r8",
+                "mov [{regs} + 0x40], r9",
+                "mov [{regs} + 0x48], r10",
+                "mov [{regs} + 0x50], r11",
+                "mov [{regs} + 0x58], r12",
+                "mov [{regs} + 0x60], r13",
+                "mov [{regs} + 0x68], r14",
+                "mov [{regs} + 0x70], r15",
+                "mov [{regs} + 0x78], rsp",
+                "lea {tmp}, [rip]",
+                "mov [{regs} + 0x80], {tmp}",
+                "pushfq",
+                "pop {tmp}",
+                "mov [{regs} + 0x88], {tmp}",
+                regs = in(reg) regs_ptr,
+                tmp = out(reg) _,
+            );
+        }
+        regs
+    }
+}
+
+/// CR0, CR2, CR3 and CR4, raw
+#[derive(Debug, Clone, Copy)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    /// Address of the last page fault
+    pub cr2: u64,
+    /// Level 4 page table address and PCID/flag bits
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+pub fn control_registers() -> ControlRegisters {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+
+    let (level_4_frame, cr3_flags) = Cr3::read_raw();
+    ControlRegisters {
+        cr0: Cr0::read_raw(),
+        cr2: Cr2::read_raw(),
+        cr3: level_4_frame.start_address().as_u64() | u64::from(cr3_flags),
+        cr4: Cr4::read_raw(),
+    }
+}
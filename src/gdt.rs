@@ -2,24 +2,69 @@ use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
+use crate::constants::memory::PAGE_SIZE;
+use crate::logln;
 
 /// Index of the double fault stack in the IST
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Index of the page fault stack in the IST, so a kernel stack overflow
+/// can still be reported instead of turning into a double fault
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+/// IST stacks in use (double fault and page fault)
+pub const IST_STACKS: usize = 2;
+
+/// Size of each exception/privilege stack, not counting its guard page
+pub const STACK_SIZE: usize = PAGE_SIZE * 5; // 20 KiB stack
+
+/// An exception stack with a page below it that `memory::init` unmaps, so
+/// overflowing the stack faults instead of corrupting whatever is next
+#[repr(C, align(4096))]
+struct GuardedStack {
+    guard: [u8; PAGE_SIZE],
+    stack: [u8; STACK_SIZE],
+}
+
+impl GuardedStack {
+    const fn new() -> Self {
+        GuardedStack {
+            guard: [0; PAGE_SIZE],
+            stack: [0; STACK_SIZE],
+        }
+    }
+}
+
+static mut DOUBLE_FAULT_STACK: GuardedStack = GuardedStack::new();
+static mut PAGE_FAULT_STACK: GuardedStack = GuardedStack::new();
+/// Kernel stack the CPU switches to (RSP0) on an interrupt from ring 3
+static mut PRIVILEGE_STACK: GuardedStack = GuardedStack::new();
+
+/// Top of `stack` (stacks grow downward, so this is its end address)
+fn stack_top(stack: *const GuardedStack) -> VirtAddr {
+    let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!((*stack).stack) });
+    stack_start + STACK_SIZE as u64
+}
+
+/// Guard pages of the exception and privilege stacks
+pub fn guard_pages() -> [VirtAddr; 3] {
+    [
+        VirtAddr::from_ptr(core::ptr::addr_of!(DOUBLE_FAULT_STACK)),
+        VirtAddr::from_ptr(core::ptr::addr_of!(PAGE_FAULT_STACK)),
+        VirtAddr::from_ptr(core::ptr::addr_of!(PRIVILEGE_STACK)),
+    ]
+}
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
 
-        // Set up a separate stack for double fault handler
-        // IST (Interrupt Stack Table) entry 0
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5; // 20 KiB stack
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        // Separate stacks for the double fault and page fault handlers
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            stack_top(core::ptr::addr_of!(DOUBLE_FAULT_STACK));
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+            stack_top(core::ptr::addr_of!(PAGE_FAULT_STACK));
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + (STACK_SIZE as u64);
-            stack_end // Stack grows downward, so we want the end address
-        };
+        // Stack for interrupts and syscalls arriving from user mode
+        tss.privilege_stack_table[0] = stack_top(core::ptr::addr_of!(PRIVILEGE_STACK));
 
         tss
     };
@@ -30,12 +75,17 @@ lazy_static! {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+        // Ring 3 segments; the selectors come back with RPL 3
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
 
         (
             gdt,
             Selectors {
                 code_selector,
                 tss_selector,
+                user_code_selector,
+                user_data_selector,
             },
         )
     };
@@ -44,6 +94,13 @@ lazy_static! {
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+}
+
+/// Code and data selectors for entering ring 3
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
 }
 
 /// Initialize the GDT
@@ -56,4 +113,5 @@ pub fn init() {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
+    logln!("DEBUG: GDT and TSS loaded");
 }
@@ -3,9 +3,12 @@ use x86_64::instructions::hlt;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
-use crate::constants::interrupts::{PIC_1_OFFSET, PIC_2_OFFSET};
-use crate::constants::keyboard::DATA_PORT;
-use crate::println;
+use crate::constants::interrupts::{
+    PIC_1_OFFSET, PIC_2_OFFSET, PIC_1_COMMAND, PIC_1_DATA, PIC_2_COMMAND, PIC_2_DATA,
+    PIC_READ_ISR, PIC_EOI,
+};
+use crate::constants::keyboard::{DATA_PORT, STATUS_AUX_DATA, STATUS_OUTPUT_BUFFER_FULL};
+use crate::{logln, println_unmuted};
 
 /// Hardware interrupt numbers (after remapping)
 #[derive(Debug, Clone, Copy)]
@@ -59,112 +62,102 @@ lazy_static! {
                 .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
         }
 
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        // Page faults get their own stack too: a kernel stack overflow
+        // faults on the guard page with no stack left to push the frame on
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(crate::gdt::PAGE_FAULT_IST_INDEX);
+        }
         idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
 
         // Hardware interrupts - set handlers for ALL PIC interrupts to avoid triple faults
         idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
-        idt[InterruptIndex::Cascade.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::COM2.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::COM1.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::LPT2.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::FloppyDisk.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::LPT1.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::RTC.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::ACPI.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::Available1.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::Available2.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::Mouse.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::CoProcessor.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::PrimaryATA.as_u8()].set_handler_fn(spurious_interrupt_handler);
-        idt[InterruptIndex::SecondaryATA.as_u8()].set_handler_fn(spurious_interrupt_handler);
+        idt[InterruptIndex::Cascade.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::COM2.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::COM1.as_u8()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::LPT2.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::FloppyDisk.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        // IRQ7 and IRQ15 are where the PICs deliver spurious interrupts
+        idt[InterruptIndex::LPT1.as_u8()].set_handler_fn(irq7_handler);
+        idt[InterruptIndex::RTC.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::ACPI.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::Available1.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::Available2.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_u8()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptIndex::CoProcessor.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::PrimaryATA.as_u8()].set_handler_fn(unhandled_interrupt_handler);
+        idt[InterruptIndex::SecondaryATA.as_u8()].set_handler_fn(irq15_handler);
+
+        // System calls from ring 3 (int 0x80); the gate must allow DPL 3
+        unsafe {
+            idt[crate::constants::usermode::SYSCALL_VECTOR]
+                .set_handler_addr(crate::usermode::syscall_entry_addr())
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+        }
 
         idt
     };
 }
 
+/// Initialize IDT and PICs, unmask the timer and enable interrupts
 pub fn init() {
-    use crate::println;
-
-    println!("DEBUG: Loading IDT into CPU...");
-    IDT.load();
-    println!("DEBUG: IDT loaded");
+    init_without_sti();
 
-    println!("DEBUG: Initializing PICs...");
-    // Initialize and remap the PICs
-    unsafe {
-        PICS.lock().initialize();
-
-        // Wait for PICs to stabilize - do a few I/O reads
-        use x86_64::instructions::port::Port;
-        let mut wait_port: Port<u8> = Port::new(0x80);  // Unused port for timing
-        for _ in 0..10 {
-            wait_port.read();
-        }
-    }
-    println!("DEBUG: PICs initialized");
-
-    // Unmask BOTH timer (IRQ0) and keyboard (IRQ1) for testing
-    unsafe {
-        use x86_64::instructions::port::Port;
-        let mut pic1_data: Port<u8> = Port::new(0x21);
-
-        // Read current mask
-        let mask_before = pic1_data.read();
-        println!("DEBUG: PIC1 mask BEFORE unmask: {:#04x}", mask_before);
-
-        // Unmask ONLY IRQ0 (timer) for now - keyboard uses polling
-        // Keep IRQ1 (keyboard) MASKED so interrupt doesn't interfere with polling
-        let new_mask = mask_before & !(1 << 0);  // Only unmask timer
-        println!("DEBUG: Writing new mask: {:#04x}", new_mask);
-        pic1_data.write(new_mask);
-
-        // Wait for write to complete
-        let mut wait_port: Port<u8> = Port::new(0x80);
-        for _ in 0..10 {
-            wait_port.read();
-        }
-
-        // Verify it was written
-        let mask_after = pic1_data.read();
-        println!("DEBUG: PIC1 mask AFTER unmask: {:#04x}", mask_after);
-        println!("DEBUG: Timer (bit 0): {}, Keyboard (bit 1): {}",
-                 if (mask_after & 1) == 0 { "UNMASKED" } else { "MASKED" },
-                 if (mask_after & 2) == 0 { "UNMASKED" } else { "MASKED" });
-    }
-
-    // Enable interrupts globally (sti instruction)
-    println!("DEBUG: Calling sti...");
+    unmask_irq(0);
+    logln!("DEBUG: Timer IRQ unmasked, calling sti...");
     x86_64::instructions::interrupts::enable();
-    println!("DEBUG: sti called, interrupts should be enabled");
+    logln!("DEBUG: Interrupts enabled? {}", x86_64::instructions::interrupts::are_enabled());
+}
 
-    // Check if interrupts are actually enabled
-    let enabled = x86_64::instructions::interrupts::are_enabled();
-    println!("DEBUG: Interrupts enabled? {}", enabled);
+/// Unmask one PIC line (0-15), plus the cascade line for slave IRQs
+pub fn unmask_irq(irq: u8) {
+    use x86_64::instructions::port::Port;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    without_interrupts(|| unsafe {
+        let mut master: Port<u8> = Port::new(PIC_1_DATA);
+        if irq < 8 {
+            let mask = master.read();
+            master.write(mask & !(1 << irq));
+        } else {
+            let mut slave: Port<u8> = Port::new(PIC_2_DATA);
+            let mask = slave.read();
+            slave.write(mask & !(1 << (irq - 8)));
+            let mask = master.read();
+            master.write(mask & !(1 << 2));
+        }
+    });
 }
 
 /// Initialize IDT and PICs but DO NOT enable interrupts (no sti)
 /// This allows pure polling mode while keeping exception handlers available
-pub fn init_without_sti() {
-    use crate::println;
-
-    println!("DEBUG: Loading IDT into CPU...");
+fn init_without_sti() {
+    logln!("DEBUG: Loading IDT into CPU...");
     IDT.load();
-    println!("DEBUG: IDT loaded");
+    logln!("DEBUG: IDT loaded");
 
-    println!("DEBUG: Initializing PICs...");
+    logln!("DEBUG: Initializing PICs...");
     // Initialize and remap the PICs
     unsafe {
         PICS.lock().initialize();
     }
-    println!("DEBUG: PICs initialized");
-
-    println!("DEBUG: Interrupts NOT enabled (no sti) - using pure polling mode");
+    logln!("DEBUG: PICs initialized");
 }
 
 // Exception handlers
-extern "x86-interrupt" fn breakpoint_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    // Serial only: the VGA writer may be locked by the code we interrupted,
+    // and the serial path skips the output rather than wait for its lock
+    crate::serial::_try_print(format_args!("EXCEPTION: BREAKPOINT\n{:#?}\n", stack_frame));
+
     // Use direct VGA write to avoid println! issues in exception context
     unsafe {
         let vga_buffer = 0xb8000 as *mut u8;
@@ -181,7 +174,7 @@ extern "x86-interrupt" fn double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    println!("EXCEPTION: DOUBLE FAULT - halting");
+    println_unmuted!("EXCEPTION: DOUBLE FAULT - halting");
 
     loop {
         hlt();
@@ -194,44 +187,78 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    let address = Cr2::read();
+    if crate::usermode::active() && stack_frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring3 {
+        println_unmuted!("EXCEPTION: PAGE FAULT in user mode at {:?}; stopping the program", address);
+        crate::usermode::abort();
+    }
+    if address.as_ref().is_ok_and(|&addr| crate::memory::is_guard_page(addr)) {
+        println_unmuted!("EXCEPTION: PAGE FAULT (stack overflow into guard page)");
+    } else {
+        println_unmuted!("EXCEPTION: PAGE FAULT");
+    }
+    println_unmuted!("Accessed Address: {:?}", address);
+    println_unmuted!("Error Code: {:?}", error_code);
+    println_unmuted!("{:#?}", stack_frame);
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+/// A GPF while a user program runs (or while entering it, e.g. with a bad
+/// selector) stops that program; anywhere else it is fatal
 extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
-    println!("EXCEPTION: GENERAL PROTECTION FAULT");
-    println!("Error Code: {}", error_code);
-    println!("{:#?}", stack_frame);
+    if crate::usermode::active() {
+        println_unmuted!("EXCEPTION: GENERAL PROTECTION FAULT in user mode (error code {:#x}); stopping the program", error_code);
+        crate::usermode::abort();
+    }
+
+    println_unmuted!("EXCEPTION: GENERAL PROTECTION FAULT");
+    println_unmuted!("Error Code: {}", error_code);
+    println_unmuted!("{:#?}", stack_frame);
     loop {
-        x86_64::instructions::hlt();
+        hlt();
     }
 }
 
-// Hardware interrupt handlers
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // DEBUG: Visual indicator that timer interrupt fired
-    static mut TIMER_COUNT: u32 = 0;
-    unsafe {
-        TIMER_COUNT += 1;
-        if TIMER_COUNT == 1 {
-            // Write directly to VGA buffer
-            let vga_buffer = 0xb8000 as *mut u8;
-            let msg = b"TIMER!";
-            let offset = 160; // Second line
-            for (i, &byte) in msg.iter().enumerate() {
-                *vga_buffer.offset((offset + i * 2) as isize) = byte;
-                *vga_buffer.offset((offset + i * 2 + 1) as isize) = 0x2f; // Green on white
+/// Define a handler for an unrecoverable exception: print its name, the
+/// error code (if the CPU pushes one) and the stack frame, then halt
+macro_rules! fatal_exception_handler {
+    ($handler:ident, $name:expr) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame) {
+            println_unmuted!("EXCEPTION: {}", $name);
+            println_unmuted!("{:#?}", stack_frame);
+            loop {
+                hlt();
             }
         }
-    }
+    };
+    ($handler:ident, $name:expr, error_code) => {
+        extern "x86-interrupt" fn $handler(stack_frame: InterruptStackFrame, error_code: u64) {
+            println_unmuted!("EXCEPTION: {}", $name);
+            println_unmuted!("Error Code: {}", error_code);
+            println_unmuted!("{:#?}", stack_frame);
+            loop {
+                hlt();
+            }
+        }
+    };
+}
+
+fatal_exception_handler!(divide_error_handler, "DIVIDE ERROR");
+fatal_exception_handler!(debug_handler, "DEBUG");
+fatal_exception_handler!(invalid_opcode_handler, "INVALID OPCODE");
+fatal_exception_handler!(invalid_tss_handler, "INVALID TSS", error_code);
+fatal_exception_handler!(segment_not_present_handler, "SEGMENT NOT PRESENT", error_code);
+fatal_exception_handler!(stack_segment_fault_handler, "STACK SEGMENT FAULT", error_code);
+
+// Hardware interrupt handlers
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::time::tick();
+    crate::vga_buffer::blink_tick(crate::time::ticks());
 
     unsafe {
         PICS.lock()
@@ -242,39 +269,93 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
 
-    // DEBUG: Visual indicator that interrupt fired
-    static mut INTERRUPT_COUNT: u32 = 0;
+    // A command exchange with interrupts off may already have read the
+    // byte that raised this IRQ; reading again would repeat a stale one
+    if crate::ps2::status() & STATUS_OUTPUT_BUFFER_FULL != 0 {
+        // Read scancode from keyboard data port
+        let mut port = Port::new(DATA_PORT);
+        let scancode: u8 = unsafe { port.read() };
+
+        // Queue it for processing in main loop
+        crate::keyboard::route_byte(scancode, false);
+    }
+
+    // Acknowledge interrupt
     unsafe {
-        INTERRUPT_COUNT += 1;
-        if INTERRUPT_COUNT <= 5 {
-            // Write directly to VGA buffer to avoid println issues
-            let vga_buffer = 0xb8000 as *mut u8;
-            let msg = b"INT!";
-            for (i, &byte) in msg.iter().enumerate() {
-                *vga_buffer.offset((i * 2) as isize) = byte;
-                *vga_buffer.offset((i * 2 + 1) as isize) = 0x4f; // White on red
-            }
-        }
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
+}
 
-    // Read scancode from keyboard data port
-    let mut port = Port::new(DATA_PORT);
-    let scancode: u8 = unsafe { port.read() };
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::serial::receive();
 
-    // Queue it for processing in main loop
-    crate::keyboard::add_scancode(scancode);
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::COM1.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    // Only read when an aux byte is waiting: a spurious IRQ12 would feed
+    // the packet decoder garbage, and a first-port byte belongs to IRQ1
+    let wanted = STATUS_OUTPUT_BUFFER_FULL | STATUS_AUX_DATA;
+    if crate::ps2::status() & wanted == wanted {
+        let mut port = Port::new(DATA_PORT);
+        let byte: u8 = unsafe { port.read() };
+        // Usually the mouse, but the keyboard may be on the aux port
+        crate::keyboard::route_byte(byte, true);
+    }
 
-    // Acknowledge interrupt
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
     }
 }
 
-extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    // Spurious interrupt - just acknowledge it and return
+extern "x86-interrupt" fn unhandled_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // An IRQ we have no driver for - just acknowledge it and return
     // We don't know which interrupt number this is, so acknowledge both PICs
     unsafe {
         PICS.lock().notify_end_of_interrupt(PIC_2_OFFSET);
     }
 }
+
+/// Read a PIC's In-Service Register: bit n set means IRQ n is being serviced
+fn read_isr(command_port: u16) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    let mut port: Port<u8> = Port::new(command_port);
+    unsafe {
+        port.write(PIC_READ_ISR);
+        port.read()
+    }
+}
+
+extern "x86-interrupt" fn irq7_handler(_stack_frame: InterruptStackFrame) {
+    // A spurious IRQ7 isn't in service on the master, and must not get an EOI
+    if read_isr(PIC_1_COMMAND) & (1 << 7) != 0 {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(InterruptIndex::LPT1.as_u8());
+        }
+    }
+}
+
+extern "x86-interrupt" fn irq15_handler(_stack_frame: InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    if read_isr(PIC_2_COMMAND) & (1 << 7) != 0 {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(InterruptIndex::SecondaryATA.as_u8());
+        }
+    } else {
+        // Spurious on the slave: the master still saw a real IRQ2 from the
+        // cascade, so it alone needs an EOI
+        let mut master: Port<u8> = Port::new(PIC_1_COMMAND);
+        unsafe {
+            master.write(PIC_EOI);
+        }
+    }
+}
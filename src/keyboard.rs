@@ -1,99 +1,1103 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
 use spin::Mutex;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-use crate::println;
+use pc_keyboard::{DecodedKey, KeyCode, KeyEvent, KeyState};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::constants::keyboard::{
+    BAT_PASSED, CMD_RESET_KEYBOARD, CMD_SELF_TEST, CMD_SET_LEDS, CMD_SET_SCANCODE_SET,
+    CMD_SET_TYPEMATIC, COMMAND_RETRIES, CONFIG_AUX_CLOCK_DISABLED, CONFIG_AUX_IRQ,
+    CONFIG_TRANSLATION, FLUSH_LIMIT, KEYBOARD_SCANCODE_SET, LED_CAPS_LOCK, LED_NUM_LOCK,
+    LED_SCROLL_LOCK, MAX_BYTES_BEFORE_ACK, MAX_KEY_HANDLERS, MAX_REMAPS, POLL_PAUSE_READS,
+    RESET_WAIT_ATTEMPTS, RESPONSE_ACK, RESPONSE_RESEND, SCANCODE_QUEUE_SIZE, SELF_TEST_PASSED,
+    SOFT_REPEAT_CPS, SOFT_REPEAT_DELAY_MS, STATUS_NO_CONTROLLER, TYPEMATIC_DELAY_MAX,
+    TYPEMATIC_DELAY_SHIFT, TYPEMATIC_DELAY_STEP_MS, TYPEMATIC_MAX_CPS, TYPEMATIC_MIN_CPS,
+    TYPEMATIC_PERIOD_UNIT_US, TYPEMATIC_RATE_MAX,
+};
+use crate::constants::pit::TICK_HZ;
+use crate::layout::{CodeSet, Decoder, Layout, DEFAULT_LAYOUT};
+use crate::logln;
+use crate::ps2::{self, Channel, ControllerInfo, Ps2Error};
+use crate::queue::ByteQueue;
 
-/// Scancode buffer for interrupt-driven keyboard input
-const SCANCODE_QUEUE_SIZE: usize = 16;
+/// Scancodes from the keyboard interrupt, waiting for the main loop
+static SCANCODE_QUEUE: ByteQueue<SCANCODE_QUEUE_SIZE> = ByteQueue::new();
 
-struct ScancodeQueue {
-    buffer: [u8; SCANCODE_QUEUE_SIZE],
-    read_pos: usize,
-    write_pos: usize,
+/// Input counters since boot, reported by `stats`
+static RECEIVED: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+static DECODED: AtomicU32 = AtomicU32::new(0);
+
+/// Snapshot of the keyboard input counters
+#[derive(Debug, Clone, Copy)]
+pub struct KeyboardStats {
+    /// Scancodes read from the controller
+    pub received: u32,
+    /// Scancodes lost because the queue was full
+    pub dropped: u32,
+    /// Keys handed to the shell
+    pub decoded: u32,
 }
 
-impl ScancodeQueue {
-    const fn new() -> Self {
-        ScancodeQueue {
-            buffer: [0; SCANCODE_QUEUE_SIZE],
-            read_pos: 0,
-            write_pos: 0,
-        }
+pub fn stats() -> KeyboardStats {
+    KeyboardStats {
+        received: RECEIVED.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+        decoded: DECODED.load(Ordering::Relaxed),
+    }
+}
+
+static KEYBOARD_DECODER: Mutex<Decoder> = Mutex::new(Decoder::new(CodeSet::Set1, DEFAULT_LAYOUT));
+
+/// Whether the controller translates the keyboard's set 2 scancodes to set 1
+static TRANSLATION: AtomicBool = AtomicBool::new(true);
+
+/// The active keyboard layout
+pub fn layout() -> Layout {
+    KEYBOARD_DECODER.lock().layout()
+}
+
+pub fn set_layout(layout: Layout) {
+    KEYBOARD_DECODER.lock().set_layout(layout);
+}
+
+/// Scancode set the decoder expects
+pub fn code_set() -> CodeSet {
+    KEYBOARD_DECODER.lock().code_set()
+}
+
+pub fn translation() -> bool {
+    TRANSLATION.load(Ordering::Relaxed)
+}
+
+/// What `init` found on the controller, `None` if it never got that far
+static CONTROLLER: Mutex<Option<ControllerInfo>> = Mutex::new(None);
+
+/// The keyboard is on the second (aux) port, so its bytes come with the
+/// aux flag and its commands go through the controller's write-aux
+static KEYBOARD_ON_AUX: AtomicBool = AtomicBool::new(false);
+
+/// Cleared when `init` finds no keyboard, so the data port, which then
+/// only holds noise, is never read for keys
+static PRESENT: AtomicBool = AtomicBool::new(true);
+
+pub fn controller_info() -> Option<ControllerInfo> {
+    *CONTROLLER.lock()
+}
+
+/// False once `init` has established there is no keyboard to read
+pub fn present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+pub fn channel() -> Channel {
+    if KEYBOARD_ON_AUX.load(Ordering::Relaxed) { Channel::Second } else { Channel::First }
+}
+
+/// Hand a byte from the controller to the device that sent it
+pub fn route_byte(byte: u8, from_aux: bool) {
+    if from_aux == KEYBOARD_ON_AUX.load(Ordering::Relaxed) {
+        add_scancode(byte);
+    } else {
+        crate::mouse::handle_byte(byte);
     }
+}
+
+/// Bring the controller and keyboard to a known state instead of trusting
+/// the firmware: self-test the controller, probe both ports for the
+/// keyboard, reset it and select scancode set 2, then pick the decoder's
+/// set. Every wait is bounded, so a missing keyboard fails rather than
+/// hangs; when there is no controller or no keyboard at all, input is
+/// turned off (see `present`). Call before `init_interrupt_mode` unmasks
+/// the keyboard IRQ, so all replies are read here and none reach the
+/// scancode queue; the timer may already be ticking, as this runs with
+/// interrupts off.
+pub fn init() -> Result<(), Ps2Error> {
+    without_interrupts(|| {
+        if ps2::status() == STATUS_NO_CONTROLLER {
+            PRESENT.store(false, Ordering::Relaxed);
+            return Err(Ps2Error::NoController);
+        }
+        ps2::flush_output();
+
+        let config = ps2::read_config()?;
+        ps2::send_command(CMD_SELF_TEST)?;
+        match ps2::read_data()? {
+            SELF_TEST_PASSED => {}
+            other => return Err(Ps2Error::UnexpectedResponse(other)),
+        }
+        // Some controllers reset the config byte during the self-test
+        ps2::write_config(config)?;
 
-    fn push(&mut self, scancode: u8) -> Result<(), ()> {
-        let next_write = (self.write_pos + 1) % SCANCODE_QUEUE_SIZE;
-        if next_write == self.read_pos {
-            return Err(()); // Queue full
+        let info = ps2::probe()?;
+        *CONTROLLER.lock() = Some(info);
+        let Some(channel) = info.keyboard_channel() else {
+            PRESENT.store(false, Ordering::Relaxed);
+            return Err(Ps2Error::NoDevice);
+        };
+        if channel == Channel::Second {
+            KEYBOARD_ON_AUX.store(true, Ordering::Relaxed);
+            let config = ps2::read_config()?;
+            ps2::write_config((config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED)?;
         }
-        self.buffer[self.write_pos] = scancode;
-        self.write_pos = next_write;
-        Ok(())
+
+        send_command(CMD_RESET_KEYBOARD)?;
+        wait_for_bat()?;
+        send_command(CMD_SET_SCANCODE_SET)?;
+        send_command(KEYBOARD_SCANCODE_SET)
+    })?;
+    detect_code_set()
+}
+
+/// Wait out the keyboard's self-test after a reset
+fn wait_for_bat() -> Result<(), Ps2Error> {
+    for _ in 0..RESET_WAIT_ATTEMPTS {
+        match ps2::read_data() {
+            Ok(BAT_PASSED) => return Ok(()),
+            Ok(other) => return Err(Ps2Error::UnexpectedResponse(other)),
+            Err(Ps2Error::Timeout) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Choose the scancode set from the controller's translation bit: with it
+/// on, the keyboard's set 2 codes reach us as set 1; with it off (seen on
+/// some firmware and emulators) they arrive untranslated. Call before
+/// `init_interrupt_mode` unmasks the keyboard IRQ, since the config byte
+/// comes back through the data port; interrupts in general may already be
+/// on, the read runs with them off.
+pub fn detect_code_set() -> Result<(), Ps2Error> {
+    let config = without_interrupts(ps2::read_config)?;
+    // Only the first port is translated
+    let translation = config & CONFIG_TRANSLATION != 0 && channel() == Channel::First;
+    TRANSLATION.store(translation, Ordering::Relaxed);
+    let set = if translation { CodeSet::Set1 } else { CodeSet::Set2 };
+    KEYBOARD_DECODER.lock().set_code_set(set);
+    Ok(())
+}
+
+/// Held modifier keys, one bit per physical key so releasing one Shift
+/// while the other is down keeps Shift held. Tracked from raw key events
+/// since the decoded keys don't carry modifier state.
+static MODIFIER_KEYS: AtomicU8 = AtomicU8::new(0);
+
+const LSHIFT: u8 = 1 << 0;
+const RSHIFT: u8 = 1 << 1;
+const LCTRL: u8 = 1 << 2;
+const RCTRL: u8 = 1 << 3;
+const LALT: u8 = 1 << 4;
+const RALT: u8 = 1 << 5;
+
+/// `DROPPED` as last seen by `track_modifiers`
+static DROPPED_SEEN: AtomicU32 = AtomicU32::new(0);
+
+/// Modifier keys held when the most recent key event was read
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    /// Either Alt key
+    pub alt: bool,
+    /// Right Alt alone, which is AltGr on non-US layouts
+    pub alt_gr: bool,
+}
+
+/// Update the held modifiers. Events are handled in the order the keys
+/// were pressed, so this stays right even if the shell was busy when a
+/// modifier was released.
+fn track_modifiers(event: &KeyEvent) {
+    // A dropped scancode may have been a release; forget everything
+    // rather than leave a modifier stuck down
+    let dropped = DROPPED.load(Ordering::Relaxed);
+    if DROPPED_SEEN.swap(dropped, Ordering::Relaxed) != dropped {
+        MODIFIER_KEYS.store(0, Ordering::Relaxed);
     }
 
-    fn pop(&mut self) -> Option<u8> {
-        if self.read_pos == self.write_pos {
-            return None; // Queue empty
+    let bit = match event.code {
+        KeyCode::LShift => LSHIFT,
+        KeyCode::RShift => RSHIFT,
+        KeyCode::LControl => LCTRL,
+        KeyCode::RControl | KeyCode::RControl2 => RCTRL,
+        KeyCode::LAlt => LALT,
+        KeyCode::RAltGr | KeyCode::RAlt2 => RALT,
+        _ => return,
+    };
+    match event.state {
+        KeyState::Down => MODIFIER_KEYS.fetch_or(bit, Ordering::Relaxed),
+        KeyState::Up => MODIFIER_KEYS.fetch_and(!bit, Ordering::Relaxed),
+        KeyState::SingleShot => return,
+    };
+}
+
+/// Lock keys currently on. Num Lock starts on, matching the decoder.
+static LOCKS: AtomicU8 = AtomicU8::new(LED_NUM_LOCK);
+
+/// Lock key state, as shown on the keyboard LEDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locks {
+    pub caps: bool,
+    pub num: bool,
+    pub scroll: bool,
+}
+
+/// Toggle the lock state on a lock key press; true if anything changed
+fn track_locks(event: &KeyEvent) -> bool {
+    let bit = match event.code {
+        KeyCode::CapsLock => LED_CAPS_LOCK,
+        KeyCode::NumpadLock => LED_NUM_LOCK,
+        KeyCode::ScrollLock => LED_SCROLL_LOCK,
+        _ => return false,
+    };
+    if event.state != KeyState::Down {
+        return false;
+    }
+    LOCKS.fetch_xor(bit, Ordering::Relaxed);
+    true
+}
+
+pub fn locks() -> Locks {
+    let locks = LOCKS.load(Ordering::Relaxed);
+    Locks {
+        caps: locks & LED_CAPS_LOCK != 0,
+        num: locks & LED_NUM_LOCK != 0,
+        scroll: locks & LED_SCROLL_LOCK != 0,
+    }
+}
+
+/// Send a command byte to the keyboard and wait for its ACK. Runs with
+/// interrupts off, so it reads the controller itself: key bytes that
+/// arrive ahead of the ACK are queued as if the interrupt had taken them,
+/// mouse bytes go to the mouse, and a resend request is retried. Every
+/// wait is bounded, so a keyboard that never answers yields an error
+/// rather than a hang.
+fn send_command(byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..COMMAND_RETRIES {
+        ps2::write_device(channel(), byte)?;
+        let mut resend = false;
+        for _ in 0..MAX_BYTES_BEFORE_ACK {
+            let (data, from_aux) = ps2::read_data_tagged()?;
+            if from_aux != KEYBOARD_ON_AUX.load(Ordering::Relaxed) {
+                crate::mouse::handle_byte(data);
+                continue;
+            }
+            match data {
+                RESPONSE_ACK => return Ok(()),
+                RESPONSE_RESEND => {
+                    resend = true;
+                    break;
+                }
+                scancode => add_scancode(scancode),
+            }
         }
-        let scancode = self.buffer[self.read_pos];
-        self.read_pos = (self.read_pos + 1) % SCANCODE_QUEUE_SIZE;
-        Some(scancode)
+        if !resend {
+            return Err(Ps2Error::Timeout);
+        }
+    }
+    Err(Ps2Error::UnexpectedResponse(RESPONSE_RESEND))
+}
+
+/// Light the keyboard LEDs
+pub fn set_leds(caps: bool, num: bool, scroll: bool) -> Result<(), Ps2Error> {
+    let mut mask = 0;
+    if caps {
+        mask |= LED_CAPS_LOCK;
+    }
+    if num {
+        mask |= LED_NUM_LOCK;
+    }
+    if scroll {
+        mask |= LED_SCROLL_LOCK;
+    }
+    without_interrupts(|| {
+        send_command(CMD_SET_LEDS)?;
+        send_command(mask)
+    })
+}
+
+/// Make the LEDs match the current lock state
+pub fn sync_leds() -> Result<(), Ps2Error> {
+    let locks = locks();
+    set_leds(locks.caps, locks.num, locks.scroll)
+}
+
+/// Set how long a key is held before it repeats and how fast it repeats,
+/// as raw codes (see `constants::keyboard` for the encodings)
+pub fn set_typematic(delay: u8, rate: u8) -> Result<(), Ps2Error> {
+    let byte = (delay.min(TYPEMATIC_DELAY_MAX) << TYPEMATIC_DELAY_SHIFT) | rate.min(TYPEMATIC_RATE_MAX);
+    without_interrupts(|| {
+        send_command(CMD_SET_TYPEMATIC)?;
+        send_command(byte)
+    })
+}
+
+/// Delay code for a delay in ms, rounded to the nearest 250 ms step
+pub fn typematic_delay_code(ms: u32) -> Option<u8> {
+    let steps = (ms + TYPEMATIC_DELAY_STEP_MS / 2) / TYPEMATIC_DELAY_STEP_MS;
+    match steps {
+        1..=4 => Some(steps as u8 - 1),
+        _ => None,
+    }
+}
+
+/// Rate code whose repeat rate is closest to `cps` repeats per second
+pub fn typematic_rate_code(cps: u32) -> Option<u8> {
+    if !(TYPEMATIC_MIN_CPS..=TYPEMATIC_MAX_CPS).contains(&cps) {
+        return None;
     }
+    // Compare periods in microseconds rather than rates, to stay in integers
+    let wanted = 1_000_000 / cps;
+    (0..=TYPEMATIC_RATE_MAX).min_by_key(|&code| typematic_period_us(code).abs_diff(wanted))
+}
+
+fn typematic_period_us(code: u8) -> u32 {
+    let a = u32::from(code & 0x07);
+    let b = u32::from(code >> 3);
+    (8 + a) * (1 << b) * TYPEMATIC_PERIOD_UNIT_US
 }
 
-static SCANCODE_QUEUE: Mutex<ScancodeQueue> = Mutex::new(ScancodeQueue::new());
-static KEYBOARD_DECODER: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-    Mutex::new(Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
-    ));
+/// Which modifiers are held, as of the key event most recently returned
+/// by `get_key` or `get_key_event`
+pub fn modifiers() -> Modifiers {
+    let keys = MODIFIER_KEYS.load(Ordering::Relaxed);
+    Modifiers {
+        shift: keys & (LSHIFT | RSHIFT) != 0,
+        ctrl: keys & (LCTRL | RCTRL) != 0,
+        alt: keys & (LALT | RALT) != 0,
+        alt_gr: keys & RALT != 0,
+    }
+}
 
-/// Called from interrupt handler to add a scancode to the queue
+/// Queue a scancode for the decoder. Called from the interrupt handler (and
+/// the poller), so it must not print or lock anything: it may have
+/// interrupted the holder.
 pub fn add_scancode(scancode: u8) {
-    if let Err(_) = SCANCODE_QUEUE.lock().push(scancode) {
-        println!("WARNING: scancode queue full; dropping keyboard input");
+    if is_response(scancode) {
+        return;
+    }
+    RECEIVED.fetch_add(1, Ordering::Relaxed);
+    if !SCANCODE_QUEUE.push(scancode) {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
     }
 }
 
-/// Get decoded key events from keyboard port (POLLING MODE)
-pub fn get_key() -> Option<DecodedKey> {
+/// Set once `check_input` has had its look
+static INPUT_CHECKED: AtomicBool = AtomicBool::new(false);
+
+/// Warn once if the keyboard has sent nothing by `NO_INPUT_WARNING_MS`:
+/// a controller that passes its self-test can still have a dead or
+/// unplugged keyboard behind it. Called from the main loop.
+pub fn check_input() {
+    use crate::constants::keyboard::NO_INPUT_WARNING_MS;
+
+    if INPUT_CHECKED.load(Ordering::Relaxed) || crate::time::uptime_ms() < NO_INPUT_WARNING_MS {
+        return;
+    }
+    INPUT_CHECKED.store(true, Ordering::Relaxed);
+    if !present() || RECEIVED.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+    let secs = NO_INPUT_WARNING_MS / 1000;
+    if crate::serial::present() {
+        crate::shell::notice(format_args!(
+            "WARNING: no scancodes from the PS/2 keyboard in {} s; the serial terminal (COM1) also takes input",
+            secs
+        ));
+    } else {
+        crate::shell::notice(format_args!("WARNING: no scancodes from the PS/2 keyboard in {} s", secs));
+    }
+}
+
+/// Scancodes come from the IRQ1 queue; when false the port is polled
+static INTERRUPT_MODE: AtomicBool = AtomicBool::new(false);
+
+/// Tick at which a byte was first seen waiting in the controller without
+/// the IRQ picking it up, or `NOT_PENDING`
+static PENDING_SINCE: AtomicU64 = AtomicU64::new(NOT_PENDING);
+const NOT_PENDING: u64 = u64::MAX;
+
+/// Ticks a byte may sit unread in interrupt mode before we decide the IRQ
+/// isn't being delivered and go back to polling (half a second)
+const IRQ_TIMEOUT_TICKS: u64 = crate::constants::pit::TICK_HZ as u64 / 2;
+
+/// Port 0x80 reads (about 1us each) to wait for the first timer tick
+const TIMER_PROBE_READS: usize = 200_000;
+
+/// Switch to interrupt-driven input if the timer is ticking. Without timer
+/// interrupts the main loop's `hlt` would never wake up, so we stay in
+/// polling mode. Call after `interrupts::init`.
+pub fn init_interrupt_mode() {
     use x86_64::instructions::port::Port;
-    use crate::constants::keyboard::DATA_PORT;
 
-    let mut port = Port::new(DATA_PORT);
-    let mut decoder = KEYBOARD_DECODER.lock();
+    let start = crate::time::ticks();
+    let mut wait_port: Port<u8> = Port::new(0x80);
+    for _ in 0..TIMER_PROBE_READS {
+        if crate::time::ticks() != start {
+            if present() {
+                let irq = match channel() {
+                    Channel::First => 1,
+                    Channel::Second => crate::constants::mouse::IRQ,
+                };
+                crate::interrupts::unmask_irq(irq);
+            }
+            INTERRUPT_MODE.store(true, Ordering::Relaxed);
+            logln!("DEBUG: Keyboard IRQ unmasked, using interrupt-driven input");
+            return;
+        }
+        unsafe { wait_port.read() };
+    }
+    logln!("WARNING: no timer interrupts; keyboard stays in polling mode");
+}
 
-    // Poll the keyboard status register
-    let mut status_port = Port::<u8>::new(0x64);
-    let status = unsafe { status_port.read() };
+/// True while the keyboard is polled rather than interrupt-driven
+pub fn polling() -> bool {
+    !INTERRUPT_MODE.load(Ordering::Relaxed)
+}
 
-    // Check if output buffer is full (bit 0)
-    if (status & 0x01) != 0 {
-        // Read scancode from data port
-        let scancode = unsafe { port.read() };
+/// Whether a key handler used a key or passes it on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consumed {
+    Yes,
+    No,
+}
+
+pub type KeyHandler = fn(DecodedKey) -> Consumed;
+
+/// `subscribe` found every handler slot taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlersFull;
+
+/// Subscribed handlers, highest priority first; equal priorities keep
+/// the order they subscribed in
+static HANDLERS: Mutex<HandlerTable> = Mutex::new([None; MAX_KEY_HANDLERS]);
+
+type HandlerTable = [Option<(u8, KeyHandler)>; MAX_KEY_HANDLERS];
+
+/// Have `handler` offered every key `dispatch` reads. Handlers with a
+/// higher `priority` see a key first and can consume it before it reaches
+/// the ones below.
+pub fn subscribe(priority: u8, handler: KeyHandler) -> Result<(), HandlersFull> {
+    insert_handler(&mut HANDLERS.lock(), priority, handler)
+}
+
+fn insert_handler(handlers: &mut HandlerTable, priority: u8, handler: KeyHandler) -> Result<(), HandlersFull> {
+    let free = handlers.iter().position(Option::is_none).ok_or(HandlersFull)?;
+    // Ahead of the first lower priority, shifting it and the rest down
+    let at = handlers[..free]
+        .iter()
+        .position(|slot| matches!(slot, Some((existing, _)) if *existing < priority))
+        .unwrap_or(free);
+    handlers[at..=free].rotate_right(1);
+    handlers[at] = Some((priority, handler));
+    Ok(())
+}
+
+/// `remap` found every slot of the table taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemapsFull;
+
+/// Key translations set with `remap`, as (from, to)
+static REMAPS: Mutex<[Option<(DecodedKey, DecodedKey)>; MAX_REMAPS]> =
+    Mutex::new([None; MAX_REMAPS]);
+
+/// Make `from` produce `to` from now on, replacing any earlier mapping of
+/// `from`. Mapping a key to itself removes its mapping.
+pub fn remap(from: DecodedKey, to: DecodedKey) -> Result<(), RemapsFull> {
+    let mut remaps = REMAPS.lock();
+    if let Some(slot) = remaps.iter_mut().find(|slot| matches!(slot, Some((key, _)) if *key == from)) {
+        *slot = (from != to).then_some((from, to));
+        return Ok(());
+    }
+    if from == to {
+        return Ok(());
+    }
+    let slot = remaps.iter_mut().find(|slot| slot.is_none()).ok_or(RemapsFull)?;
+    *slot = Some((from, to));
+    Ok(())
+}
+
+pub fn clear_remaps() {
+    *REMAPS.lock() = [None; MAX_REMAPS];
+}
+
+/// The current mappings, as (from, to)
+pub fn remaps() -> impl Iterator<Item = (DecodedKey, DecodedKey)> {
+    let remaps = *REMAPS.lock();
+    remaps.into_iter().flatten()
+}
+
+/// Translate `key` through the remap table. Only one step is taken, so
+/// swapping two keys works and a cycle can't loop.
+fn apply_remap(key: DecodedKey) -> DecodedKey {
+    REMAPS
+        .lock()
+        .iter()
+        .flatten()
+        .find(|(from, _)| *from == key)
+        .map_or(key, |&(_, to)| to)
+}
+
+/// Whether Caps Lock is remapped. It then types its new key like any
+/// other instead of toggling the lock.
+fn caps_lock_remapped() -> bool {
+    let caps = DecodedKey::RawKey(KeyCode::CapsLock);
+    REMAPS.lock().iter().flatten().any(|(from, _)| *from == caps)
+}
+
+/// Toggle Caps Lock for a key remapped to it, in the decoder and on the
+/// LEDs, as if Caps Lock itself had been pressed
+fn press_caps_lock() {
+    let event = KeyEvent::new(KeyCode::CapsLock, KeyState::Down);
+    KEYBOARD_DECODER.lock().process_keyevent(event.clone());
+    if track_locks(&event) && sync_leds().is_err() {
+        logln!("WARNING: keyboard did not accept the LED update");
+    }
+}
+
+/// Names `key_from_name` accepts for keys that don't type a visible
+/// character
+const KEY_NAMES: [(&str, DecodedKey); 28] = [
+    ("esc", DecodedKey::Unicode('\u{1b}')),
+    ("capslock", DecodedKey::RawKey(KeyCode::CapsLock)),
+    ("tab", DecodedKey::Unicode('\t')),
+    ("enter", DecodedKey::Unicode('\n')),
+    ("backspace", DecodedKey::Unicode('\u{8}')),
+    ("space", DecodedKey::Unicode(' ')),
+    ("up", DecodedKey::RawKey(KeyCode::ArrowUp)),
+    ("down", DecodedKey::RawKey(KeyCode::ArrowDown)),
+    ("left", DecodedKey::RawKey(KeyCode::ArrowLeft)),
+    ("right", DecodedKey::RawKey(KeyCode::ArrowRight)),
+    ("home", DecodedKey::RawKey(KeyCode::Home)),
+    ("end", DecodedKey::RawKey(KeyCode::End)),
+    ("insert", DecodedKey::RawKey(KeyCode::Insert)),
+    ("delete", DecodedKey::RawKey(KeyCode::Delete)),
+    ("pgup", DecodedKey::RawKey(KeyCode::PageUp)),
+    ("pgdn", DecodedKey::RawKey(KeyCode::PageDown)),
+    ("f1", DecodedKey::RawKey(KeyCode::F1)),
+    ("f2", DecodedKey::RawKey(KeyCode::F2)),
+    ("f3", DecodedKey::RawKey(KeyCode::F3)),
+    ("f4", DecodedKey::RawKey(KeyCode::F4)),
+    ("f5", DecodedKey::RawKey(KeyCode::F5)),
+    ("f6", DecodedKey::RawKey(KeyCode::F6)),
+    ("f7", DecodedKey::RawKey(KeyCode::F7)),
+    ("f8", DecodedKey::RawKey(KeyCode::F8)),
+    ("f9", DecodedKey::RawKey(KeyCode::F9)),
+    ("f10", DecodedKey::RawKey(KeyCode::F10)),
+    ("f11", DecodedKey::RawKey(KeyCode::F11)),
+    ("f12", DecodedKey::RawKey(KeyCode::F12)),
+];
+
+/// A key by name ("esc", "f1", ...) or as the single character it types
+pub fn key_from_name(name: &str) -> Option<DecodedKey> {
+    let named = KEY_NAMES.iter().find(|(candidate, _)| candidate.eq_ignore_ascii_case(name));
+    if let Some(&(_, key)) = named {
+        return Some(key);
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(DecodedKey::Unicode(c)),
+        _ => None,
+    }
+}
+
+/// Shows a key the way `key_from_name` would accept it
+pub struct KeyName(pub DecodedKey);
+
+impl fmt::Display for KeyName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match KEY_NAMES.iter().find(|(_, key)| *key == self.0) {
+            Some((name, _)) => write!(f, "{}", name),
+            None => match self.0 {
+                DecodedKey::Unicode(c) => write!(f, "{}", c),
+                DecodedKey::RawKey(code) => write!(f, "{:?}", code),
+            },
+        }
+    }
+}
+
+/// Hand every pending key to the subscribed handlers, in priority order,
+/// until one consumes it. Modal readers such as the pager use `wait_key`
+/// instead and don't go through the handlers.
+pub fn dispatch() {
+    while let Some(key) = get_key() {
+        // Copied so a handler can run a long command, or subscribe,
+        // without the table locked
+        let handlers = *HANDLERS.lock();
+        for (_, handler) in handlers.into_iter().flatten() {
+            if handler(key) == Consumed::Yes {
+                break;
+            }
+        }
+    }
+}
+
+/// Get the next decoded key, if any, from the keyboard or else COM1.
+/// Releases and modifier keys are consumed without producing anything.
+pub fn get_key() -> Option<DecodedKey> {
+    while let Some(event) = get_key_event() {
+        let code = event.code;
+        if polling() && !repeat_track(&event) {
+            continue;
+        }
+        if let Some(key) = decode_event(event).map(apply_remap) {
+            if key == DecodedKey::RawKey(KeyCode::CapsLock) {
+                press_caps_lock();
+                continue;
+            }
+            if polling() {
+                repeat_start(code, key);
+            }
+            return Some(key);
+        }
+    }
+    if polling() {
+        if let Some(key) = repeat_due() {
+            return Some(key);
+        }
+    }
+    // A serial terminal types into the same stream, so the shell and
+    // anything waiting on a key also work without a keyboard
+    crate::serial::read_key()
+}
+
+/// A key held down in polling mode, repeated in software
+struct Repeat {
+    code: KeyCode,
+    key: DecodedKey,
+    /// Tick at which the next repeat is due
+    next: u64,
+}
+
+static REPEAT: Mutex<Option<Repeat>> = Mutex::new(None);
+static REPEAT_DELAY_TICKS: AtomicU64 = AtomicU64::new((SOFT_REPEAT_DELAY_MS as u64 * TICK_HZ as u64).div_ceil(1000));
+static REPEAT_PERIOD_TICKS: AtomicU64 = AtomicU64::new((TICK_HZ as u64).div_ceil(SOFT_REPEAT_CPS as u64));
+
+/// Set the software repeat used in polling mode: the first repeat after
+/// `delay_ms`, then `cps` a second. Repeats are timed by the timer
+/// interrupt, so there are none if it isn't running.
+pub fn set_soft_repeat(delay_ms: u32, cps: u32) {
+    use crate::time::ms_to_ticks;
+
+    REPEAT_DELAY_TICKS.store(ms_to_ticks(delay_ms.into()), Ordering::Relaxed);
+    let period = u64::from(TICK_HZ).div_ceil(u64::from(cps.max(1)));
+    REPEAT_PERIOD_TICKS.store(period, Ordering::Relaxed);
+}
+
+/// Modifier and lock keys, which never repeat
+fn is_modifier_or_lock(code: KeyCode) -> bool {
+    matches!(
+        code,
+        KeyCode::LShift
+            | KeyCode::RShift
+            | KeyCode::LControl
+            | KeyCode::RControl
+            | KeyCode::RControl2
+            | KeyCode::LAlt
+            | KeyCode::RAltGr
+            | KeyCode::RAlt2
+            | KeyCode::LWin
+            | KeyCode::RWin
+            | KeyCode::CapsLock
+            | KeyCode::NumpadLock
+            | KeyCode::ScrollLock
+    )
+}
+
+/// Update the software repeat for a key event. A release of the repeating
+/// key stops it, and so does pressing another key. Returns false for the
+/// keyboard's own repeats of the key already being repeated, which would
+/// double the rate.
+fn repeat_track(event: &KeyEvent) -> bool {
+    let mut repeat = REPEAT.lock();
+    let same_key = repeat.as_ref().is_some_and(|repeat| repeat.code == event.code);
+    match event.state {
+        KeyState::Down if same_key => false,
+        KeyState::Up if !same_key => true,
+        KeyState::Down if is_modifier_or_lock(event.code) => true,
+        _ => {
+            *repeat = None;
+            true
+        }
+    }
+}
+
+/// Start repeating a freshly pressed key
+fn repeat_start(code: KeyCode, key: DecodedKey) {
+    if is_modifier_or_lock(code) {
+        return;
+    }
+    let delay = REPEAT_DELAY_TICKS.load(Ordering::Relaxed);
+    *REPEAT.lock() = Some(Repeat { code, key, next: crate::time::ticks() + delay });
+}
+
+/// The repeating key, if its next repeat is due
+fn repeat_due() -> Option<DecodedKey> {
+    let mut repeat = REPEAT.lock();
+    let repeat = repeat.as_mut()?;
+    if crate::time::ticks() < repeat.next {
+        return None;
+    }
+    repeat.next = crate::time::ticks() + REPEAT_PERIOD_TICKS.load(Ordering::Relaxed);
+    DECODED.fetch_add(1, Ordering::Relaxed);
+    Some(repeat.key)
+}
+
+/// Get the next raw key event (key code and press/release), before any
+/// layout or modifier processing
+pub fn get_key_event() -> Option<KeyEvent> {
+    while let Some(scancode) = next_scancode() {
+        if let Some(event) = decode_byte(scancode) {
+            return Some(event);
+        }
+    }
+    None
+}
+
+/// Get the next scancode byte along with what it decoded to, for
+/// diagnostics. The event and key are `None` for bytes that only start
+/// a sequence (such as an E0 prefix) and for keys that produce nothing.
+pub fn get_key_debug() -> Option<(u8, Option<KeyEvent>, Option<DecodedKey>)> {
+    let scancode = next_scancode()?;
+    let event = decode_byte(scancode);
+    let key = event.clone().and_then(decode_event);
+    Some((scancode, event, key))
+}
+
+/// Feed one scancode byte to the decoder, tracking modifiers and lock
+/// keys when it completes a key event
+fn decode_byte(scancode: u8) -> Option<KeyEvent> {
+    let event = KEYBOARD_DECODER.lock().add_byte(scancode).ok()??;
+    track_modifiers(&event);
+    let remapped_lock = event.code == KeyCode::CapsLock && caps_lock_remapped();
+    if !remapped_lock && track_locks(&event) && sync_leds().is_err() {
+        logln!("WARNING: keyboard did not accept the LED update");
+    }
+    Some(event)
+}
+
+/// Keypad keys with the character they type while Num Lock is on and the
+/// navigation key they act as while it is off
+const KEYPAD: [(KeyCode, char, Option<KeyCode>); 11] = [
+    (KeyCode::Numpad0, '0', Some(KeyCode::Insert)),
+    (KeyCode::Numpad1, '1', Some(KeyCode::End)),
+    (KeyCode::Numpad2, '2', Some(KeyCode::ArrowDown)),
+    (KeyCode::Numpad3, '3', Some(KeyCode::PageDown)),
+    (KeyCode::Numpad4, '4', Some(KeyCode::ArrowLeft)),
+    (KeyCode::Numpad5, '5', None),
+    (KeyCode::Numpad6, '6', Some(KeyCode::ArrowRight)),
+    (KeyCode::Numpad7, '7', Some(KeyCode::Home)),
+    (KeyCode::Numpad8, '8', Some(KeyCode::ArrowUp)),
+    (KeyCode::Numpad9, '9', Some(KeyCode::PageUp)),
+    (KeyCode::NumpadPeriod, '.', Some(KeyCode::Delete)),
+];
+
+/// What a keypad key produces given the Num Lock state: `None` if `code`
+/// isn't one of the keypad's digit keys, `Some(None)` if it does nothing
+/// (keypad 5 with Num Lock off)
+fn keypad_key(code: KeyCode, num_lock: bool) -> Option<Option<DecodedKey>> {
+    let &(_, digit, navigation) = KEYPAD.iter().find(|(key, ..)| *key == code)?;
+    Some(if num_lock { Some(DecodedKey::Unicode(digit)) } else { navigation.map(DecodedKey::RawKey) })
+}
+
+/// Map a key event through the layout. The keypad is handled here rather
+/// than by the layout so it always follows our Num Lock state, the one on
+/// the LED.
+fn decode_event(mut event: KeyEvent) -> Option<DecodedKey> {
+    // A remapped Caps Lock is left for `apply_remap` to translate, and
+    // must not reach the layout decoder, which would toggle its own state
+    if event.code == KeyCode::CapsLock && caps_lock_remapped() {
+        return (event.state == KeyState::Down).then_some(DecodedKey::RawKey(KeyCode::CapsLock));
+    }
+    if let Some(key) = keypad_key(event.code, locks().num) {
+        if event.state == KeyState::Up {
+            return None;
+        }
+        if key.is_some() {
+            DECODED.fetch_add(1, Ordering::Relaxed);
+        }
+        return key;
+    }
+    // AltGr characters come from our own tables, the same for every
+    // version of the layout decoders
+    if event.state == KeyState::Down && modifiers().alt_gr {
+        if let Some(c) = layout().alt_gr(event.code) {
+            DECODED.fetch_add(1, Ordering::Relaxed);
+            return Some(DecodedKey::Unicode(c));
+        }
+    }
+    // Keypad Enter is just Enter
+    if event.code == KeyCode::NumpadEnter {
+        event.code = KeyCode::Return;
+    }
+    // The layouts turn Delete into DEL (U+007F), which reads like a
+    // backspace; keep it a raw key, the same as keypad Delete
+    if event.code == KeyCode::Delete {
+        if event.state != KeyState::Down {
+            return None;
+        }
+        DECODED.fetch_add(1, Ordering::Relaxed);
+        return Some(DecodedKey::RawKey(KeyCode::Delete));
+    }
+    let key = KEYBOARD_DECODER.lock().process_keyevent(event)?;
+    DECODED.fetch_add(1, Ordering::Relaxed);
+    Some(key)
+}
 
-        // Decode it
-        if let Ok(Some(key_event)) = decoder.add_byte(scancode) {
-            if let Some(key) = decoder.process_keyevent(key_event) {
-                return Some(key);
+/// ACK/resend bytes from the keyboard controller, which are answers to
+/// commands rather than key scancodes and must not reach the decoder
+fn is_response(byte: u8) -> bool {
+    byte == RESPONSE_ACK || byte == RESPONSE_RESEND
+}
+
+/// Wait for the next interrupt when input is interrupt-driven; in polling
+/// mode nothing would wake us for a key, so return straight away
+pub fn idle() {
+    if !polling() {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Discard input typed but not read yet: the queued scancodes, and in
+/// polling mode what is still waiting in the controller. The bytes are
+/// decoded and the keys dropped, so modifier and lock key changes still
+/// count; then any half-received sequence is forgotten so it can't corrupt
+/// the next key. Call before reading an answer that must not be typed
+/// ahead, like a confirmation.
+pub fn flush() {
+    if polling() {
+        for _ in 0..FLUSH_LIMIT {
+            poll_scancode();
+        }
+    }
+    while let Some(scancode) = SCANCODE_QUEUE.pop() {
+        decode_byte(scancode);
+    }
+    KEYBOARD_DECODER.lock().reset_sequence();
+    *REPEAT.lock() = None;
+}
+
+/// Block until a key is decoded. Interrupt-driven input sleeps in `hlt`
+/// between keys; polling input checks the port with a short pause. Call
+/// from the main loop or a shell command, never from an interrupt handler.
+pub fn wait_key() -> DecodedKey {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    loop {
+        if let Some(key) = get_key() {
+            return key;
+        }
+        if polling() {
+            let mut wait_port: Port<u8> = Port::new(0x80);
+            for _ in 0..POLL_PAUSE_READS {
+                unsafe { wait_port.read() };
+            }
+        } else {
+            // Check and sleep with interrupts off, so a scancode queued in
+            // between still wakes us instead of waiting for the next tick
+            interrupts::disable();
+            if SCANCODE_QUEUE.is_empty() {
+                interrupts::enable_and_hlt();
+            } else {
+                interrupts::enable();
             }
         }
     }
+}
+
+/// Next byte for the decoder. Every byte goes through the queue, whether
+/// the interrupt or the poll below collected it, so the bytes of a
+/// multi-byte sequence always reach the decoder in the order they arrived.
+fn next_scancode() -> Option<u8> {
+    if !present() {
+        return None;
+    }
+    if polling() {
+        poll_scancode();
+    }
+    if let Some(scancode) = SCANCODE_QUEUE.pop() {
+        return Some(scancode);
+    }
 
+    if !polling() {
+        check_irq_delivery();
+    }
     None
 }
 
-/// Send reset command to keyboard controller (for reboot)
+/// Fall back to polling if the controller holds a byte that no interrupt
+/// has collected for `IRQ_TIMEOUT_TICKS`
+fn check_irq_delivery() {
+    use x86_64::instructions::port::Port;
+    use crate::constants::keyboard::{STATUS_COMMAND_PORT, STATUS_OUTPUT_BUFFER_FULL};
+
+    let mut status_port = Port::<u8>::new(STATUS_COMMAND_PORT);
+    let status = unsafe { status_port.read() };
+    if status & STATUS_OUTPUT_BUFFER_FULL == 0 {
+        PENDING_SINCE.store(NOT_PENDING, Ordering::Relaxed);
+        return;
+    }
+
+    let now = crate::time::ticks();
+    let since = PENDING_SINCE.load(Ordering::Relaxed);
+    if since == NOT_PENDING {
+        PENDING_SINCE.store(now, Ordering::Relaxed);
+    } else if now.wrapping_sub(since) >= IRQ_TIMEOUT_TICKS {
+        INTERRUPT_MODE.store(false, Ordering::Relaxed);
+        PENDING_SINCE.store(NOT_PENDING, Ordering::Relaxed);
+        logln!("WARNING: keyboard IRQ not firing; falling back to polling");
+    }
+}
+
+/// Move one byte from the controller to the queue (POLLING MODE). Runs
+/// with interrupts off so a late keyboard IRQ can't take the byte between
+/// the status check and the read.
+fn poll_scancode() {
+    use x86_64::instructions::port::Port;
+    use crate::constants::keyboard::{DATA_PORT, STATUS_COMMAND_PORT, STATUS_OUTPUT_BUFFER_FULL, STATUS_AUX_DATA};
+
+    without_interrupts(|| {
+        let mut port = Port::new(DATA_PORT);
+
+        // Poll the keyboard status register
+        let mut status_port = Port::<u8>::new(STATUS_COMMAND_PORT);
+        let status = unsafe { status_port.read() };
+
+        if status & STATUS_OUTPUT_BUFFER_FULL == 0 {
+            return;
+        }
+        let scancode = unsafe { port.read() };
+
+        // Bytes from the mouse share the data port; never decode them
+        route_byte(scancode, status & STATUS_AUX_DATA != 0);
+    });
+}
+
+/// Reboot the machine. Tries, in order:
+///
+/// 1. Pulsing the CPU reset line through the keyboard controller (0xFE)
+/// 2. If that hasn't reset us after a short wait, loading an empty IDT and
+///    raising an exception: with no handlers the CPU triple faults, which
+///    resets it on any PC-compatible machine
 pub fn reset_cpu() -> ! {
     use x86_64::instructions::port::Port;
-    use crate::constants::keyboard::{STATUS_COMMAND_PORT, CMD_RESET_CPU};
+    use x86_64::instructions::tables::lidt;
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+    use crate::constants::keyboard::{CMD_RESET_CPU, RESET_WAIT_READS};
+
+    x86_64::instructions::interrupts::disable();
 
-    unsafe {
-        let mut port = Port::<u8>::new(STATUS_COMMAND_PORT);
-        port.write(CMD_RESET_CPU);
+    // 1. Keyboard controller reset pulse
+    if ps2::send_command(CMD_RESET_CPU).is_ok() {
+        // Each port 0x80 read takes about a microsecond
+        let mut wait_port: Port<u8> = Port::new(0x80);
+        for _ in 0..RESET_WAIT_READS {
+            unsafe { wait_port.read() };
+        }
     }
 
+    // 2. Triple fault: int3 can't be delivered, nor can the #GP or double
+    // fault that follow it
+    let empty_idt = DescriptorTablePointer { limit: 0, base: VirtAddr::zero() };
+    unsafe { lidt(&empty_idt) };
+    x86_64::instructions::interrupts::int3();
+
     loop {
         x86_64::instructions::hlt();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn remap_swaps_without_looping() {
+        let a = DecodedKey::Unicode('a');
+        let esc = key_from_name("ESC").unwrap();
+        assert_eq!(remap(a, esc), Ok(()));
+        assert_eq!(remap(esc, a), Ok(()));
+        assert_eq!(apply_remap(a), esc);
+        assert_eq!(apply_remap(esc), a);
+        // Mapping a key to itself drops the mapping
+        assert_eq!(remap(a, a), Ok(()));
+        assert_eq!(apply_remap(a), a);
+        clear_remaps();
+        assert_eq!(remaps().count(), 0);
+    }
+
+    #[test_case]
+    fn caps_lock_can_be_remapped() {
+        let caps = key_from_name("capslock").unwrap();
+        let esc = key_from_name("esc").unwrap();
+        assert_eq!(remap(caps, esc), Ok(()));
+        let press = KeyEvent::new(KeyCode::CapsLock, KeyState::Down);
+        assert_eq!(decode_event(press).map(apply_remap), Some(esc));
+        assert_eq!(decode_event(KeyEvent::new(KeyCode::CapsLock, KeyState::Up)), None);
+        clear_remaps();
+    }
+
+    #[test_case]
+    fn typematic_codes_cover_the_documented_range() {
+        assert_eq!(typematic_rate_code(30), Some(0x00));
+        assert_eq!(typematic_rate_code(2), Some(0x1F));
+        assert_eq!(typematic_rate_code(10), Some(0x0C));
+        assert_eq!(typematic_rate_code(31), None);
+        assert_eq!(typematic_delay_code(250), Some(0));
+        assert_eq!(typematic_delay_code(1000), Some(3));
+        assert_eq!(typematic_delay_code(100), None);
+    }
+
+    #[test_case]
+    fn handlers_ordered_by_priority() {
+        fn pass(_: DecodedKey) -> Consumed {
+            Consumed::No
+        }
+        fn take(_: DecodedKey) -> Consumed {
+            Consumed::Yes
+        }
+
+        let mut table: HandlerTable = [None; MAX_KEY_HANDLERS];
+        assert_eq!(insert_handler(&mut table, 1, take), Ok(()));
+        assert_eq!(insert_handler(&mut table, 5, pass), Ok(()));
+        assert_eq!(insert_handler(&mut table, 1, pass), Ok(()));
+        let priorities: [Option<u8>; 3] = core::array::from_fn(|i| table[i].map(|(p, _)| p));
+        assert_eq!(priorities, [Some(5), Some(1), Some(1)]);
+        // Equal priorities stay in subscription order
+        let key = DecodedKey::Unicode('a');
+        assert_eq!(table[1].map(|(_, handler)| handler(key)), Some(Consumed::Yes));
+        for _ in 3..MAX_KEY_HANDLERS {
+            assert_eq!(insert_handler(&mut table, 0, pass), Ok(()));
+        }
+        assert_eq!(insert_handler(&mut table, 9, pass), Err(HandlersFull));
+    }
+
+    #[test_case]
+    fn keypad_follows_num_lock() {
+        let digit = DecodedKey::Unicode('8');
+        let up = DecodedKey::RawKey(KeyCode::ArrowUp);
+        assert_eq!(keypad_key(KeyCode::Numpad8, true), Some(Some(digit)));
+        assert_eq!(keypad_key(KeyCode::Numpad8, false), Some(Some(up)));
+        assert_eq!(keypad_key(KeyCode::Numpad5, false), Some(None));
+        assert_eq!(keypad_key(KeyCode::Key8, false), None);
+    }
+
+    #[test_case]
+    fn modifier_held_until_both_sides_released() {
+        let event = KeyEvent::new;
+        MODIFIER_KEYS.store(0, Ordering::Relaxed);
+
+        track_modifiers(&event(KeyCode::LShift, KeyState::Down));
+        track_modifiers(&event(KeyCode::RShift, KeyState::Down));
+        track_modifiers(&event(KeyCode::LShift, KeyState::Up));
+        assert!(modifiers().shift);
+
+        track_modifiers(&event(KeyCode::RShift, KeyState::Up));
+        track_modifiers(&event(KeyCode::LControl, KeyState::Down));
+        assert_eq!(modifiers(), Modifiers { shift: false, ctrl: true, alt: false, alt_gr: false });
+
+        track_modifiers(&event(KeyCode::LControl, KeyState::Up));
+        assert_eq!(modifiers(), Modifiers::default());
+    }
+}
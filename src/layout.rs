@@ -0,0 +1,376 @@
+//! Keyboard layouts and scancode sets selectable at runtime
+//!
+//! `pc_keyboard`'s decoders are generic over the layout and scancode set,
+//! so each choice gets its own concrete decoder and `Decoder` dispatches
+//! between them.
+
+use pc_keyboard::{
+    layouts, DecodedKey, Error, EventDecoder, HandleControl, KeyCode, KeyEvent, KeyState, Modifiers,
+    ScancodeSet, ScancodeSet1, ScancodeSet2,
+};
+use crate::constants::keyboard::{PAUSE_PREFIX, PAUSE_SEQUENCE_LEN_SET1, PAUSE_SEQUENCE_LEN_SET2};
+
+#[cfg(not(feature = "us"))]
+compile_error!("the `us` layout feature must be enabled: it is the boot layout");
+
+/// Generates `Layout`, its name table and the matching `Events` decoder
+/// from one list, so each entry is compiled in only with its feature
+macro_rules! layouts {
+    ($($feature:literal => $variant:ident($keys:path), $name:literal, $description:literal;)+) => {
+        /// A keyboard layout the decoder can be switched to
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Layout {
+            $(#[cfg(feature = $feature)] $variant,)+
+        }
+
+        impl Layout {
+            /// The layouts compiled into this kernel
+            pub const ALL: &'static [Layout] = &[$(#[cfg(feature = $feature)] Layout::$variant,)+];
+
+            /// Short name used by the `layout` command
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(#[cfg(feature = $feature)] Layout::$variant => $name,)+
+                }
+            }
+
+            pub fn description(self) -> &'static str {
+                match self {
+                    $(#[cfg(feature = $feature)] Layout::$variant => $description,)+
+                }
+            }
+        }
+
+        /// Turns key events into characters for one of the supported layouts.
+        /// Ctrl+letter is mapped to the matching control character (Ctrl+C is
+        /// U+0003).
+        enum Events {
+            $(#[cfg(feature = $feature)] $variant(EventDecoder<$keys>),)+
+        }
+
+        impl Events {
+            const fn new(layout: Layout) -> Events {
+                const CTRL: HandleControl = HandleControl::MapLettersToUnicode;
+                match layout {
+                    $(#[cfg(feature = $feature)] Layout::$variant => Events::$variant(EventDecoder::new($keys, CTRL)),)+
+                }
+            }
+
+            fn layout(&self) -> Layout {
+                match self {
+                    $(#[cfg(feature = $feature)] Events::$variant(_) => Layout::$variant,)+
+                }
+            }
+
+            fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+                match self {
+                    $(#[cfg(feature = $feature)] Events::$variant(decoder) => decoder.process_keyevent(event),)+
+                }
+            }
+
+            fn modifiers(&self) -> &Modifiers {
+                match self {
+                    $(#[cfg(feature = $feature)] Events::$variant(decoder) => decoder.get_modifiers(),)+
+                }
+            }
+        }
+    };
+}
+
+// Each layout is behind the Cargo feature of the same name
+layouts! {
+    "us" => Us(layouts::Us104Key), "us", "US 104-key";
+    "uk" => Uk(layouts::Uk105Key), "uk", "UK 105-key";
+    "de" => De(layouts::De105Key), "de", "German 105-key";
+    "dvorak" => Dvorak(layouts::Dvorak104Key), "dvorak", "Dvorak 104-key";
+}
+
+/// Layout used at boot
+pub const DEFAULT_LAYOUT: Layout = Layout::Us;
+
+impl Layout {
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.iter().copied().find(|layout| layout.name() == name)
+    }
+
+    /// Character typed by `code` with AltGr held, if the layout has one
+    pub fn alt_gr(self, code: KeyCode) -> Option<char> {
+        let table: &[(KeyCode, char)] = match self {
+            #[cfg(feature = "de")]
+            Layout::De => &DE_ALT_GR,
+            #[cfg(feature = "uk")]
+            Layout::Uk => &UK_ALT_GR,
+            _ => &[],
+        };
+        table.iter().find(|(key, _)| *key == code).map(|&(_, c)| c)
+    }
+}
+
+/// AltGr characters of the German layout, by the key's US position name
+#[cfg(feature = "de")]
+const DE_ALT_GR: [(KeyCode, char); 12] = [
+    (KeyCode::Key2, '²'),
+    (KeyCode::Key3, '³'),
+    (KeyCode::Key7, '{'),
+    (KeyCode::Key8, '['),
+    (KeyCode::Key9, ']'),
+    (KeyCode::Key0, '}'),
+    (KeyCode::OemMinus, '\\'),
+    (KeyCode::Q, '@'),
+    (KeyCode::E, '€'),
+    (KeyCode::Oem6, '~'),
+    (KeyCode::M, 'µ'),
+    // The extra ISO key left of Z (< > |)
+    (KeyCode::Oem5, '|'),
+];
+
+/// AltGr characters of the UK layout
+#[cfg(feature = "uk")]
+const UK_ALT_GR: [(KeyCode, char); 2] = [
+    (KeyCode::Key4, '€'),
+    // The key left of 1 (` ¬ ¦)
+    (KeyCode::Oem8, '¦'),
+];
+
+/// Scancode set the keyboard's bytes are decoded as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeSet {
+    /// Set 1, what the controller produces with translation on
+    Set1,
+    /// Set 2, the keyboard's native set, seen with translation off
+    Set2,
+}
+
+impl CodeSet {
+    pub fn number(self) -> u8 {
+        match self {
+            CodeSet::Set1 => 1,
+            CodeSet::Set2 => 2,
+        }
+    }
+}
+
+/// Turns raw bytes into key events; holds any partial multi-byte sequence
+enum Scancodes {
+    Set1(ScancodeSet1),
+    Set2(ScancodeSet2),
+}
+
+impl Scancodes {
+    const fn new(set: CodeSet) -> Scancodes {
+        match set {
+            CodeSet::Set1 => Scancodes::Set1(ScancodeSet1::new()),
+            CodeSet::Set2 => Scancodes::Set2(ScancodeSet2::new()),
+        }
+    }
+
+    fn code_set(&self) -> CodeSet {
+        match self {
+            Scancodes::Set1(_) => CodeSet::Set1,
+            Scancodes::Set2(_) => CodeSet::Set2,
+        }
+    }
+
+    fn advance_state(&mut self, byte: u8) -> Result<Option<KeyEvent>, Error> {
+        match self {
+            Scancodes::Set1(set) => set.advance_state(byte),
+            Scancodes::Set2(set) => set.advance_state(byte),
+        }
+    }
+}
+
+/// Keyboard decoder with a runtime-selectable scancode set and layout.
+/// The two halves are kept apart so switching layout never disturbs a
+/// multi-byte scancode sequence that is halfway through.
+pub struct Decoder {
+    scancodes: Scancodes,
+    events: Events,
+    /// Bytes of a Pause/Break sequence still to be swallowed
+    pause_remaining: u8,
+}
+
+impl Decoder {
+    pub const fn new(set: CodeSet, layout: Layout) -> Decoder {
+        Decoder {
+            scancodes: Scancodes::new(set),
+            events: Events::new(layout),
+            pause_remaining: 0,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.events.layout()
+    }
+
+    /// Switch layout, keeping the held modifiers and lock keys
+    pub fn set_layout(&mut self, layout: Layout) {
+        // The new decoder starts with nothing held and only Num Lock on, so
+        // press the keys that bring it to the old state
+        let old = self.events.modifiers();
+        let presses = [
+            (old.lshift, KeyCode::LShift),
+            (old.rshift, KeyCode::RShift),
+            (old.lctrl, KeyCode::LControl),
+            (old.rctrl, KeyCode::RControl),
+            (old.lalt, KeyCode::LAlt),
+            (old.ralt, KeyCode::RAltGr),
+            (old.capslock, KeyCode::CapsLock),
+            (!old.numlock, KeyCode::NumpadLock),
+        ];
+        self.events = Events::new(layout);
+        for (_, code) in presses.into_iter().filter(|&(pressed, _)| pressed) {
+            self.events.process_keyevent(KeyEvent::new(code, KeyState::Down));
+        }
+    }
+
+    pub fn code_set(&self) -> CodeSet {
+        self.scancodes.code_set()
+    }
+
+    /// Switch scancode set, dropping any partly received sequence
+    pub fn set_code_set(&mut self, set: CodeSet) {
+        self.scancodes = Scancodes::new(set);
+        self.pause_remaining = 0;
+    }
+
+    /// Drop any partly received sequence, keeping set and layout
+    pub fn reset_sequence(&mut self) {
+        self.set_code_set(self.code_set());
+    }
+
+    /// Feed one scancode byte; returns the key event once a key's sequence
+    /// is complete. Pause/Break comes out as a single `SingleShot` event.
+    pub fn add_byte(&mut self, byte: u8) -> Result<Option<KeyEvent>, Error> {
+        // The scancode sets would read the tail of the Pause sequence as
+        // ordinary keys (Ctrl and Num Lock), so it never reaches them
+        if self.pause_remaining > 0 {
+            self.pause_remaining -= 1;
+            if self.pause_remaining == 0 {
+                return Ok(Some(KeyEvent::new(KeyCode::PauseBreak, KeyState::SingleShot)));
+            }
+            return Ok(None);
+        }
+        if byte == PAUSE_PREFIX {
+            let len = match self.code_set() {
+                CodeSet::Set1 => PAUSE_SEQUENCE_LEN_SET1,
+                CodeSet::Set2 => PAUSE_SEQUENCE_LEN_SET2,
+            };
+            self.pause_remaining = len - 1;
+            return Ok(None);
+        }
+        self.scancodes.advance_state(byte)
+    }
+
+    pub fn process_keyevent(&mut self, event: KeyEvent) -> Option<DecodedKey> {
+        self.events.process_keyevent(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "de")]
+    #[test_case]
+    fn layout_switch_keeps_partial_sequence() {
+        let mut decoder = Decoder::new(CodeSet::Set1, Layout::Us);
+        // Extended prefix of Up Arrow; the switch must not drop it
+        assert_eq!(decoder.add_byte(0xE0), Ok(None));
+        decoder.set_layout(Layout::De);
+        assert_eq!(decoder.layout(), Layout::De);
+        assert!(decoder.add_byte(0x48).unwrap().is_some());
+    }
+
+    #[cfg(feature = "uk")]
+    #[test_case]
+    fn layout_switch_keeps_caps_lock() {
+        let mut decoder = Decoder::new(CodeSet::Set1, Layout::Us);
+        decoder.process_keyevent(KeyEvent::new(KeyCode::CapsLock, KeyState::Down));
+        decoder.process_keyevent(KeyEvent::new(KeyCode::CapsLock, KeyState::Up));
+        decoder.set_layout(Layout::Uk);
+        let key = decoder.process_keyevent(KeyEvent::new(KeyCode::A, KeyState::Down));
+        assert_eq!(key, Some(DecodedKey::Unicode('A')));
+    }
+
+    /// Feed `bytes` and collect the events they complete
+    fn events(decoder: &mut Decoder, bytes: &[u8]) -> ([Option<KeyEvent>; 8], usize) {
+        let mut out = [const { None }; 8];
+        let mut count = 0;
+        for &byte in bytes {
+            if let Ok(Some(event)) = decoder.add_byte(byte) {
+                out[count] = Some(event);
+                count += 1;
+            }
+        }
+        (out, count)
+    }
+
+    #[test_case]
+    fn extended_navigation_keys() {
+        let keys = [
+            (0x47, KeyCode::Home),
+            (0x4F, KeyCode::End),
+            (0x53, KeyCode::Delete),
+            (0x52, KeyCode::Insert),
+            (0x49, KeyCode::PageUp),
+            (0x51, KeyCode::PageDown),
+        ];
+        let mut decoder = Decoder::new(CodeSet::Set1, Layout::Us);
+        for (code, key) in keys {
+            let (found, count) = events(&mut decoder, &[0xE0, code, 0xE0, code | 0x80]);
+            assert_eq!(count, 2);
+            assert_eq!(found[0], Some(KeyEvent::new(key, KeyState::Down)));
+            assert_eq!(found[1], Some(KeyEvent::new(key, KeyState::Up)));
+        }
+    }
+
+    #[test_case]
+    fn pause_is_one_event() {
+        let mut decoder = Decoder::new(CodeSet::Set1, Layout::Us);
+        let (found, count) = events(&mut decoder, &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5, 0x1E]);
+        assert_eq!(count, 2);
+        assert_eq!(found[0], Some(KeyEvent::new(KeyCode::PauseBreak, KeyState::SingleShot)));
+        assert_eq!(found[1], Some(KeyEvent::new(KeyCode::A, KeyState::Down)));
+
+        let mut decoder = Decoder::new(CodeSet::Set2, Layout::Us);
+        let (found, count) = events(&mut decoder, &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77]);
+        assert_eq!(count, 1);
+        assert_eq!(found[0], Some(KeyEvent::new(KeyCode::PauseBreak, KeyState::SingleShot)));
+    }
+
+    #[test_case]
+    fn reset_drops_extended_prefix() {
+        let mut decoder = Decoder::new(CodeSet::Set1, Layout::Us);
+        assert_eq!(decoder.add_byte(0xE0), Ok(None));
+        decoder.reset_sequence();
+        // Without the prefix 0x48 is keypad 8, not Up Arrow
+        let event = decoder.add_byte(0x48).unwrap();
+        assert_eq!(event, Some(KeyEvent::new(KeyCode::Numpad8, KeyState::Down)));
+    }
+
+    #[test_case]
+    fn set2_decodes_break_prefix() {
+        let mut decoder = Decoder::new(CodeSet::Set2, Layout::Us);
+        // 'A' pressed then released: 1C, F0 1C
+        assert!(decoder.add_byte(0x1C).unwrap().is_some());
+        assert_eq!(decoder.add_byte(0xF0), Ok(None));
+        assert!(decoder.add_byte(0x1C).unwrap().is_some());
+    }
+
+    #[cfg(all(feature = "de", feature = "uk"))]
+    #[test_case]
+    fn alt_gr_tables() {
+        assert_eq!(Layout::De.alt_gr(KeyCode::Q), Some('@'));
+        assert_eq!(Layout::De.alt_gr(KeyCode::Oem5), Some('|'));
+        assert_eq!(Layout::Uk.alt_gr(KeyCode::Key4), Some('€'));
+        assert_eq!(Layout::Us.alt_gr(KeyCode::Q), None);
+    }
+
+    #[test_case]
+    fn layout_names_round_trip() {
+        for &layout in Layout::ALL {
+            assert_eq!(Layout::from_name(layout.name()), Some(layout));
+        }
+        assert_eq!(Layout::from_name("xx"), None);
+    }
+}
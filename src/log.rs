@@ -0,0 +1,22 @@
+//! Kernel log output, mirrored to the VGA console and COM1
+
+use core::fmt;
+
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => ($crate::log::_log(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! logln {
+    () => ($crate::log!("\n"));
+    ($($arg:tt)*) => ($crate::log!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _log(args: fmt::Arguments) {
+    crate::vga_buffer::_print(args);
+    // Serial is best effort: if the port is already locked (we interrupted
+    // a writer) skip it rather than deadlock
+    crate::serial::_try_print(args);
+}
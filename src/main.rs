@@ -1,48 +1,152 @@
 #![no_std]
 #![no_main]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
 
 mod constants;
+mod log;
 mod vga_buffer;
 mod keyboard;
+mod layout;
 mod shell;
+mod pager;
 mod gdt;
 mod interrupts;
+mod rtc;
+mod serial;
+mod qemu;
+mod time;
+mod speaker;
+mod allocator;
+mod memory;
+mod usermode;
+mod ps2;
+mod mouse;
+mod ata;
+mod cpu;
+mod statusbar;
+mod rng;
+mod ramfs;
+mod queue;
+mod pci;
 
 use core::panic::PanicInfo;
+use bootloader::{entry_point, BootInfo};
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    println_unmuted!("{}", info);
     loop {
         x86_64::instructions::hlt();
     }
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    println!("DEBUG: Starting DxOS...");
+/// In test builds a panic fails the current test and ends the run
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    qemu::exit_qemu(qemu::QemuExitCode::Failed);
+}
+
+/// A test case that reports its own name over serial
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+#[cfg(test)]
+fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    qemu::exit_qemu(qemu::QemuExitCode::Success);
+}
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    serial::init();
+
+    // Keep the bottom row out of scrolling for the status bar
+    vga_buffer::set_scroll_region(0, constants::vga::STATUS_ROW - 1);
+
+    logln!("DEBUG: Starting {}...", constants::system::OS_NAME);
+
+    allocator::init();
 
     // Initialize GDT with TSS for double fault protection
     gdt::init();
 
-    // Initialize interrupts (IDT, PICs) but DON'T call sti
-    interrupts::init_without_sti();
+    // The bootloader maps all physical memory at this offset
+    let physical_memory_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(physical_memory_offset) };
+
+    // Initialize interrupts (IDT, PICs) and enable them
+    time::init();
+    interrupts::init();
+    match keyboard::init() {
+        Ok(()) => {}
+        Err(err) if !keyboard::present() => {
+            logln!("WARNING: no PS/2 keyboard found ({:?}), keyboard input is off", err);
+        }
+        Err(err) => {
+            logln!("WARNING: keyboard init failed ({:?}), keeping the firmware's setup", err);
+            if let Err(err) = keyboard::detect_code_set() {
+                logln!("WARNING: could not read the PS/2 controller config ({:?}), assuming scancode set 1", err);
+            }
+        }
+    }
+    keyboard::init_interrupt_mode();
+    if keyboard::present() && keyboard::sync_leds().is_err() {
+        logln!("WARNING: could not set the keyboard LEDs");
+    }
+
+    #[cfg(test)]
+    test_main();
+
+    serial::enable_input_interrupt();
+
+    if let Err(err) = mouse::init() {
+        logln!("WARNING: PS/2 mouse not available ({:?})", err);
+    }
 
     //vga_buffer::clear_screen();
 
-    println!("Welcome to DxOS CLI v0.2");
-    println!("Type 'help' for available commands.");
-    println!("Use UP/DOWN arrows for command history.");
-    print!("> ");
+    if let Err(err) = ata::init() {
+        logln!("WARNING: could not register the disk commands ({:?})", err);
+    }
+
+    let banner = alloc::format!("Welcome to {} CLI v{}", constants::system::OS_NAME, constants::system::VERSION);
+    vga_buffer::print_centered(None, &banner);
+    vga_buffer::print_centered(None, "Type 'help' for available commands.");
+    vga_buffer::print_centered(None, "Use UP/DOWN arrows for command history.");
+    shell::init();
 
-    // Main event loop - interrupt-driven (no hlt for testing)
+    // Main event loop
     loop {
-        // Process all pending keyboard input from interrupt queue
-        while let Some(key) = keyboard::get_key() {
-            shell::process_key(key);
-        }
+        // Hand all pending keyboard input to the key handlers
+        keyboard::dispatch();
+        keyboard::check_input();
+        mouse::update_cursor();
+        statusbar::update();
 
-        // NO hlt() - just spin to see if interrupts fire
+        // Sleep until the next interrupt (spins in polling mode)
+        keyboard::idle();
     }
 }
@@ -0,0 +1,101 @@
+//! Paging setup on top of the bootloader's page tables
+//!
+//! The bootloader (with the `map_physical_memory` feature) maps all of
+//! physical memory linearly at `BootInfo::physical_memory_offset`, so the
+//! frame holding any page table is readable at `offset + frame address`.
+//! Everything here relies on that mapping.
+
+use spin::Mutex;
+use x86_64::structures::paging::{
+    mapper::{FlagUpdateError, TranslateResult, UnmapError},
+    Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, Size4KiB, Translate,
+};
+use x86_64::VirtAddr;
+use crate::constants::memory::KERNEL_STACK_ADDRESS;
+use crate::logln;
+
+/// The active page tables, available once `init` has run
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// Take over the active page tables and put guard pages under the stacks
+///
+/// # Safety
+/// All physical memory must be mapped at `physical_memory_offset`, and this
+/// must only be called once.
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    let mut mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+
+    let [double_fault_guard, page_fault_guard, privilege_guard] = crate::gdt::guard_pages();
+    unmap_guard(&mut mapper, VirtAddr::new(KERNEL_STACK_ADDRESS), "boot stack");
+    unmap_guard(&mut mapper, double_fault_guard, "double fault stack");
+    unmap_guard(&mut mapper, page_fault_guard, "page fault stack");
+    unmap_guard(&mut mapper, privilege_guard, "privilege stack");
+
+    *MAPPER.lock() = Some(mapper);
+    logln!("DEBUG: Paging initialized, physical memory at {:#x}", physical_memory_offset.as_u64());
+}
+
+/// The level 4 table CR3 points to, through the physical memory mapping
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_frame, _) = Cr3::read();
+    let virt = physical_memory_offset + level_4_frame.start_address().as_u64();
+    &mut *virt.as_mut_ptr::<PageTable>()
+}
+
+/// Make sure the page at `addr` is unmapped. The boot stack's guard is
+/// normally already missing; ours live in .bss and start out mapped.
+fn unmap_guard(mapper: &mut OffsetPageTable, addr: VirtAddr, name: &str) {
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    match mapper.unmap(page) {
+        Ok((_, flush)) => flush.flush(),
+        Err(UnmapError::PageNotMapped) => {}
+        Err(err) => logln!("WARNING: no guard page for {}: {:?}", name, err),
+    }
+}
+
+/// Whether `addr` falls in one of the stack guard pages
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let kernel_stack_guard = VirtAddr::new(KERNEL_STACK_ADDRESS);
+    core::iter::once(kernel_stack_guard)
+        .chain(crate::gdt::guard_pages())
+        .any(|guard| Page::containing_address(guard) == page)
+}
+
+/// Whether `addr` is mapped, and writable if `write` is set
+pub fn is_mapped(addr: VirtAddr, write: bool) -> bool {
+    let mapper = MAPPER.lock();
+    let Some(mapper) = mapper.as_ref() else { return false };
+    match mapper.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => !write || flags.contains(PageTableFlags::WRITABLE),
+        _ => false,
+    }
+}
+
+/// Let ring 3 use the already-mapped pages covering `start..start + len`.
+/// The page table entries above them get the user bit too, which is
+/// harmless: access is only allowed where the final entry also has it.
+pub fn set_user_accessible(start: VirtAddr, len: u64) -> Result<(), FlagUpdateError> {
+    let mut mapper = MAPPER.lock();
+    let mapper = mapper.as_mut().expect("memory::init has not run");
+
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let first: Page<Size4KiB> = Page::containing_address(start);
+    let last: Page<Size4KiB> = Page::containing_address(start + (len.max(1) - 1));
+    for page in Page::range_inclusive(first, last) {
+        let flags = match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => return Err(FlagUpdateError::PageNotMapped),
+        };
+        unsafe {
+            mapper.set_flags_p4_entry(page, parent_flags)?.flush_all();
+            mapper.set_flags_p3_entry(page, parent_flags)?.flush_all();
+            mapper.set_flags_p2_entry(page, parent_flags)?.flush_all();
+            mapper.update_flags(page, flags | PageTableFlags::USER_ACCESSIBLE)?.flush();
+        }
+    }
+    Ok(())
+}
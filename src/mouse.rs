@@ -0,0 +1,192 @@
+//! PS/2 mouse on IRQ12, shown as an inverted cell on the text screen
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::constants::keyboard::{CMD_ENABLE_AUX, CONFIG_AUX_CLOCK_DISABLED, CONFIG_AUX_IRQ};
+use crate::constants::mouse::*;
+use crate::constants::vga::{BUFFER_HEIGHT, BUFFER_WIDTH};
+use crate::ps2::{self, Channel, DeviceKind, Ps2Error};
+
+/// One packet's worth of movement and button state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// Movement in mouse counts; positive `dy` is upwards
+    pub dx: i16,
+    pub dy: i16,
+    /// Left, right and middle button bits as in the packet
+    pub buttons: u8,
+}
+
+/// Fixed-size FIFO of events; filled under the `MOUSE` lock
+struct EventQueue {
+    events: [MouseEvent; EVENT_QUEUE_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl EventQueue {
+    const fn new() -> Self {
+        EventQueue {
+            events: [MouseEvent { dx: 0, dy: 0, buttons: 0 }; EVENT_QUEUE_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, event: MouseEvent) {
+        if self.len == EVENT_QUEUE_SIZE {
+            return;
+        }
+        self.events[(self.start + self.len) % EVENT_QUEUE_SIZE] = event;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<MouseEvent> {
+        if self.len == 0 {
+            return None;
+        }
+        let event = self.events[self.start];
+        self.start = (self.start + 1) % EVENT_QUEUE_SIZE;
+        self.len -= 1;
+        Some(event)
+    }
+}
+
+struct MouseState {
+    /// Bytes of the packet being assembled
+    packet: [u8; 3],
+    /// Index of the next packet byte
+    cycle: usize,
+    /// Position in mouse counts, clamped to the screen
+    x: i32,
+    y: i32,
+    buttons: u8,
+    events: EventQueue,
+    /// Cell the pointer is drawn in
+    drawn: Option<(usize, usize)>,
+}
+
+impl MouseState {
+    const fn new() -> Self {
+        MouseState {
+            packet: [0; 3],
+            cycle: 0,
+            x: (BUFFER_WIDTH as i32 / 2) * COUNTS_PER_CELL,
+            y: (BUFFER_HEIGHT as i32 / 2) * COUNTS_PER_CELL,
+            buttons: 0,
+            events: EventQueue::new(),
+            drawn: None,
+        }
+    }
+
+    /// Cell under the pointer as (row, col)
+    fn cell(&self) -> (usize, usize) {
+        ((self.y / COUNTS_PER_CELL) as usize, (self.x / COUNTS_PER_CELL) as usize)
+    }
+
+    fn apply_packet(&mut self) {
+        let flags = self.packet[0];
+        self.buttons = flags & PACKET_BUTTONS;
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) != 0 {
+            // The movement is garbage but the buttons are still good
+            self.events.push(MouseEvent { dx: 0, dy: 0, buttons: self.buttons });
+            return;
+        }
+
+        // Movement is 9-bit two's complement with the sign bit in byte 0
+        let mut dx = self.packet[1] as i32;
+        if flags & PACKET_X_SIGN != 0 {
+            dx -= 0x100;
+        }
+        let mut dy = self.packet[2] as i32;
+        if flags & PACKET_Y_SIGN != 0 {
+            dy -= 0x100;
+        }
+
+        // Mouse Y grows upwards, screen rows grow downwards
+        let max_x = BUFFER_WIDTH as i32 * COUNTS_PER_CELL - 1;
+        let max_y = BUFFER_HEIGHT as i32 * COUNTS_PER_CELL - 1;
+        self.x = (self.x + dx).clamp(0, max_x);
+        self.y = (self.y - dy).clamp(0, max_y);
+        self.events.push(MouseEvent { dx: dx as i16, dy: dy as i16, buttons: self.buttons });
+    }
+}
+
+static MOUSE: Mutex<MouseState> = Mutex::new(MouseState::new());
+
+/// Whether the pointer is drawn on screen
+static CURSOR_VISIBLE: AtomicBool = AtomicBool::new(true);
+
+/// Enable the auxiliary port, route it to IRQ12 and start data reporting
+pub fn init() -> Result<(), Ps2Error> {
+    // Probing may have found the aux port empty, or the keyboard on it
+    if let Some(info) = crate::keyboard::controller_info() {
+        if info.port(Channel::Second).kind() != Some(DeviceKind::Mouse) {
+            return Err(Ps2Error::NoDevice);
+        }
+    }
+    without_interrupts(|| {
+        ps2::send_command(CMD_ENABLE_AUX)?;
+
+        let config = ps2::read_config()?;
+        ps2::write_config((config | CONFIG_AUX_IRQ) & !CONFIG_AUX_CLOCK_DISABLED)?;
+
+        ps2::write_aux(CMD_SET_DEFAULTS)?;
+        ps2::write_aux(CMD_ENABLE_REPORTING)?;
+        Ok(())
+    })?;
+
+    crate::interrupts::unmask_irq(IRQ);
+    Ok(())
+}
+
+/// Feed one byte from the mouse. Called from the IRQ12 handler, or from the
+/// keyboard poll when the controller flags a byte as auxiliary data.
+pub fn handle_byte(byte: u8) {
+    without_interrupts(|| feed(&mut MOUSE.lock(), byte));
+}
+
+fn feed(mouse: &mut MouseState, byte: u8) {
+    // Bit 3 of the first byte is always set; if it isn't we're out of
+    // step with the packet stream, so drop bytes until we find one that is
+    if mouse.cycle == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+        return;
+    }
+
+    let cycle = mouse.cycle;
+    mouse.packet[cycle] = byte;
+    mouse.cycle += 1;
+    if mouse.cycle == mouse.packet.len() {
+        mouse.cycle = 0;
+        mouse.apply_packet();
+    }
+}
+
+/// Take the oldest unread mouse event
+pub fn poll_event() -> Option<MouseEvent> {
+    without_interrupts(|| MOUSE.lock().events.pop())
+}
+
+pub fn set_cursor_visible(visible: bool) {
+    CURSOR_VISIBLE.store(visible, Ordering::Relaxed);
+}
+
+pub fn cursor_visible() -> bool {
+    CURSOR_VISIBLE.load(Ordering::Relaxed)
+}
+
+/// Move the on-screen pointer to the current position, or remove it if
+/// hidden. Called from the main loop so the interrupt handler never has to
+/// take the VGA lock.
+pub fn update_cursor() {
+    without_interrupts(|| {
+        let mut mouse = MOUSE.lock();
+        let cell = cursor_visible().then(|| mouse.cell());
+        if mouse.drawn == cell {
+            return;
+        }
+        crate::vga_buffer::set_pointer(cell);
+        mouse.drawn = cell;
+    });
+}
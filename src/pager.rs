@@ -0,0 +1,96 @@
+//! `more`-style paging for output longer than the screen
+
+use core::fmt;
+use crate::{print, println};
+use crate::keyboard;
+use crate::vga_buffer;
+
+const PROMPT: &str = "--more--";
+
+/// Counts the bytes a formatted line will take, without printing it
+struct Measure(usize);
+
+impl fmt::Write for Measure {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+/// Prints lines, stopping with `--more--` whenever the next line would
+/// push the first unread one off the screen
+pub struct Pager {
+    /// Rows left before we have to pause
+    remaining: usize,
+    quit: bool,
+}
+
+impl Pager {
+    pub fn new() -> Self {
+        Pager {
+            remaining: page_rows(),
+            quit: false,
+        }
+    }
+
+    /// Print one line. Returns false once the user has pressed `q`, after
+    /// which further lines are dropped.
+    pub fn line(&mut self, args: fmt::Arguments) -> bool {
+        if self.quit {
+            return false;
+        }
+
+        // Nothing reaches the screen while muted, so never stop for a key
+        if vga_buffer::muted() {
+            println!("{}", args);
+            return true;
+        }
+
+        let mut measure = Measure(0);
+        let _ = fmt::write(&mut measure, args);
+        let rows = rows_for(measure.0);
+
+        if rows > self.remaining {
+            print!("{}", PROMPT);
+            let key = keyboard::wait_key();
+            for _ in 0..PROMPT.len() {
+                vga_buffer::backspace();
+            }
+            if matches!(key, pc_keyboard::DecodedKey::Unicode('q' | 'Q')) {
+                self.quit = true;
+                return false;
+            }
+            self.remaining = page_rows();
+        }
+
+        println!("{}", args);
+        self.remaining = self.remaining.saturating_sub(rows);
+        true
+    }
+}
+
+/// Print every line from `lines`, pausing after each screenful
+pub fn print_paged<'a>(lines: impl Iterator<Item = &'a str>) {
+    let mut pager = Pager::new();
+    for line in lines {
+        if !pager.line(format_args!("{}", line)) {
+            break;
+        }
+    }
+}
+
+/// Rows per page, keeping the last one for the prompt
+fn page_rows() -> usize {
+    let (_, height) = vga_buffer::dimensions();
+    height - 1
+}
+
+/// Screen rows a line of `len` bytes occupies
+fn rows_for(len: usize) -> usize {
+    let (width, _) = vga_buffer::dimensions();
+    if len == 0 || !vga_buffer::wrap_enabled() {
+        1
+    } else {
+        len.div_ceil(width)
+    }
+}
@@ -0,0 +1,159 @@
+//! PCI bus enumeration through configuration mechanism #1 (ports 0xCF8/0xCFC)
+
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::constants::pci::*;
+
+/// Vendor names shown by `lspci`; add entries as drivers need them
+const VENDORS: &[(u16, &str)] = &[
+    (0x1022, "AMD"),
+    (0x1234, "QEMU"),
+    (0x1AF4, "Red Hat (virtio)"),
+    (0x1B36, "Red Hat (QEMU)"),
+    (0x10DE, "NVIDIA"),
+    (0x10EC, "Realtek"),
+    (0x15AD, "VMware"),
+    (0x8086, "Intel"),
+];
+
+/// Class names by class and subclass; a subclass of `None` matches any
+/// subclass not listed before it
+const CLASSES: &[(u8, Option<u8>, &str)] = &[
+    (0x01, Some(0x01), "IDE controller"),
+    (0x01, Some(0x06), "SATA controller"),
+    (0x01, Some(0x08), "NVMe controller"),
+    (0x01, None, "storage controller"),
+    (0x02, Some(0x00), "Ethernet controller"),
+    (0x02, None, "network controller"),
+    (0x03, Some(0x00), "VGA controller"),
+    (0x03, None, "display controller"),
+    (0x04, None, "multimedia controller"),
+    (0x05, None, "memory controller"),
+    (0x06, Some(0x00), "host bridge"),
+    (0x06, Some(0x01), "ISA bridge"),
+    (0x06, Some(0x04), "PCI bridge"),
+    (0x06, None, "bridge"),
+    (0x07, None, "communication controller"),
+    (0x08, None, "system peripheral"),
+    (0x0C, Some(0x03), "USB controller"),
+    (0x0C, Some(0x05), "SMBus controller"),
+    (0x0C, None, "serial bus controller"),
+];
+
+/// One function found on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+}
+
+impl Device {
+    pub fn vendor_name(&self) -> Option<&'static str> {
+        vendor_name(self.vendor_id)
+    }
+
+    pub fn class_name(&self) -> Option<&'static str> {
+        class_name(self.class, self.subclass)
+    }
+}
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    VENDORS.iter().find(|&&(id, _)| id == vendor_id).map(|&(_, name)| name)
+}
+
+pub fn class_name(class: u8, subclass: u8) -> Option<&'static str> {
+    CLASSES
+        .iter()
+        .find(|&&(c, sub, _)| c == class && (sub.is_none() || sub == Some(subclass)))
+        .map(|&(_, _, name)| name)
+}
+
+/// Value for `CONFIG_ADDRESS` selecting dword `offset` of a function
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    ENABLE
+        | (bus as u32) << 16
+        | ((device & 0x1F) as u32) << 11
+        | ((function & 0x07) as u32) << 8
+        | (offset & 0xFC) as u32
+}
+
+/// Read a config space dword; interrupts are off so nothing can move
+/// `CONFIG_ADDRESS` between the two accesses
+fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    let mut address: Port<u32> = Port::new(CONFIG_ADDRESS);
+    let mut data: Port<u32> = Port::new(CONFIG_DATA);
+    without_interrupts(|| unsafe {
+        address.write(config_address(bus, device, function, offset));
+        data.read()
+    })
+}
+
+/// The function's IDs and class, or `None` if nothing answers there
+fn probe(bus: u8, device: u8, function: u8) -> Option<Device> {
+    let id = read_config(bus, device, function, REG_ID);
+    let vendor_id = id as u16;
+    if vendor_id == NO_VENDOR {
+        return None;
+    }
+    let class = read_config(bus, device, function, REG_CLASS);
+    Some(Device {
+        bus,
+        device,
+        function,
+        vendor_id,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+    })
+}
+
+fn header_type(bus: u8, device: u8) -> u8 {
+    (read_config(bus, device, 0, REG_HEADER) >> 16) as u8
+}
+
+/// Every function on every bus, in bus/device/function order. Functions
+/// 1-7 are only probed when function 0 reports a multi-function device,
+/// since single-function devices may mirror function 0 there.
+pub fn scan() -> Vec<Device> {
+    let mut devices = Vec::new();
+    for bus in 0..=MAX_BUS {
+        for device in 0..DEVICES_PER_BUS {
+            let Some(first) = probe(bus, device, 0) else {
+                continue;
+            };
+            devices.push(first);
+            if header_type(bus, device) & HEADER_MULTI_FUNCTION == 0 {
+                continue;
+            }
+            devices.extend((1..FUNCTIONS_PER_DEVICE).filter_map(|function| probe(bus, device, function)));
+        }
+    }
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn config_address_layout() {
+        assert_eq!(config_address(0, 0, 0, 0), 0x8000_0000);
+        assert_eq!(config_address(1, 2, 3, 0x0E), 0x8001_1308);
+        assert_eq!(config_address(0xFF, 31, 7, 0xFF), 0x80FF_FFFC);
+    }
+
+    #[test_case]
+    fn name_tables() {
+        assert_eq!(vendor_name(0x8086), Some("Intel"));
+        assert_eq!(vendor_name(0xABCD), None);
+        assert_eq!(class_name(0x01, 0x01), Some("IDE controller"));
+        assert_eq!(class_name(0x01, 0x42), Some("storage controller"));
+        assert_eq!(class_name(0xFE, 0x00), None);
+    }
+}
@@ -0,0 +1,297 @@
+//! Low-level access to the 8042 PS/2 controller shared by keyboard and mouse
+
+use x86_64::instructions::port::Port;
+use crate::constants::keyboard::*;
+
+/// Status polls before a controller operation is considered to have failed
+const TIMEOUT_POLLS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// The controller or device didn't respond in time
+    Timeout,
+    /// A device answered something other than ACK
+    UnexpectedResponse(u8),
+    /// The status register floats high: no controller (or it's hidden,
+    /// e.g. by USB legacy support being off)
+    NoController,
+    /// The device wanted (a keyboard, or a mouse on the aux port) isn't
+    /// attached
+    NoDevice,
+}
+
+/// One of the controller's two device ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Port 1, normally the keyboard (IRQ1)
+    First,
+    /// Port 2, the auxiliary port, normally the mouse (IRQ12)
+    Second,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 2] = [Channel::First, Channel::Second];
+
+    pub fn number(self) -> usize {
+        match self {
+            Channel::First => 1,
+            Channel::Second => 2,
+        }
+    }
+}
+
+/// What a device's identify reply says it is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Keyboard,
+    Mouse,
+    Unknown,
+}
+
+/// The bytes a device sent after acknowledging identify
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DeviceId {
+    bytes: [u8; 2],
+    len: usize,
+}
+
+impl DeviceId {
+    pub fn new(bytes: &[u8]) -> DeviceId {
+        let mut id = DeviceId::default();
+        for &byte in bytes.iter().take(id.bytes.len()) {
+            id.bytes[id.len] = byte;
+            id.len += 1;
+        }
+        id
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    pub fn kind(&self) -> DeviceKind {
+        match self.bytes().first() {
+            None => DeviceKind::Keyboard,
+            Some(&ID_MF2_KEYBOARD | &ID_122_KEY_KEYBOARD) => DeviceKind::Keyboard,
+            Some(id) if ID_MICE.contains(id) => DeviceKind::Mouse,
+            Some(_) => DeviceKind::Unknown,
+        }
+    }
+}
+
+/// What probing found on one port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PortInfo {
+    /// Interface test reply, `None` if the port wasn't tested
+    pub test: Option<u8>,
+    /// Identify reply, `None` if no device answered
+    pub id: Option<DeviceId>,
+}
+
+impl PortInfo {
+    pub fn passed(&self) -> bool {
+        self.test == Some(PORT_TEST_PASSED)
+    }
+
+    pub fn kind(&self) -> Option<DeviceKind> {
+        self.id.map(|id| id.kind())
+    }
+}
+
+/// Controller topology found at boot, reported by `lsps2`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerInfo {
+    /// The controller has a second port
+    pub dual_channel: bool,
+    pub ports: [PortInfo; 2],
+}
+
+impl ControllerInfo {
+    pub fn port(&self, channel: Channel) -> &PortInfo {
+        &self.ports[channel.number() - 1]
+    }
+
+    /// Port the keyboard is on, preferring the first
+    pub fn keyboard_channel(&self) -> Option<Channel> {
+        Channel::ALL
+            .into_iter()
+            .find(|&channel| self.port(channel).kind() == Some(DeviceKind::Keyboard))
+    }
+}
+
+pub fn status() -> u8 {
+    let mut port: Port<u8> = Port::new(STATUS_COMMAND_PORT);
+    unsafe { port.read() }
+}
+
+/// Wait until the controller can accept a command or data byte
+fn wait_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT_POLLS {
+        if status() & STATUS_INPUT_BUFFER_FULL == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Wait until a byte is ready to read from the data port
+fn wait_output_full() -> Result<(), Ps2Error> {
+    for _ in 0..TIMEOUT_POLLS {
+        if status() & STATUS_OUTPUT_BUFFER_FULL != 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(Ps2Error::Timeout)
+}
+
+pub fn send_command(command: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    let mut port: Port<u8> = Port::new(STATUS_COMMAND_PORT);
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+pub fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait_input_empty()?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    unsafe { port.write(byte) };
+    Ok(())
+}
+
+pub fn read_data() -> Result<u8, Ps2Error> {
+    wait_output_full()?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    Ok(unsafe { port.read() })
+}
+
+/// Wait for a byte from either port; the flag is set if the mouse sent it
+pub fn read_data_tagged() -> Result<(u8, bool), Ps2Error> {
+    wait_output_full()?;
+    let from_aux = status() & STATUS_AUX_DATA != 0;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    Ok((unsafe { port.read() }, from_aux))
+}
+
+/// Discard bytes left in the output buffer, e.g. by the firmware
+pub fn flush_output() {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    for _ in 0..FLUSH_LIMIT {
+        if status() & STATUS_OUTPUT_BUFFER_FULL == 0 {
+            return;
+        }
+        unsafe { port.read() };
+    }
+}
+
+pub fn read_config() -> Result<u8, Ps2Error> {
+    send_command(CMD_READ_CONFIG)?;
+    read_data()
+}
+
+pub fn write_config(config: u8) -> Result<(), Ps2Error> {
+    send_command(CMD_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+/// Send a byte to the device on `channel` without waiting for a reply
+pub fn write_device(channel: Channel, byte: u8) -> Result<(), Ps2Error> {
+    if channel == Channel::Second {
+        send_command(CMD_WRITE_AUX)?;
+    }
+    write_data(byte)
+}
+
+/// Send a byte to the device on `channel` and wait for its ACK, skipping
+/// stray input that arrives first
+fn write_device_acked(channel: Channel, byte: u8) -> Result<(), Ps2Error> {
+    write_device(channel, byte)?;
+    for _ in 0..MAX_BYTES_BEFORE_ACK {
+        match read_data()? {
+            RESPONSE_ACK => return Ok(()),
+            RESPONSE_RESEND => return Err(Ps2Error::UnexpectedResponse(RESPONSE_RESEND)),
+            _ => {}
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// Ask the device on `channel` what it is. Scanning is turned off for the
+/// exchange and back on for keyboards; mice are left for `mouse::init`.
+fn identify(channel: Channel) -> Result<DeviceId, Ps2Error> {
+    write_device_acked(channel, CMD_DISABLE_SCANNING)?;
+    write_device_acked(channel, CMD_IDENTIFY)?;
+    // AT keyboards send no ID bytes, so a timeout ends the reply
+    let mut bytes = [0u8; 2];
+    let mut len = 0;
+    while len < bytes.len() {
+        match read_data() {
+            Ok(byte) => {
+                bytes[len] = byte;
+                len += 1;
+            }
+            Err(Ps2Error::Timeout) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    let id = DeviceId::new(&bytes[..len]);
+    if id.kind() != DeviceKind::Mouse {
+        write_device_acked(channel, CMD_ENABLE_SCANNING)?;
+    }
+    Ok(id)
+}
+
+/// Find out whether the controller has a second port, test each port's
+/// interface and identify what is plugged in. Ports that pass are left
+/// enabled. Run with interrupts off, before any device IRQ is unmasked.
+pub fn probe() -> Result<ControllerInfo, Ps2Error> {
+    // The second port's clock-disabled bit only clears if the port exists
+    send_command(CMD_ENABLE_AUX)?;
+    let dual_channel = read_config()? & CONFIG_AUX_CLOCK_DISABLED == 0;
+    let mut info = ControllerInfo { dual_channel, ports: [PortInfo::default(); 2] };
+
+    for channel in Channel::ALL {
+        if channel == Channel::Second && !dual_channel {
+            continue;
+        }
+        let (test, enable) = match channel {
+            Channel::First => (CMD_TEST_PORT1, CMD_ENABLE_PORT1),
+            Channel::Second => (CMD_TEST_AUX, CMD_ENABLE_AUX),
+        };
+        send_command(test)?;
+        let port = &mut info.ports[channel.number() - 1];
+        port.test = Some(read_data()?);
+        if !port.passed() {
+            continue;
+        }
+        send_command(enable)?;
+        port.id = identify(channel).ok();
+        flush_output();
+    }
+    Ok(info)
+}
+
+/// Send a byte to the mouse and check that it acknowledges it
+pub fn write_aux(byte: u8) -> Result<(), Ps2Error> {
+    send_command(CMD_WRITE_AUX)?;
+    write_data(byte)?;
+    match read_data()? {
+        RESPONSE_ACK => Ok(()),
+        other => Err(Ps2Error::UnexpectedResponse(other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn device_id_kinds() {
+        assert_eq!(DeviceId::new(&[]).kind(), DeviceKind::Keyboard);
+        assert_eq!(DeviceId::new(&[0xAB, 0x83]).kind(), DeviceKind::Keyboard);
+        assert_eq!(DeviceId::new(&[0x03]).kind(), DeviceKind::Mouse);
+        assert_eq!(DeviceId::new(&[0x42]).kind(), DeviceKind::Unknown);
+        assert_eq!(DeviceId::new(&[0xAB, 0x83, 0x00]).bytes(), &[0xAB, 0x83]);
+    }
+}
@@ -0,0 +1,46 @@
+//! Helpers that only make sense when running under QEMU
+
+use x86_64::instructions::port::Port;
+use crate::constants::qemu::{ACPI_SHUTDOWN_PORT, BOCHS_SHUTDOWN_PORT, SHUTDOWN_VALUE};
+#[cfg(test)]
+use crate::constants::qemu::ISA_DEBUG_EXIT_PORT;
+use crate::println;
+
+/// Values written to the `isa-debug-exit` device. QEMU exits with
+/// `(value << 1) | 1`, so these become 33 and 35 on the host.
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Power the machine off through the emulator's ACPI ports. Real hardware
+/// needs a proper ACPI driver for this, so if nothing happens we say so and
+/// halt.
+pub fn poweroff() -> ! {
+    x86_64::instructions::interrupts::disable();
+    for port in [ACPI_SHUTDOWN_PORT, BOCHS_SHUTDOWN_PORT] {
+        unsafe { Port::<u16>::new(port).write(SHUTDOWN_VALUE) };
+    }
+
+    println!("Power-off did not take effect; it is now safe to turn off the computer.");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Terminate QEMU with `exit_code` (requires `-device isa-debug-exit`)
+#[cfg(test)]
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    unsafe {
+        let mut port: Port<u32> = Port::new(ISA_DEBUG_EXIT_PORT);
+        port.write(exit_code as u32);
+    }
+
+    // Without the device the write does nothing; stop here anyway
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,84 @@
+//! Byte queue between an interrupt handler and the main loop
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Single-producer single-consumer ring of `N - 1` bytes: an interrupt
+/// handler pushes and the main loop pops. Neither side takes a lock, so the
+/// handler can never spin on one held by the code it interrupted. One slot
+/// is left empty to tell a full queue from an empty one.
+pub struct ByteQueue<const N: usize> {
+    buffer: [AtomicU8; N],
+    /// Next slot to read; only the consumer stores it
+    read_pos: AtomicUsize,
+    /// Next slot to write; only the producer stores it
+    write_pos: AtomicUsize,
+}
+
+impl<const N: usize> ByteQueue<N> {
+    pub const fn new() -> Self {
+        ByteQueue {
+            buffer: [const { AtomicU8::new(0) }; N],
+            read_pos: AtomicUsize::new(0),
+            write_pos: AtomicUsize::new(0),
+        }
+    }
+
+    /// Producer side; returns false if the queue is full
+    pub fn push(&self, byte: u8) -> bool {
+        let write_pos = self.write_pos.load(Ordering::Relaxed);
+        let next_write = (write_pos + 1) % N;
+        if next_write == self.read_pos.load(Ordering::Acquire) {
+            return false;
+        }
+        self.buffer[write_pos].store(byte, Ordering::Relaxed);
+        // Publish the byte before the new write position
+        self.write_pos.store(next_write, Ordering::Release);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read_pos.load(Ordering::Relaxed) == self.write_pos.load(Ordering::Acquire)
+    }
+
+    /// Consumer side
+    pub fn pop(&self) -> Option<u8> {
+        let read_pos = self.read_pos.load(Ordering::Relaxed);
+        if read_pos == self.write_pos.load(Ordering::Acquire) {
+            return None;
+        }
+        let byte = self.buffer[read_pos].load(Ordering::Relaxed);
+        // Free the slot only after the byte has been read
+        self.read_pos.store((read_pos + 1) % N, Ordering::Release);
+        Some(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn queue_is_fifo_across_wraparound() {
+        let queue = ByteQueue::<16>::new();
+        for round in 0..3u8 {
+            for i in 0..10 {
+                assert!(queue.push(round * 10 + i));
+            }
+            for i in 0..10 {
+                assert_eq!(queue.pop(), Some(round * 10 + i));
+            }
+        }
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test_case]
+    fn full_queue_rejects_push() {
+        let queue = ByteQueue::<16>::new();
+        for i in 0..15 {
+            assert!(queue.push(i));
+        }
+        assert!(!queue.push(0xff));
+        assert_eq!(queue.pop(), Some(0));
+        assert!(queue.push(0xff));
+    }
+}
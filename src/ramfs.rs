@@ -0,0 +1,114 @@
+//! Flat in-memory filesystem on the kernel heap
+//!
+//! Files live until reboot. There are no directories: a name is any short
+//! string without spaces or slashes. The number of files and the bytes they
+//! hold are capped so the filesystem can't use up the heap.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use crate::constants::ramfs::{MAX_FILES, MAX_NAME_LEN, MAX_TOTAL_SIZE};
+
+struct File {
+    name: String,
+    /// Shared so `read` can hand out the contents without holding the lock
+    data: Arc<[u8]>,
+}
+
+/// All files, in the order they were created
+static FILES: Mutex<Vec<File>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamfsError {
+    /// Empty, longer than `MAX_NAME_LEN`, or containing a space or `/`
+    BadName,
+    /// All `MAX_FILES` names are taken
+    TooManyFiles,
+    /// The contents would take the total past `MAX_TOTAL_SIZE`
+    NoSpace,
+}
+
+impl fmt::Display for RamfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RamfsError::BadName => {
+                write!(f, "names must be 1-{} characters without spaces or '/'", MAX_NAME_LEN)
+            }
+            RamfsError::TooManyFiles => write!(f, "too many files (max {})", MAX_FILES),
+            RamfsError::NoSpace => write!(f, "out of space ({} bytes in all)", MAX_TOTAL_SIZE),
+        }
+    }
+}
+
+/// A file as `list` reports it
+pub struct Entry {
+    pub name: String,
+    pub size: usize,
+}
+
+/// Whether `create` would accept `name`
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN && !name.contains(|c: char| c.is_whitespace() || c == '/')
+}
+
+/// Create the file `name` holding `bytes`, replacing it if it exists
+pub fn create(name: &str, bytes: &[u8]) -> Result<(), RamfsError> {
+    create_in(&mut FILES.lock(), name, bytes)
+}
+
+fn create_in(files: &mut Vec<File>, name: &str, bytes: &[u8]) -> Result<(), RamfsError> {
+    if !valid_name(name) {
+        return Err(RamfsError::BadName);
+    }
+    let existing = files.iter().position(|file| file.name == name);
+    // A replaced file's old contents don't count against the new ones
+    let used: usize = files
+        .iter()
+        .enumerate()
+        .filter(|&(index, _)| Some(index) != existing)
+        .map(|(_, file)| file.data.len())
+        .sum();
+    if used + bytes.len() > MAX_TOTAL_SIZE {
+        return Err(RamfsError::NoSpace);
+    }
+    match existing {
+        Some(index) => files[index].data = Arc::from(bytes),
+        None if files.len() >= MAX_FILES => return Err(RamfsError::TooManyFiles),
+        None => files.push(File { name: String::from(name), data: Arc::from(bytes) }),
+    }
+    Ok(())
+}
+
+/// Contents of the file `name`
+pub fn read(name: &str) -> Option<Arc<[u8]>> {
+    FILES.lock().iter().find(|file| file.name == name).map(|file| file.data.clone())
+}
+
+/// Names and sizes of all files, copied out so no lock is held while
+/// they are printed
+pub fn list() -> Vec<Entry> {
+    FILES.lock().iter().map(|file| Entry { name: file.name.clone(), size: file.data.len() }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn create_replaces_and_checks_limits() {
+        // A table of its own, so the files don't outlive the test
+        let mut files = Vec::new();
+        assert_eq!(create_in(&mut files, "test", b"old"), Ok(()));
+        assert_eq!(create_in(&mut files, "test", b"new!"), Ok(()));
+        assert_eq!(files.len(), 1);
+        assert_eq!(&*files[0].data, b"new!");
+
+        assert_eq!(create_in(&mut files, "a b", b""), Err(RamfsError::BadName));
+        assert_eq!(create_in(&mut files, "", b""), Err(RamfsError::BadName));
+        let too_big = alloc::vec![0u8; MAX_TOTAL_SIZE + 1];
+        assert_eq!(create_in(&mut files, "big", &too_big), Err(RamfsError::NoSpace));
+        assert_eq!(files.len(), 1);
+    }
+}
@@ -0,0 +1,81 @@
+//! Pseudo-random numbers from a xorshift64* generator
+//!
+//! Not suitable for anything security related. The state is seeded on first
+//! use from the time-stamp counter and the PIT tick count, so the sequence
+//! depends on how long the machine ran before the first call.
+
+use spin::Mutex;
+
+/// Generator state; zero means not seeded yet (xorshift never reaches zero)
+static STATE: Mutex<u64> = Mutex::new(0);
+
+/// Spread the bits of `value` so similar seeds give unrelated states
+/// (the splitmix64 finalizer)
+fn mix(mut value: u64) -> u64 {
+    value = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
+
+fn seed() -> u64 {
+    let seed = mix(crate::cpu::rdtsc() ^ crate::time::ticks().rotate_left(32));
+    if seed == 0 { 1 } else { seed }
+}
+
+/// Advance `state` one step and return the output
+fn step(state: &mut u64) -> u64 {
+    let mut x = *state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+pub fn next_u64() -> u64 {
+    let mut state = STATE.lock();
+    if *state == 0 {
+        *state = seed();
+    }
+    step(&mut state)
+}
+
+/// Uniform value in `lo..=hi`; `lo` must not be greater than `hi`
+pub fn range(lo: u64, hi: u64) -> u64 {
+    assert!(lo <= hi, "rng::range: lo > hi");
+    let Some(span) = (hi - lo).checked_add(1) else {
+        return next_u64(); // The whole u64 range
+    };
+    // Reject the top partial block so every value is equally likely
+    let limit = u64::MAX - u64::MAX % span;
+    loop {
+        let value = next_u64();
+        if value < limit {
+            return lo + value % span;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn range_stays_in_bounds() {
+        for _ in 0..1000 {
+            let value = range(10, 20);
+            assert!((10..=20).contains(&value));
+        }
+        assert_eq!(range(7, 7), 7);
+    }
+
+    #[test_case]
+    fn step_never_reaches_zero() {
+        let mut state = 1;
+        for _ in 0..1000 {
+            step(&mut state);
+            assert_ne!(state, 0);
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use core::fmt;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::constants::rtc::*;
+
+/// Wall-clock time as read from the CMOS RTC (no time zone applied)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Read one CMOS register with NMI masked for the duration of the access
+fn read_register(reg: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        address.write(NMI_DISABLE | reg);
+        data.read()
+    }
+}
+
+/// Select a harmless register with bit 7 clear, which unmasks NMI again
+fn enable_nmi() {
+    let mut address: Port<u8> = Port::new(ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        address.write(REG_STATUS_D);
+        data.read();
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Raw register values, straight from CMOS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw() -> RawTime {
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: read_register(REG_CENTURY),
+    }
+}
+
+/// Read the current date and time from the RTC
+pub fn now() -> DateTime {
+    let (raw, status_b) = without_interrupts(|| {
+        // The registers are inconsistent while an update is running, and
+        // one can start right after the flag is checked, so keep reading
+        // until two reads in a row agree
+        let mut last = None;
+        let raw = loop {
+            while update_in_progress() {
+                core::hint::spin_loop();
+            }
+            let raw = read_raw();
+            if last == Some(raw) {
+                break raw;
+            }
+            last = Some(raw);
+        };
+        let status_b = read_register(REG_STATUS_B);
+        enable_nmi();
+        (raw, status_b)
+    });
+    decode(&raw, status_b)
+}
+
+/// Turn raw registers into a date, following the formats status B selects
+fn decode(raw: &RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    // In 12-hour mode the PM flag sits in bit 7 of the (possibly BCD) hour
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        let pm = raw.hour & HOUR_PM != 0;
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // Without a century register the byte reads as junk or 0
+    let century = convert(raw.century);
+    let century = if CENTURY_RANGE.contains(&century) { century } else { 20 };
+
+    DateTime {
+        year: century as u16 * 100 + convert(raw.year) as u16,
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bcd_conversion() {
+        assert_eq!(bcd_to_binary(0x00), 0);
+        assert_eq!(bcd_to_binary(0x09), 9);
+        assert_eq!(bcd_to_binary(0x59), 59);
+        assert_eq!(bcd_to_binary(0x23), 23);
+    }
+
+    #[test_case]
+    fn decode_handles_modes_and_century() {
+        // BCD, 12-hour: 1:37:42 PM on 2024-06-01 with a century register
+        let raw = RawTime {
+            second: 0x42,
+            minute: 0x37,
+            hour: HOUR_PM | 0x01,
+            day: 0x01,
+            month: 0x06,
+            year: 0x24,
+            century: 0x20,
+        };
+        let expected = DateTime { year: 2024, month: 6, day: 1, hour: 13, minute: 37, second: 42 };
+        assert_eq!(decode(&raw, 0), expected);
+
+        // Binary, 24-hour, no century register
+        let raw = RawTime { second: 42, minute: 37, hour: 13, day: 1, month: 6, year: 24, century: 0xFF };
+        assert_eq!(decode(&raw, STATUS_B_BINARY | STATUS_B_24_HOUR), expected);
+
+        // 12 AM is midnight, and the century register is honoured
+        let raw = RawTime { hour: 0x12, year: 0x99, century: 0x19, ..raw };
+        let decoded = decode(&raw, 0);
+        assert_eq!((decoded.year, decoded.hour), (1999, 0));
+    }
+}
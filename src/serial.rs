@@ -0,0 +1,235 @@
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::constants::serial::*;
+use crate::queue::ByteQueue;
+
+/// A 16550-compatible UART addressed by its I/O base
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> Self {
+        SerialPort { base }
+    }
+
+    fn port(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// Program the UART for 8N1 at `BAUD_DIVISOR` with FIFOs enabled
+    pub fn init(&mut self) {
+        unsafe {
+            // No interrupts while we reconfigure
+            self.port(REG_INT_ENABLE).write(0x00);
+
+            // Baud rate divisor is written with DLAB set
+            self.port(REG_LINE_CTRL).write(LINE_DLAB);
+            self.port(REG_DIVISOR_LOW).write((BAUD_DIVISOR & 0xFF) as u8);
+            self.port(REG_DIVISOR_HIGH).write((BAUD_DIVISOR >> 8) as u8);
+
+            // Clearing DLAB while setting the frame format
+            self.port(REG_LINE_CTRL).write(LINE_8N1);
+            self.port(REG_FIFO_CTRL).write(FIFO_ENABLE_CLEAR_14);
+            self.port(REG_MODEM_CTRL).write(MODEM_DTR_RTS_OUT2);
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.port(REG_LINE_STATUS).read() }
+    }
+
+    fn transmit_empty(&self) -> bool {
+        self.line_status() & LINE_STATUS_THR_EMPTY != 0
+    }
+
+    /// Whether a UART answers at all; a missing one floats the bus
+    pub fn present(&self) -> bool {
+        self.line_status() != LINE_STATUS_NO_UART
+    }
+
+    /// Interrupt when a byte arrives. Needs OUT2, which `init` sets, to
+    /// reach the PIC.
+    pub fn enable_receive_interrupt(&mut self) {
+        unsafe { self.port(REG_INT_ENABLE).write(INT_RECEIVED_DATA) };
+    }
+
+    /// Take one received byte, if one is waiting
+    pub fn read_byte(&mut self) -> Option<u8> {
+        if !self.present() || self.line_status() & LINE_STATUS_DATA_READY == 0 {
+            return None;
+        }
+        Some(unsafe { self.port(REG_DATA).read() })
+    }
+
+    /// Send one byte, waiting for the transmit holding register to drain
+    pub fn write_byte(&mut self, byte: u8) {
+        while !self.transmit_empty() {
+            core::hint::spin_loop();
+        }
+        unsafe {
+            self.port(REG_DATA).write(byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // Terminals expect CRLF line endings
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+pub static SERIAL1: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1_BASE));
+
+/// Initialize COM1
+pub fn init() {
+    SERIAL1.lock().init();
+}
+
+/// Whether COM1 exists, and so can be used as a terminal
+pub fn present() -> bool {
+    SERIAL1.lock().present()
+}
+
+/// Bytes the IRQ4 handler took from the UART, waiting for `read_key`
+static INPUT_QUEUE: ByteQueue<INPUT_QUEUE_SIZE> = ByteQueue::new();
+
+/// Have COM1 interrupt on received bytes instead of waiting to be polled
+pub fn enable_input_interrupt() {
+    let mut port = SERIAL1.lock();
+    if !port.present() {
+        return;
+    }
+    port.enable_receive_interrupt();
+    // Bytes that came in before now would otherwise hold the line raised
+    while let Some(byte) = port.read_byte() {
+        INPUT_QUEUE.push(byte);
+    }
+    drop(port);
+    crate::interrupts::unmask_irq(IRQ);
+}
+
+/// Move received bytes into the input queue. Called from the IRQ4 handler,
+/// so it reads the UART directly instead of through `SERIAL1`, which the
+/// interrupted code may be holding. Bytes that don't fit are dropped.
+pub fn receive() {
+    let mut port = SerialPort::new(COM1_BASE);
+    while let Some(byte) = port.read_byte() {
+        INPUT_QUEUE.push(byte);
+    }
+}
+
+/// The previous input byte was a CR, so an LF right after it belongs to
+/// the same Enter
+static AFTER_CR: AtomicBool = AtomicBool::new(false);
+
+/// Character the shell should see for a byte from the terminal: CR, LF and
+/// CRLF are all Enter, BS and DEL are Backspace, and other ASCII control
+/// characters pass through the way Ctrl+letter arrives from the keyboard.
+/// `None` for bytes to drop.
+fn translate(byte: u8, after_cr: bool) -> Option<char> {
+    match byte {
+        b'\n' if after_cr => None,
+        b'\r' | b'\n' => Some('\n'),
+        0x08 | 0x7F => Some('\u{8}'),
+        0x01..=0x7E => Some(byte as char),
+        _ => None,
+    }
+}
+
+/// Next received byte: queued ones first, then (for when IRQ4 doesn't
+/// arrive) the UART itself. Interrupts are held off so the handler can't
+/// queue a byte between the two and have it come out of order.
+fn next_byte() -> Option<u8> {
+    without_interrupts(|| INPUT_QUEUE.pop().or_else(|| SERIAL1.lock().read_byte()))
+}
+
+/// Next key typed on the serial terminal, as the same `DecodedKey` the
+/// PS/2 keyboard would produce for it
+pub fn read_key() -> Option<DecodedKey> {
+    while let Some(byte) = next_byte() {
+        let after_cr = AFTER_CR.swap(byte == b'\r', Ordering::Relaxed);
+        if let Some(c) = translate(byte, after_cr) {
+            return Some(DecodedKey::Unicode(c));
+        }
+    }
+    None
+}
+
+/// Send one byte on COM1, as is
+pub fn write_byte(byte: u8) {
+    SERIAL1.lock().write_byte(byte);
+}
+
+/// Send `s` on COM1, turning each `\n` into the CRLF terminals expect
+pub fn write_str(s: &str) {
+    for byte in s.bytes() {
+        if byte == b'\n' {
+            write_byte(b'\r');
+        }
+        write_byte(byte);
+    }
+}
+
+/// Lets `serial_print!` format straight into `write_str`
+struct Com1;
+
+impl fmt::Write for Com1 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_str(s);
+        Ok(())
+    }
+}
+
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($($arg:tt)*) => ($crate::serial_print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    Com1.write_fmt(args).unwrap();
+}
+
+/// Like `_print`, but gives up instead of spinning if COM1 is busy
+#[doc(hidden)]
+pub fn _try_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    if let Some(mut port) = SERIAL1.try_lock() {
+        port.write_fmt(args).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn terminal_bytes_translate() {
+        assert_eq!(translate(b'a', false), Some('a'));
+        assert_eq!(translate(b'\r', false), Some('\n'));
+        assert_eq!(translate(b'\n', false), Some('\n'));
+        assert_eq!(translate(b'\n', true), None);
+        assert_eq!(translate(0x7F, false), Some('\u{8}'));
+        assert_eq!(translate(0x03, false), Some('\u{3}'));
+        assert_eq!(translate(0xC3, false), None);
+    }
+}
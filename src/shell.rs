@@ -1,30 +1,230 @@
+use alloc::borrow::Cow;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
 use core::str;
-use pc_keyboard::DecodedKey;
-use crate::{print, println};
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use crate::constants::vga::NUM_CONSOLES;
+use crate::keyboard::Consumed;
+use crate::{logln, print, println, print_unmuted, println_unmuted};
 
 const LINE_BUF_LEN: usize = 128;
 const HISTORY_SIZE: usize = 10;
 
-static mut LINE_BUF: [u8; LINE_BUF_LEN] = [0; LINE_BUF_LEN];
-static mut LINE_LEN: usize = 0;
+/// Line editor and history state; each virtual console has its own
+struct Session {
+    line_buf: [u8; LINE_BUF_LEN],
+    line_len: usize,
+    /// Offset in `line_buf` where typing goes. Every byte takes one screen
+    /// cell, so it is also how far the screen cursor is from the line start.
+    cursor: usize,
+    /// Past command lines, oldest first, at most `HISTORY_SIZE`
+    history: VecDeque<String>,
+    /// Entry of `history` currently recalled with the arrow keys
+    history_browse_index: Option<usize>,
+    /// Line typed when browsing started; only entries starting with it are
+    /// recalled, and it comes back after the newest one
+    history_prefix: String,
+    /// Screen cells (row, col, len) of a recalled history line shown inverted
+    highlight: Option<(usize, usize, usize)>,
+    /// Whether the console has shown its first prompt
+    started: bool,
+    /// Status of the last command run, shown by `status`
+    last_status: u8,
+    /// Ctrl+R search in progress, if any
+    search: Option<Search>,
+}
+
+/// State of a Ctrl+R reverse incremental history search
+struct Search {
+    query: String,
+    /// Entry of `history` shown as the current match
+    found: Option<usize>,
+    /// Line being typed when the search began, restored on cancel
+    saved_line: String,
+    /// Number of cells the search line occupies after the prompt
+    shown: usize,
+}
+
+/// What a function key does when pressed
+#[derive(Clone)]
+enum Binding {
+    /// Clear the screen, keeping the line being typed (like Ctrl+L)
+    ClearScreen,
+    /// Run the most recent history entry again
+    RepeatLast,
+    /// Run this line as if it had been typed
+    Command(Cow<'static, str>),
+}
+
+impl Binding {
+    /// Builtin actions are named in angle brackets; anything else is a command
+    fn parse(text: &str) -> Binding {
+        match text {
+            "<clear>" => Binding::ClearScreen,
+            "<repeat>" => Binding::RepeatLast,
+            _ => Binding::Command(Cow::Owned(String::from(text))),
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Binding::ClearScreen => write!(f, "<clear>"),
+            Binding::RepeatLast => write!(f, "<repeat>"),
+            Binding::Command(line) => write!(f, "\"{}\"", line),
+        }
+    }
+}
+
+/// Keys that can be bound, in `ShellState::bindings` order
+const FUNCTION_KEYS: [KeyCode; 12] = [
+    KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5, KeyCode::F6,
+    KeyCode::F7, KeyCode::F8, KeyCode::F9, KeyCode::F10, KeyCode::F11, KeyCode::F12,
+];
+
+/// Index into `FUNCTION_KEYS` for a key name such as "F5"
+fn parse_function_key(name: &str) -> Option<usize> {
+    let number = name.strip_prefix(['F', 'f'])?.parse::<usize>().ok()?;
+    (1..=FUNCTION_KEYS.len()).contains(&number).then(|| number - 1)
+}
+
+/// Room in the alias table
+const MAX_ALIASES: usize = 16;
+/// Longest alias name; the expansion is bounded by `LINE_BUF_LEN`
+const MAX_ALIAS_NAME: usize = 16;
+
+/// A name that stands for the start of a command line
+struct Alias {
+    name: String,
+    value: String,
+}
+
+/// All shell state: one session per virtual console, plus the function
+/// key bindings, aliases and editing mode they share
+struct ShellState {
+    sessions: [Session; NUM_CONSOLES],
+    bindings: [Option<Binding>; FUNCTION_KEYS.len()],
+    aliases: [Option<Alias>; MAX_ALIASES],
+    /// Typing replaces the character under the cursor instead of
+    /// inserting; toggled with Insert
+    overwrite: bool,
+}
+
+impl ShellState {
+    /// Session of the console currently on screen
+    fn active(&mut self) -> &mut Session {
+        &mut self.sessions[crate::vga_buffer::active_console()]
+    }
+}
+
+/// Only locked for short stretches of line editing and never while a
+/// command runs, since commands such as `history` take it themselves
+static SHELL: Mutex<ShellState> = Mutex::new(ShellState {
+    sessions: [const { Session::new() }; NUM_CONSOLES],
+    bindings: [
+        Some(Binding::Command(Cow::Borrowed("help"))),
+        None,
+        None,
+        None,
+        Some(Binding::RepeatLast),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        Some(Binding::ClearScreen),
+    ],
+    aliases: [const { None }; MAX_ALIASES],
+    overwrite: false,
+});
+
+/// Key handler priorities: console switching works whatever the shell is
+/// doing, e.g. in the middle of a Ctrl+R search
+const CONSOLE_HOTKEY_PRIORITY: u8 = 200;
+const SHELL_PRIORITY: u8 = 0;
+
+/// Log which input sources are available, subscribe the console hotkey
+/// and shell key handlers, and print the first prompt on the boot console
+pub fn init() {
+    use crate::keyboard::subscribe;
+
+    match (crate::keyboard::present(), crate::serial::present()) {
+        (true, true) => logln!("Input: PS/2 keyboard and serial terminal (COM1)"),
+        (true, false) => logln!("Input: PS/2 keyboard"),
+        (false, true) => logln!("WARNING: no PS/2 keyboard detected, input is from the serial terminal (COM1) only"),
+        (false, false) => logln!("WARNING: no PS/2 keyboard or serial port detected, there is no input"),
+    }
+    if subscribe(CONSOLE_HOTKEY_PRIORITY, console_hotkey).is_err()
+        || subscribe(SHELL_PRIORITY, shell_key).is_err()
+    {
+        logln!("WARNING: key handler table full, shell input may not work");
+    }
+    SHELL.lock().active().started = true;
+    prompt();
+}
 
-static mut HISTORY: [[u8; LINE_BUF_LEN]; HISTORY_SIZE] = [[0; LINE_BUF_LEN]; HISTORY_SIZE];
-static mut HISTORY_LENS: [usize; HISTORY_SIZE] = [0; HISTORY_SIZE];
-static mut HISTORY_INDEX: usize = 0;
-static mut HISTORY_COUNT: usize = 0;
-static mut HISTORY_BROWSE_INDEX: Option<usize> = None;
+/// Print a message from outside the shell, like a late boot warning, on a
+/// line of its own, then put back the prompt and the line being typed
+pub fn notice(args: fmt::Arguments) {
+    let mut shell = SHELL.lock();
+    let session = shell.active();
+    session.clear_highlight();
+    println_unmuted!();
+    logln!("{}", args);
+    prompt();
+    session.redraw_line();
+}
 
 fn prompt() {
-    print!("> ");
+    print_unmuted!("> ");
+}
+
+/// Statuses a command line can end with
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_FAILED: u8 = 1;
+/// Bad arguments, or a line that couldn't be split into them
+pub const STATUS_USAGE: u8 = 2;
+pub const STATUS_UNKNOWN_COMMAND: u8 = 127;
+
+/// Why a command failed. The shell prints it in red after the command
+/// returns and records its status.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShellError {
+    /// The arguments don't fit; the command's usage line is shown
+    Usage,
+    /// Anything else, shown after the command's name
+    Failed(String),
+}
+
+impl ShellError {
+    pub fn status(&self) -> u8 {
+        match self {
+            ShellError::Usage => STATUS_USAGE,
+            ShellError::Failed(_) => STATUS_FAILED,
+        }
+    }
+}
+
+/// `Err(ShellError::Failed(..))` with a formatted message
+macro_rules! fail {
+    ($($arg:tt)*) => (Err(ShellError::Failed(format!($($arg)*))));
 }
 
 /// Command function type
-type CommandFn = fn(&[&str]);
+pub type CommandFn = fn(&[&str]) -> Result<(), ShellError>;
 
 /// Command registry entry
+#[derive(Clone, Copy)]
 struct Command {
     name: &'static str,
     help: &'static str,
+    /// One-line synopsis shown by `help <command>`
+    usage: &'static str,
     func: CommandFn,
 }
 
@@ -32,316 +232,2946 @@ struct Command {
 const COMMANDS: &[Command] = &[
     Command {
         name: "help",
-        help: "Display this help message",
+        help: "Display this help message, or one command's",
+        usage: "help [command]",
         func: cmd_help,
     },
     Command {
         name: "echo",
         help: "Echo arguments to the screen",
+        usage: "echo <text>...",
         func: cmd_echo,
     },
+    Command {
+        name: "status",
+        help: "Show how the last command ended",
+        usage: "status",
+        func: cmd_status,
+    },
     Command {
         name: "clear",
-        help: "Clear the screen",
+        help: "Clear the screen, optionally in new colors",
+        usage: "clear [bg [fg]]",
         func: cmd_clear,
     },
     Command {
         name: "reboot",
-        help: "Reboot the system",
+        help: "Reboot the system after asking (-f skips the question)",
+        usage: "reboot [-f]",
         func: cmd_reboot,
     },
+    Command {
+        name: "shutdown",
+        help: "Power off (QEMU/Bochs only; -f skips the question)",
+        usage: "shutdown [-f]",
+        func: cmd_shutdown,
+    },
+    Command {
+        name: "bind",
+        help: "List or set function key bindings",
+        usage: "bind [Fn [command|<clear>|<repeat>]]",
+        func: cmd_bind,
+    },
+    Command {
+        name: "alias",
+        help: "Define or list command aliases",
+        usage: "alias [name=command...]",
+        func: cmd_alias,
+    },
+    Command {
+        name: "unalias",
+        help: "Remove a command alias",
+        usage: "unalias <name>",
+        func: cmd_unalias,
+    },
+    Command {
+        name: "pause",
+        help: "Wait for any key",
+        usage: "pause",
+        func: cmd_pause,
+    },
+    Command {
+        name: "watch",
+        help: "Re-run a command every N seconds until a key is pressed",
+        usage: "watch <seconds> <command> [args...]",
+        func: cmd_watch,
+    },
+    Command {
+        name: "bench",
+        help: "Time a command in TSC cycles",
+        usage: "bench <command> [args...]",
+        func: cmd_bench,
+    },
+    Command {
+        name: "repeat",
+        help: "Run a command several times (Ctrl+C stops)",
+        usage: "repeat <count> <command> [args...]",
+        func: cmd_repeat,
+    },
+    Command {
+        name: "lock",
+        help: "Lock the console until the password is typed",
+        usage: "lock [password]",
+        func: cmd_lock,
+    },
     Command {
         name: "history",
-        help: "Show command history",
+        help: "Show or clear command history",
+        usage: "history [clear|N]",
         func: cmd_history,
     },
+    Command {
+        name: "wrap",
+        help: "Toggle line wrapping, or wrap at spaces",
+        usage: "wrap on|word|off",
+        func: cmd_wrap,
+    },
+    Command {
+        name: "ansi",
+        help: "Toggle ANSI color/cursor escape handling",
+        usage: "ansi on|off",
+        func: cmd_ansi,
+    },
+    Command {
+        name: "blink",
+        help: "Toggle a software-drawn blinking cursor",
+        usage: "blink on|off",
+        func: cmd_blink,
+    },
+    Command {
+        name: "palette",
+        help: "Switch the color palette",
+        usage: "palette <name>",
+        func: cmd_palette,
+    },
+    Command {
+        name: "ascii",
+        help: "Show the CP437 character table",
+        usage: "ascii [all]",
+        func: cmd_ascii,
+    },
+    Command {
+        name: "colortest",
+        help: "Show every foreground/background color combination",
+        usage: "colortest",
+        func: cmd_colortest,
+    },
+    Command {
+        name: "ls",
+        help: "List the files in the RAM filesystem",
+        usage: "ls",
+        func: cmd_ls,
+    },
+    Command {
+        name: "cat",
+        help: "Print a file",
+        usage: "cat <name>",
+        func: cmd_cat,
+    },
+    Command {
+        name: "write",
+        help: "Write text to a file, replacing what it held",
+        usage: "write <name> [text...]",
+        func: cmd_write,
+    },
+    Command {
+        name: "edit",
+        help: "Type lines into a file, ending with a '.' line",
+        usage: "edit <name>",
+        func: cmd_edit,
+    },
+    Command {
+        name: "date",
+        help: "Show the date and time from the RTC",
+        usage: "date",
+        func: cmd_date,
+    },
+    Command {
+        name: "time",
+        help: "Show the time of day from the RTC",
+        usage: "time",
+        func: cmd_time,
+    },
+    Command {
+        name: "uptime",
+        help: "Show how long the system has been running",
+        usage: "uptime",
+        func: cmd_uptime,
+    },
+    Command {
+        name: "quiet",
+        help: "Suppress command output",
+        usage: "quiet on|off",
+        func: cmd_quiet,
+    },
+    Command {
+        name: "overflow",
+        help: "Overflow the kernel stack to test the guard page (halts)",
+        usage: "overflow",
+        func: cmd_overflow,
+    },
+    Command {
+        name: "bp",
+        help: "Execute int3 to test the breakpoint handler",
+        usage: "bp",
+        func: cmd_bp,
+    },
+    Command {
+        name: "panic",
+        help: "Panic the kernel (halts the system)",
+        usage: "panic",
+        func: cmd_panic,
+    },
+    Command {
+        name: "divzero",
+        help: "Divide by zero to test the divide error handler (halts the system)",
+        usage: "divzero",
+        func: cmd_divzero,
+    },
+    Command {
+        name: "usertest",
+        help: "Run a small program in ring 3 that makes a system call",
+        usage: "usertest",
+        func: cmd_usertest,
+    },
+    Command {
+        name: "kbstat",
+        help: "Show keyboard input counters",
+        usage: "kbstat",
+        func: cmd_kbstat,
+    },
+    Command {
+        name: "mem",
+        help: "Show heap usage and the sizes of static buffers",
+        usage: "mem",
+        func: cmd_mem,
+    },
+    Command {
+        name: "showkeys",
+        help: "Print raw scancodes and decoded keys until Escape",
+        usage: "showkeys",
+        func: cmd_showkeys,
+    },
+    Command {
+        name: "remap",
+        help: "Make one key type as another, or list/clear remappings",
+        usage: "remap [from to | clear]",
+        func: cmd_remap,
+    },
+    Command {
+        name: "beep",
+        help: "Sound the PC speaker",
+        usage: "beep [hz] [ms]",
+        func: cmd_beep,
+    },
+    Command {
+        name: "sleep",
+        help: "Wait for a number of seconds, or milliseconds with ms (Ctrl+C stops)",
+        usage: "sleep <seconds>|<n>ms",
+        func: cmd_sleep,
+    },
+    Command {
+        name: "calc",
+        help: "Integer arithmetic, left to right (calc 3 + 4 * 2 is 14)",
+        usage: "calc <number> [<op> <number>]...",
+        func: cmd_calc,
+    },
+    Command {
+        name: "rand",
+        help: "Print a random number, in lo..=hi if given",
+        usage: "rand [lo hi]",
+        func: cmd_rand,
+    },
+    Command {
+        name: "uname",
+        help: "Show the OS name, or with -a the version, target and CPU",
+        usage: "uname [-a]",
+        func: cmd_uname,
+    },
+    Command {
+        name: "version",
+        help: "Show the OS version",
+        usage: "version",
+        func: cmd_version,
+    },
+    Command {
+        name: "cpuid",
+        help: "Show the CPU vendor and feature flags, or dump one leaf",
+        usage: "cpuid [leaf]",
+        func: cmd_cpuid,
+    },
+    Command {
+        name: "layout",
+        help: "List or switch keyboard layouts",
+        usage: "layout [name]",
+        func: cmd_layout,
+    },
+    Command {
+        name: "keymap",
+        help: "Same as layout",
+        usage: "keymap [name]",
+        func: cmd_layout,
+    },
+    Command {
+        name: "mouse",
+        help: "Show or hide the mouse pointer, or print events",
+        usage: "mouse on|off|events",
+        func: cmd_mouse,
+    },
+    Command {
+        name: "kbinfo",
+        help: "Show the keyboard scancode set, translation and layout",
+        usage: "kbinfo",
+        func: cmd_kbinfo,
+    },
+    Command {
+        name: "lsps2",
+        help: "Show the PS/2 controller's ports and attached devices",
+        usage: "lsps2",
+        func: cmd_lsps2,
+    },
+    Command {
+        name: "lspci",
+        help: "List the devices on the PCI bus",
+        usage: "lspci",
+        func: cmd_lspci,
+    },
+    Command {
+        name: "kbrate",
+        help: "Set key repeat delay and rate",
+        usage: "kbrate <delay-ms> <cps>",
+        func: cmd_kbrate,
+    },
+    Command {
+        name: "regs",
+        help: "Dump the CPU registers (general-purpose values are approximate)",
+        usage: "regs",
+        func: cmd_regs,
+    },
+    Command {
+        name: "peek",
+        help: "Read a byte, word or dword of memory",
+        usage: "peek [-b|-w|-d] <addr>",
+        func: cmd_peek,
+    },
+    Command {
+        name: "poke",
+        help: "Write a byte, word or dword of memory",
+        usage: "poke [-b|-w|-d] <addr> <value>",
+        func: cmd_poke,
+    },
 ];
 
-/// Find command by name
-fn find_command(name: &str) -> Option<&'static Command> {
-    COMMANDS.iter().find(|cmd| cmd.name == name)
+/// Byte offset where the last word of `line` starts, skipping trailing spaces
+fn word_start(line: &str) -> usize {
+    let trimmed = line.trim_end();
+    let word = trimmed.rsplit(char::is_whitespace).next().unwrap_or("");
+    trimmed.len() - word.len()
+}
+
+/// Room for commands added at runtime by `register_command`
+const MAX_REGISTERED_COMMANDS: usize = 16;
+
+type Registry = [Option<Command>; MAX_REGISTERED_COMMANDS];
+
+/// Commands registered by drivers at init, searched after `COMMANDS`
+static REGISTERED: Mutex<Registry> = Mutex::new([None; MAX_REGISTERED_COMMANDS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// A built-in or registered command already has this name
+    Duplicate,
+    /// All `MAX_REGISTERED_COMMANDS` slots are taken
+    Full,
+}
+
+/// Add a command at runtime, so a module can provide its own commands from
+/// its init function. Names are compared ignoring ASCII case.
+pub fn register_command(
+    name: &'static str,
+    help: &'static str,
+    usage: &'static str,
+    func: CommandFn,
+) -> Result<(), RegisterError> {
+    register_in(&mut REGISTERED.lock(), Command { name, help, usage, func })
+}
+
+fn register_in(registered: &mut Registry, command: Command) -> Result<(), RegisterError> {
+    let name = command.name;
+    if COMMANDS.iter().any(|cmd| cmd.name.eq_ignore_ascii_case(name)) {
+        return Err(RegisterError::Duplicate);
+    }
+    if registered.iter().flatten().any(|cmd| cmd.name.eq_ignore_ascii_case(name)) {
+        return Err(RegisterError::Duplicate);
+    }
+    let slot = registered.iter_mut().find(|slot| slot.is_none()).ok_or(RegisterError::Full)?;
+    *slot = Some(command);
+    Ok(())
+}
+
+/// Built-in commands followed by registered ones. The registry is copied
+/// out so no lock is held while a command or the pager runs.
+fn all_commands() -> impl Iterator<Item = Command> {
+    let registered = *REGISTERED.lock();
+    COMMANDS.iter().copied().chain(registered.into_iter().flatten())
+}
+
+/// Find command by name, ignoring ASCII case
+fn find_command(name: &str) -> Option<Command> {
+    all_commands().find(|cmd| cmd.name.eq_ignore_ascii_case(name))
 }
 
-/// Called from main when a key is decoded
-pub fn process_key(key: DecodedKey) {
+/// Line editing for a decoded key; `shell_key` feeds it from the keyboard
+fn process_key(key: DecodedKey) {
+    crate::vga_buffer::reset_blink();
+    let searching = SHELL.lock().active().search.is_some();
+    if searching && search_key(key) {
+        return;
+    }
     match key {
         DecodedKey::Unicode(c) => match c {
             '\n' => {
-                let cmd = get_line();
-                println!("");
+                let mut line = [0u8; LINE_BUF_LEN];
+                let len = SHELL.lock().active().take_line(&mut line);
+                println_unmuted!();
+                let cmd = str::from_utf8(&line[..len]).unwrap_or("");
                 if !cmd.is_empty() {
-                    add_to_history(cmd);
-                    execute_command(cmd);
+                    run_line(cmd);
                 }
                 prompt();
             }
             '\u{8}' | '\u{7f}' => {
-                backspace();
+                SHELL.lock().active().backspace();
+            }
+            // Ctrl+C: abandon the line without running or recording it
+            '\u{3}' => {
+                SHELL.lock().active().cancel_line();
+                println_unmuted!("^C");
+                prompt();
             }
+            // Ctrl+L: clear the screen but keep the line being typed
+            '\u{c}' => clear_screen_keep_line(),
+            // Ctrl+R: search backwards through history
+            '\u{12}' => SHELL.lock().active().start_search(),
+            // Ctrl+U: erase the whole line
+            '\u{15}' => {
+                let mut shell = SHELL.lock();
+                let session = shell.active();
+                session.stop_browsing();
+                session.clear_current_line();
+            }
+            // Ctrl+W: erase the word before the cursor
+            '\u{17}' => SHELL.lock().active().delete_word(),
+            '\t' => complete_line(),
+            // Other Ctrl+letter combinations aren't bound to anything
+            c if c.is_ascii_control() => {}
             c => {
-                push_char(c);
+                let mut shell = SHELL.lock();
+                if shell.overwrite {
+                    shell.active().overwrite_char(c);
+                } else {
+                    shell.active().push_char(c);
+                }
             }
         },
         DecodedKey::RawKey(raw) => {
-            use pc_keyboard::KeyCode;
             match raw {
-                KeyCode::ArrowUp => history_prev(),
-                KeyCode::ArrowDown => history_next(),
-                _ => {} // Ignore other special keys
+                KeyCode::ArrowUp => SHELL.lock().active().history_prev(),
+                KeyCode::ArrowDown => SHELL.lock().active().history_next(),
+                KeyCode::ArrowLeft => SHELL.lock().active().cursor_left(),
+                KeyCode::ArrowRight => SHELL.lock().active().cursor_right(),
+                KeyCode::Home => SHELL.lock().active().cursor_home(),
+                KeyCode::End => SHELL.lock().active().cursor_to_end(),
+                KeyCode::Delete => SHELL.lock().active().delete_char(),
+                KeyCode::Insert => toggle_overwrite(),
+                _ => {
+                    let Some(index) = FUNCTION_KEYS.iter().position(|&key| key == raw) else {
+                        return; // Ignore other special keys
+                    };
+                    let binding = SHELL.lock().bindings[index].clone();
+                    if let Some(binding) = binding {
+                        run_binding(binding);
+                    }
+                }
             }
         }
     }
 }
 
-fn push_char(c: char) {
-    let mut buf_overflow = false;
-    unsafe {
-        if LINE_LEN < LINE_BUF_LEN - 1 {
-            LINE_BUF[LINE_LEN] = c as u8;
-            LINE_LEN += 1;
-            print!("{}", c);
-        } else {
-            buf_overflow = true;
-        }
+/// Tab: complete the command name being typed. A unique match is filled in
+/// with a space after it; several matches are listed and the line is
+/// extended as far as they agree. Arguments aren't completed.
+fn complete_line() {
+    let mut shell = SHELL.lock();
+    let session = shell.active();
+    let typed = String::from(session.line());
+    if typed.contains(char::is_whitespace) {
+        return;
     }
-    if buf_overflow {
-        println!("\n[buffer full]");
-        unsafe {
-            LINE_LEN = 0;
+    match complete(&typed) {
+        (0, _) => {}
+        (1, name) => session.replace_line(&format!("{} ", name)),
+        (_, common) => {
+            session.clear_highlight();
+            println_unmuted!();
+            for cmd in all_commands().filter(|cmd| has_prefix(cmd.name, &typed)) {
+                print_unmuted!("{}  ", cmd.name);
+            }
+            println_unmuted!();
+            prompt();
+            if common.len() > typed.len() {
+                session.set_line(common);
+            }
+            session.redraw_line();
         }
-        prompt();
     }
 }
 
-fn backspace() {
-    unsafe {
-        if LINE_LEN > 0 {
-            LINE_LEN -= 1;
-            crate::vga_buffer::backspace();
-        }
-    }
+/// Whether command `name` starts with `prefix`, ignoring ASCII case
+fn has_prefix(name: &str, prefix: &str) -> bool {
+    name.len() >= prefix.len() && name.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
 }
 
-fn get_line() -> &'static str {
-    unsafe {
-        let slice = &LINE_BUF[..LINE_LEN];
-        match str::from_utf8(slice) {
-            Ok(s) => {
-                LINE_LEN = 0;
-                HISTORY_BROWSE_INDEX = None;
-                s
-            }
-            Err(_) => {
-                LINE_LEN = 0;
-                HISTORY_BROWSE_INDEX = None;
-                ""
-            }
-        }
+/// How many commands start with `prefix`, and the longest prefix of a
+/// name that all of them share
+fn complete(prefix: &str) -> (usize, &'static str) {
+    let mut count = 0;
+    let mut common = "";
+    for cmd in all_commands().filter(|cmd| has_prefix(cmd.name, prefix)) {
+        common = if count == 0 {
+            cmd.name
+        } else {
+            let shared = common.bytes().zip(cmd.name.bytes()).take_while(|(a, b)| a == b).count();
+            &common[..shared]
+        };
+        count += 1;
     }
+    (count, common)
 }
 
-fn add_to_history(line: &str) {
-    unsafe {
-        if line.is_empty() {
-            return;
-        }
+/// Switch between inserting and overwriting, shown by the cursor changing
+/// from an underline to a block
+fn toggle_overwrite() {
+    let mut shell = SHELL.lock();
+    shell.overwrite = !shell.overwrite;
+    update_cursor_shape(shell.overwrite);
+}
 
-        // Copy to history
-        let bytes = line.as_bytes();
-        let len = bytes.len().min(LINE_BUF_LEN);
-        HISTORY[HISTORY_INDEX][..len].copy_from_slice(&bytes[..len]);
-        HISTORY_LENS[HISTORY_INDEX] = len;
+/// Show the hardware cursor in the shape for the editing mode, unless the
+/// software cursor is standing in for it
+fn update_cursor_shape(overwrite: bool) {
+    use crate::constants::vga::{CURSOR_BLOCK, CURSOR_UNDERLINE};
 
-        HISTORY_INDEX = (HISTORY_INDEX + 1) % HISTORY_SIZE;
-        if HISTORY_COUNT < HISTORY_SIZE {
-            HISTORY_COUNT += 1;
-        }
+    if crate::vga_buffer::soft_cursor_enabled() {
+        return;
     }
+    let (start, end) = if overwrite { CURSOR_BLOCK } else { CURSOR_UNDERLINE };
+    crate::vga_buffer::enable_cursor(start, end);
 }
 
-fn history_prev() {
-    unsafe {
-        if HISTORY_COUNT == 0 {
-            return;
-        }
-
-        let browse_idx = match HISTORY_BROWSE_INDEX {
-            None => {
-                // Start browsing from most recent
-                if HISTORY_COUNT < HISTORY_SIZE {
-                    HISTORY_COUNT - 1
-                } else {
-                    (HISTORY_INDEX + HISTORY_SIZE - 1) % HISTORY_SIZE
-                }
-            }
-            Some(idx) => {
-                // Go to previous command
-                if HISTORY_COUNT < HISTORY_SIZE {
-                    if idx > 0 {
-                        idx - 1
-                    } else {
-                        return; // At oldest command
-                    }
-                } else {
-                    (idx + HISTORY_SIZE - 1) % HISTORY_SIZE
-                }
-            }
-        };
+fn clear_screen_keep_line() {
+    let mut shell = SHELL.lock();
+    let session = shell.active();
+    session.clear_highlight();
+    crate::vga_buffer::clear_screen();
+    prompt();
+    session.redraw_line();
+}
 
-        HISTORY_BROWSE_INDEX = Some(browse_idx);
-        load_history_line(browse_idx);
-    }
+/// Carry out a function key binding. Commands go through the prompt as if
+/// typed, so they're echoed and recorded in history.
+fn run_binding(binding: Binding) {
+    let line = match binding {
+        Binding::ClearScreen => return clear_screen_keep_line(),
+        Binding::RepeatLast => match SHELL.lock().active().history.back() {
+            Some(line) => line.clone(),
+            None => return,
+        },
+        Binding::Command(line) => line.into_owned(),
+    };
+    SHELL.lock().active().replace_line(&line);
+    process_key(DecodedKey::Unicode('\n'));
 }
 
-fn history_next() {
-    unsafe {
-        if let Some(idx) = HISTORY_BROWSE_INDEX {
-            if HISTORY_COUNT < HISTORY_SIZE {
-                if idx + 1 < HISTORY_COUNT {
-                    let new_idx = idx + 1;
-                    HISTORY_BROWSE_INDEX = Some(new_idx);
-                    load_history_line(new_idx);
-                } else {
-                    // At newest, clear line
-                    HISTORY_BROWSE_INDEX = None;
-                    clear_current_line();
-                }
-            } else {
-                let new_idx = (idx + 1) % HISTORY_SIZE;
-                if new_idx != HISTORY_INDEX {
-                    HISTORY_BROWSE_INDEX = Some(new_idx);
-                    load_history_line(new_idx);
-                } else {
-                    HISTORY_BROWSE_INDEX = None;
-                    clear_current_line();
-                }
-            }
+/// Handle a key while a Ctrl+R search is active. Returns false if the key
+/// ended the search and should also get its usual handling (Enter runs
+/// the accepted line, arrows start editing it).
+fn search_key(key: DecodedKey) -> bool {
+    let mut shell = SHELL.lock();
+    let session = shell.active();
+    match key {
+        DecodedKey::Unicode('\u{12}') => session.search_older(),
+        // Escape or Ctrl+C give back the line as it was before the search
+        DecodedKey::Unicode('\u{1b}') | DecodedKey::Unicode('\u{3}') => session.end_search(false),
+        DecodedKey::Unicode('\u{8}') | DecodedKey::Unicode('\u{7f}') => session.search_backspace(),
+        DecodedKey::Unicode(c) if c != '\n' && !c.is_ascii_control() => session.search_push(c),
+        DecodedKey::Unicode(c) if c != '\n' => {}
+        _ => {
+            session.end_search(true);
+            return false;
         }
     }
+    true
 }
 
-fn load_history_line(idx: usize) {
-    unsafe {
-        // Clear current line
-        clear_current_line();
-
-        // Load history entry
-        let len = HISTORY_LENS[idx];
-        LINE_BUF[..len].copy_from_slice(&HISTORY[idx][..len]);
-        LINE_LEN = len;
-
-        // Display it
-        if let Ok(s) = str::from_utf8(&LINE_BUF[..len]) {
-            print!("{}", s);
-        }
+/// Alt+F1..F4 switches to that virtual console
+fn console_hotkey(key: DecodedKey) -> Consumed {
+    let index = match key {
+        DecodedKey::RawKey(KeyCode::F1) => 0,
+        DecodedKey::RawKey(KeyCode::F2) => 1,
+        DecodedKey::RawKey(KeyCode::F3) => 2,
+        DecodedKey::RawKey(KeyCode::F4) => 3,
+        _ => return Consumed::No,
+    };
+    if !crate::keyboard::modifiers().alt {
+        return Consumed::No;
     }
+    switch_console(index);
+    Consumed::Yes
 }
 
-fn clear_current_line() {
-    unsafe {
-        for _ in 0..LINE_LEN {
-            crate::vga_buffer::backspace();
-        }
-        LINE_LEN = 0;
-    }
+/// The shell takes every key that reaches it
+fn shell_key(key: DecodedKey) -> Consumed {
+    process_key(key);
+    Consumed::Yes
 }
 
-fn execute_command(line: &str) {
-    let parts = split_whitespace(line);
-    if parts[0].is_empty() {
+/// Bring console `index` to the front, greeting it the first time it's shown
+fn switch_console(index: usize) {
+    if !crate::vga_buffer::switch_console(index) {
         return;
     }
-
-    let cmd_name = parts[0];
-    let args = &parts[1..];
-
-    match find_command(cmd_name) {
-        Some(cmd) => (cmd.func)(args),
-        None => println!("Unknown command: {}. Type 'help' for available commands.", cmd_name),
+    crate::statusbar::draw();
+    let first_visit = !core::mem::replace(&mut SHELL.lock().active().started, true);
+    if first_visit {
+        println_unmuted!("DxOS virtual console {}", index + 1);
+        prompt();
     }
 }
 
-// ============================================================================
-// Command implementations
-// ============================================================================
+impl Session {
+    const fn new() -> Self {
+        Session {
+            line_buf: [0; LINE_BUF_LEN],
+            line_len: 0,
+            cursor: 0,
+            history: VecDeque::new(),
+            history_browse_index: None,
+            history_prefix: String::new(),
+            highlight: None,
+            started: false,
+            last_status: STATUS_OK,
+            search: None,
+        }
+    }
 
-fn cmd_help(_args: &[&str]) {
-    println!("Available commands:");
-    for cmd in COMMANDS {
-        println!("  {:<12} - {}", cmd.name, cmd.help);
+    /// The typed line; only whole UTF-8 characters are ever stored
+    fn line(&self) -> &str {
+        str::from_utf8(&self.line_buf[..self.line_len]).unwrap_or("")
     }
-}
 
-fn cmd_echo(args: &[&str]) {
-    for (i, arg) in args.iter().enumerate() {
-        if i > 0 {
-            print!(" ");
+    /// Store `line` as the typed line, cut at a character boundary if it
+    /// doesn't fit. Doesn't touch the screen.
+    fn set_line(&mut self, line: &str) {
+        let mut len = line.len().min(LINE_BUF_LEN - 1);
+        while !line.is_char_boundary(len) {
+            len -= 1;
         }
-        print!("{}", arg);
+        self.line_buf[..len].copy_from_slice(&line.as_bytes()[..len]);
+        self.line_len = len;
+        self.cursor = len;
     }
-    println!("");
-}
 
-fn cmd_clear(_args: &[&str]) {
-    crate::vga_buffer::clear_screen();
-}
+    /// The part of the line left of the cursor
+    fn before_cursor(&self) -> &str {
+        &self.line()[..self.cursor]
+    }
 
-fn cmd_reboot(_args: &[&str]) {
-    println!("Rebooting system...");
-    crate::keyboard::reset_cpu();
-}
+    /// The part of the line from the cursor on
+    fn after_cursor(&self) -> &str {
+        &self.line()[self.cursor..]
+    }
 
-fn cmd_history(_args: &[&str]) {
-    unsafe {
-        if HISTORY_COUNT == 0 {
-            println!("No command history");
-            return;
+    /// Echo the text from the cursor on and step back over it, after it has
+    /// moved. `erase` more cells are blanked for a line that got shorter.
+    fn redraw_tail(&self, erase: usize) {
+        let tail = self.after_cursor();
+        print_unmuted!("{}", tail);
+        for _ in 0..erase {
+            print_unmuted!(" ");
         }
+        crate::vga_buffer::cursor_back(tail.len() + erase);
+    }
 
-        println!("Command history:");
-        let start = if HISTORY_COUNT < HISTORY_SIZE {
-            0
+    /// Insert `c` at the cursor, pushing the rest of the line right
+    fn push_char(&mut self, c: char) {
+        self.clear_highlight();
+        self.stop_browsing();
+        // Store all of the character's bytes or none of them
+        let mut utf8 = [0u8; 4];
+        let encoded = c.encode_utf8(&mut utf8).as_bytes();
+        let len = encoded.len();
+        if self.line_len + len < LINE_BUF_LEN {
+            self.line_buf.copy_within(self.cursor..self.line_len, self.cursor + len);
+            self.line_buf[self.cursor..self.cursor + len].copy_from_slice(encoded);
+            self.line_len += len;
+            self.cursor += len;
+            print_unmuted!("{}", c);
+            self.redraw_tail(0);
         } else {
-            HISTORY_INDEX
-        };
-
-        for i in 0..HISTORY_COUNT {
-            let idx = (start + i) % HISTORY_SIZE;
-            let len = HISTORY_LENS[idx];
-            if let Ok(s) = str::from_utf8(&HISTORY[idx][..len]) {
-                println!("  {} {}", i + 1, s);
+            self.cursor_to_end();
+            println_unmuted!("\n[buffer full]");
+            self.line_len = 0;
+            self.cursor = 0;
+            prompt();
+        }
+    }
+
+    /// Replace the character under the cursor with `c`. At the end of the
+    /// line this is the same as inserting.
+    fn overwrite_char(&mut self, c: char) {
+        // The two may differ in length, so drop the old one and insert
+        self.delete_char();
+        self.push_char(c);
+    }
+
+    /// Delete the character left of the cursor, pulling the rest of the
+    /// line left
+    fn backspace(&mut self) {
+        self.clear_highlight();
+        self.stop_browsing();
+        let Some(c) = self.before_cursor().chars().next_back() else { return };
+        let len = c.len_utf8();
+        self.line_buf.copy_within(self.cursor..self.line_len, self.cursor - len);
+        self.line_len -= len;
+        self.cursor -= len;
+        // The screen shows a cell per byte of a non-ASCII character
+        crate::vga_buffer::cursor_back(len);
+        self.redraw_tail(len);
+    }
+
+    /// Delete the character under the cursor, pulling the rest of the line
+    /// left. Nothing happens at the end of the line.
+    fn delete_char(&mut self) {
+        self.clear_highlight();
+        self.stop_browsing();
+        let Some(c) = self.after_cursor().chars().next() else { return };
+        let len = c.len_utf8();
+        self.line_buf.copy_within(self.cursor + len..self.line_len, self.cursor);
+        self.line_len -= len;
+        self.redraw_tail(len);
+    }
+
+    /// Erase back to the start of the previous word, and any spaces after it
+    fn delete_word(&mut self) {
+        let start = word_start(self.before_cursor());
+        while self.cursor > start {
+            self.backspace();
+        }
+    }
+
+    fn cursor_left(&mut self) {
+        let Some(c) = self.before_cursor().chars().next_back() else { return };
+        self.cursor -= c.len_utf8();
+        crate::vga_buffer::cursor_back(c.len_utf8());
+    }
+
+    fn cursor_home(&mut self) {
+        crate::vga_buffer::cursor_back(self.cursor);
+        self.cursor = 0;
+    }
+
+    fn cursor_right(&mut self) {
+        let Some(c) = self.after_cursor().chars().next() else { return };
+        self.cursor += c.len_utf8();
+        // Re-echo the character to step over it, wrapping like typing does
+        print_unmuted!("{}", c);
+    }
+
+    /// Move the screen cursor past the end of the line, so output that
+    /// follows doesn't land on top of it
+    fn cursor_to_end(&mut self) {
+        print_unmuted!("{}", self.after_cursor());
+        self.cursor = self.line_len;
+    }
+
+    /// Move the typed line into `out` and reset the editor; returns its length
+    fn take_line(&mut self, out: &mut [u8; LINE_BUF_LEN]) -> usize {
+        self.clear_highlight();
+        self.cursor_to_end();
+        let len = self.line_len;
+        out[..len].copy_from_slice(&self.line_buf[..len]);
+        self.line_len = 0;
+        self.cursor = 0;
+        self.history_browse_index = None;
+        len
+    }
+
+    /// Drop the typed line and stop browsing history
+    fn cancel_line(&mut self) {
+        self.clear_highlight();
+        self.cursor_to_end();
+        self.line_len = 0;
+        self.cursor = 0;
+        self.history_browse_index = None;
+    }
+
+    fn add_to_history(&mut self, line: &str) {
+        // A repeated command doesn't need a second slot
+        if line.is_empty() || self.history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+
+    fn history_prev(&mut self) {
+        if self.history_browse_index.is_none() {
+            self.history_prefix = String::from(self.line());
+        }
+        // Start browsing from most recent
+        let end = self.history_browse_index.unwrap_or(self.history.len());
+        let prefix = self.history_prefix.as_str();
+        let Some(idx) = self.history.range(..end).rposition(|entry| entry.starts_with(prefix)) else {
+            // Nothing stored, or no older match
+            return;
+        };
+        self.history_browse_index = Some(idx);
+        self.load_history_line(idx);
+    }
+
+    fn history_next(&mut self) {
+        let Some(idx) = self.history_browse_index else { return };
+        let prefix = self.history_prefix.as_str();
+        match self.history.range(idx + 1..).position(|entry| entry.starts_with(prefix)) {
+            Some(offset) => {
+                self.history_browse_index = Some(idx + 1 + offset);
+                self.load_history_line(idx + 1 + offset);
+            }
+            None => {
+                // Past the newest match, back to what was typed
+                self.history_browse_index = None;
+                let typed = core::mem::take(&mut self.history_prefix);
+                self.replace_line(&typed);
+            }
+        }
+    }
+
+    /// Typing into a recalled line ends browsing, so the next Up searches
+    /// for whatever the line now starts with
+    fn stop_browsing(&mut self) {
+        self.history_browse_index = None;
+        self.history_prefix.clear();
+    }
+
+    fn load_history_line(&mut self, idx: usize) {
+        // Clear current line
+        self.clear_current_line();
+
+        // Load history entry
+        let entry = self.history[idx].clone();
+        self.set_line(&entry);
+        let len = self.line_len;
+
+        // Display it with the cursor at the end, highlighted while it's an
+        // unedited history entry
+        self.redraw_line();
+        let (row, col) = crate::vga_buffer::cursor_position();
+        if len > 0 && col >= len {
+            crate::vga_buffer::invert_region(row, col - len, len);
+            self.highlight = Some((row, col - len, len));
+        }
+    }
+
+    /// Echo the line buffer at the cursor, e.g. after a fresh prompt, and
+    /// put the cursor back where it was in the line
+    fn redraw_line(&self) {
+        print_unmuted!("{}", self.line());
+        crate::vga_buffer::cursor_back(self.line_len - self.cursor);
+    }
+
+    /// Return a highlighted history line to normal colors
+    fn clear_highlight(&mut self) {
+        if let Some((row, col, len)) = self.highlight.take() {
+            crate::vga_buffer::invert_region(row, col, len);
+        }
+    }
+
+    /// Blank the line on screen, with normal colors, and empty it
+    fn clear_current_line(&mut self) {
+        self.highlight = None;
+        crate::vga_buffer::cursor_back(self.cursor);
+        for _ in 0..self.line_len {
+            print_unmuted!(" ");
+        }
+        crate::vga_buffer::cursor_back(self.line_len);
+        self.line_len = 0;
+        self.cursor = 0;
+    }
+
+    /// Replace the line on screen with `line`, ready for editing
+    fn replace_line(&mut self, line: &str) {
+        self.clear_current_line();
+        self.set_line(line);
+        self.redraw_line();
+    }
+
+    fn start_search(&mut self) {
+        self.clear_highlight();
+        let saved_line = String::from(self.line());
+        self.clear_current_line();
+        self.search = Some(Search { query: String::new(), found: None, saved_line, shown: 0 });
+        self.show_search();
+    }
+
+    /// Most recent entry at or before `from` that contains the query
+    fn find_match(&self, from: usize) -> Option<usize> {
+        let query = &self.search.as_ref()?.query;
+        if query.is_empty() {
+            return None;
+        }
+        let end = (from + 1).min(self.history.len());
+        self.history.range(..end).rposition(|entry| entry.contains(query.as_str()))
+    }
+
+    fn search_push(&mut self, c: char) {
+        let Some(search) = self.search.as_mut() else { return };
+        search.query.push(c);
+        // A longer query can only narrow the match, so keep looking from it
+        let from = search.found.unwrap_or(usize::MAX);
+        let found = self.find_match(from);
+        self.set_match(found);
+    }
+
+    fn search_backspace(&mut self) {
+        let Some(search) = self.search.as_mut() else { return };
+        search.query.pop();
+        let found = self.find_match(usize::MAX);
+        self.set_match(found);
+    }
+
+    /// Step to the next older match; stay put if there is none
+    fn search_older(&mut self) {
+        let found = match self.search.as_ref().and_then(|search| search.found) {
+            Some(0) => return,
+            Some(index) => self.find_match(index - 1),
+            None => self.find_match(usize::MAX),
+        };
+        if found.is_some() {
+            self.set_match(found);
+        }
+    }
+
+    fn set_match(&mut self, found: Option<usize>) {
+        if let Some(search) = self.search.as_mut() {
+            search.found = found;
+        }
+        self.show_search();
+    }
+
+    /// Redraw the search line in place after the prompt
+    fn show_search(&mut self) {
+        let Some(search) = self.search.as_mut() else { return };
+        for _ in 0..search.shown {
+            crate::vga_buffer::backspace();
+        }
+        let candidate = search.found.map_or("", |index| self.history[index].as_str());
+        let text = format!("(reverse-i-search)'{}': {}", search.query, candidate);
+        print_unmuted!("{}", text);
+        search.shown = text.len();
+    }
+
+    /// Leave search mode, putting the match (if `accept`) or the original
+    /// line back on the prompt
+    fn end_search(&mut self, accept: bool) {
+        let Some(search) = self.search.take() else { return };
+        for _ in 0..search.shown {
+            crate::vga_buffer::backspace();
+        }
+        let line = match search.found {
+            Some(index) if accept => self.history[index].clone(),
+            _ => search.saved_line,
+        };
+        self.history_browse_index = None;
+        self.replace_line(&line);
+    }
+}
+
+/// A `!` history reference that doesn't match a stored line
+#[derive(Debug, PartialEq, Eq)]
+enum HistoryError {
+    /// `!!` with nothing in the history
+    Empty,
+    /// `!n` with no nth entry
+    NoEntry(usize),
+    /// A leading `!` followed by something other than `!` or a number
+    BadReference(String),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::Empty => write!(f, "!!: history is empty"),
+            HistoryError::NoEntry(n) => write!(f, "!{}: no such history entry", n),
+            HistoryError::BadReference(reference) => {
+                write!(f, "{}: bad history reference (use !! or !n)", reference)
+            }
+        }
+    }
+}
+
+/// Expand a leading `!!` (previous line) or `!n` (nth line, numbered as
+/// `history` prints them) against `history`. Anything after the reference
+/// is kept, so `!! foo` appends to the previous command. Returns `None` if
+/// the line doesn't start with `!`; any other `!` word is an error rather
+/// than a command name.
+fn expand_history(history: &VecDeque<String>, line: &str) -> Result<Option<String>, HistoryError> {
+    let line = line.trim_start();
+    let end = line.find([' ', '\t']).unwrap_or(line.len());
+    let (reference, rest) = line.split_at(end);
+
+    let entry = if reference == "!!" {
+        history.back().ok_or(HistoryError::Empty)?
+    } else if let Some(Ok(n)) = reference.strip_prefix('!').map(str::parse::<usize>) {
+        n.checked_sub(1)
+            .and_then(|index| history.get(index))
+            .ok_or(HistoryError::NoEntry(n))?
+    } else if reference.starts_with('!') {
+        return Err(HistoryError::BadReference(String::from(reference)));
+    } else {
+        return Ok(None);
+    };
+
+    let mut expanded = entry.clone();
+    expanded.push_str(rest);
+    Ok(Some(expanded))
+}
+
+/// An alias whose expansion starts with another alias. Aliases are only
+/// expanded once, so this is reported rather than followed.
+#[derive(Debug, PartialEq, Eq)]
+struct NestedAlias {
+    name: String,
+    inner: String,
+}
+
+impl fmt::Display for NestedAlias {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "alias {} expands to alias {} (aliases don't nest)", self.name, self.inner)
+    }
+}
+
+/// Slot of the alias called `name`, ignoring ASCII case like command names
+fn alias_index(aliases: &[Option<Alias>], name: &str) -> Option<usize> {
+    aliases.iter().position(|slot| slot.as_ref().is_some_and(|alias| alias.name.eq_ignore_ascii_case(name)))
+}
+
+fn find_alias<'a>(aliases: &'a [Option<Alias>], name: &str) -> Option<&'a Alias> {
+    alias_index(aliases, name).and_then(|index| aliases[index].as_ref())
+}
+
+/// Replace a leading alias name with its expansion, keeping the rest of
+/// the line. Returns `None` if the first word isn't an alias. An alias
+/// may start with its own name (`ls=ls -l`); that runs the command.
+fn expand_alias(aliases: &[Option<Alias>], line: &str) -> Result<Option<String>, NestedAlias> {
+    let line = line.trim_start();
+    let end = line.find([' ', '\t']).unwrap_or(line.len());
+    let (name, rest) = line.split_at(end);
+    let Some(alias) = find_alias(aliases, name) else {
+        return Ok(None);
+    };
+
+    let inner = alias.value.split([' ', '\t']).next().unwrap_or("");
+    if !inner.eq_ignore_ascii_case(&alias.name) && find_alias(aliases, inner).is_some() {
+        return Err(NestedAlias { name: alias.name.clone(), inner: String::from(inner) });
+    }
+    let mut expanded = alias.value.clone();
+    expanded.push_str(rest);
+    Ok(Some(expanded))
+}
+
+/// Expand history references in an entered line, record it and run it
+fn run_line(line: &str) {
+    let mut shell = SHELL.lock();
+    let session = shell.active();
+    let line = match expand_history(&session.history, line) {
+        Ok(Some(expanded)) => {
+            // Show what is actually about to run, unless that shows a password
+            if !runs_lock(&expanded) {
+                println_unmuted!("{}", expanded);
+            }
+            expanded
+        }
+        Ok(None) => String::from(line),
+        Err(err) => {
+            print_error(format_args!("{}", err));
+            session.last_status = STATUS_FAILED;
+            return;
+        }
+    };
+    if !runs_lock(&line) {
+        session.add_to_history(&line);
+    }
+    drop(shell);
+    execute_command(&line);
+}
+
+/// Whether `line` may run `lock` and so hold its password, including
+/// through `repeat`, `bench` and the like
+fn runs_lock(line: &str) -> bool {
+    line.split_whitespace().any(|word| word.eq_ignore_ascii_case("lock"))
+}
+
+fn execute_command(line: &str) {
+    let expanded = expand_alias(&SHELL.lock().aliases, line);
+    let expanded = match expanded {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            print_error(format_args!("{}", err));
+            SHELL.lock().active().last_status = STATUS_FAILED;
+            return;
+        }
+    };
+    let line = expanded.as_deref().unwrap_or(line);
+
+    let mut scratch = String::new();
+    let tokens = match tokenize(line, &mut scratch) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            print_error(format_args!("Syntax error: {}", err));
+            SHELL.lock().active().last_status = STATUS_USAGE;
+            return;
+        }
+    };
+    let parts = tokens.as_slice();
+    if parts.is_empty() {
+        return;
+    }
+
+    let cmd_name = parts[0];
+    let args = &parts[1..];
+
+    let status = match find_command(cmd_name) {
+        Some(cmd) => run_command(cmd, args),
+        None => {
+            match suggest_command(cmd_name) {
+                Some(suggestion) => print_error(format_args!("Unknown command: {}. Did you mean '{}'?", cmd_name, suggestion)),
+                None => print_error(format_args!("Unknown command: {}. Type 'help' for available commands.", cmd_name)),
+            }
+            STATUS_UNKNOWN_COMMAND
+        }
+    };
+    SHELL.lock().active().last_status = status;
+}
+
+/// Run a command and report how it went: errors are printed, and the
+/// status is returned
+fn run_command(cmd: Command, args: &[&str]) -> u8 {
+    match (cmd.func)(args) {
+        Ok(()) => STATUS_OK,
+        Err(err) => {
+            match &err {
+                ShellError::Usage => print_error(format_args!("Usage: {}", cmd.usage)),
+                ShellError::Failed(message) => print_error(format_args!("{}: {}", cmd.name, message)),
+            }
+            err.status()
+        }
+    }
+}
+
+/// Print a line in the error color, even while output is muted
+fn print_error(args: fmt::Arguments) {
+    crate::vga_buffer::print_colored(crate::vga_buffer::Color::LightRed, format_args!("{}\n", args));
+}
+
+/// Longest command name `edit_distance` can measure against
+const MAX_COMMAND_NAME: usize = 16;
+
+/// Edits allowed between a typo and the command it suggests
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// Levenshtein distance between `typed` and `name`, ignoring ASCII case.
+/// Works on bytes with one row of scratch space, so `name` must be at most
+/// `MAX_COMMAND_NAME` bytes; longer names give `usize::MAX`.
+fn edit_distance(typed: &str, name: &str) -> usize {
+    let name = name.as_bytes();
+    if name.len() > MAX_COMMAND_NAME {
+        return usize::MAX;
+    }
+    // row[j]: distance between the typed prefix so far and name[..j]
+    let mut row = [0usize; MAX_COMMAND_NAME + 1];
+    for (j, cell) in row.iter_mut().enumerate() {
+        *cell = j;
+    }
+    for (i, &t) in typed.as_bytes().iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for j in 1..=name.len() {
+            let substitution = diagonal + usize::from(!t.eq_ignore_ascii_case(&name[j - 1]));
+            diagonal = row[j];
+            row[j] = substitution.min(row[j] + 1).min(row[j - 1] + 1);
+        }
+    }
+    row[name.len()]
+}
+
+/// Closest command to a mistyped name, if it is close enough to be a typo:
+/// at most `MAX_SUGGESTION_DISTANCE` edits, and fewer edits than the name
+/// has characters so short names don't match everything
+fn suggest_command(typed: &str) -> Option<&'static str> {
+    all_commands()
+        .map(|cmd| (edit_distance(typed, cmd.name), cmd.name))
+        .filter(|&(distance, _)| distance <= MAX_SUGGESTION_DISTANCE && distance < typed.len())
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, name)| name)
+}
+
+// ============================================================================
+// Command implementations
+// ============================================================================
+
+fn cmd_help(args: &[&str]) -> Result<(), ShellError> {
+    if let Some(name) = args.first() {
+        let Some(cmd) = find_command(name) else {
+            return fail!("no such command: {}", name);
+        };
+        println!("{} - {}", cmd.name, cmd.help);
+        println!("Usage: {}", cmd.usage);
+        return Ok(());
+    }
+    let mut pager = crate::pager::Pager::new();
+    pager.line(format_args!("Available commands:"));
+    for cmd in all_commands() {
+        if !pager.line(format_args!("  {:<12} - {}", cmd.name, cmd.help)) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn cmd_status(_args: &[&str]) -> Result<(), ShellError> {
+    let status = SHELL.lock().active().last_status;
+    let meaning = match status {
+        STATUS_OK => "ok",
+        STATUS_USAGE => "usage error",
+        STATUS_UNKNOWN_COMMAND => "unknown command",
+        _ => "failed",
+    };
+    println!("{} ({})", status, meaning);
+    Ok(())
+}
+
+fn cmd_echo(args: &[&str]) -> Result<(), ShellError> {
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            print!(" ");
+        }
+        print!("{}", arg);
+    }
+    println!("");
+    Ok(())
+}
+
+fn cmd_clear(args: &[&str]) -> Result<(), ShellError> {
+    use crate::vga_buffer::{clear_screen, clear_screen_with, Color};
+
+    let Some(bg) = args.first() else {
+        clear_screen();
+        return Ok(());
+    };
+    let bg = Color::from_name(bg);
+    let fg = match args.get(1) {
+        Some(name) => Color::from_name(name),
+        // Keep text readable on the new background
+        None => bg.map(|bg| match bg {
+            Color::LightGray | Color::LightGreen | Color::LightCyan | Color::Yellow | Color::White => Color::Black,
+            _ => Color::White,
+        }),
+    };
+    let (Some(bg), Some(fg)) = (bg, fg) else {
+        let mut names = String::new();
+        for name in Color::names() {
+            names.push(' ');
+            names.push_str(name);
+        }
+        return fail!("unknown color (colors:{})", names);
+    };
+    clear_screen_with(fg, bg);
+    Ok(())
+}
+
+/// Ask a yes/no question and wait for a key; anything but `y` is no.
+/// Keys typed before the question are discarded so they can't answer it.
+fn confirm(question: &str) -> bool {
+    crate::keyboard::flush();
+    print_unmuted!("{} [y/N] ", question);
+    let answer = loop {
+        if let DecodedKey::Unicode(c) = crate::keyboard::wait_key() {
+            break c;
+        }
+    };
+    println_unmuted!("{}", if answer.is_control() { ' ' } else { answer });
+    matches!(answer, 'y' | 'Y')
+}
+
+/// Whether a command given only `-f` should go ahead, asking `question`
+/// when the flag is missing
+fn forced_or_confirmed(args: &[&str], question: &str) -> bool {
+    args.first() == Some(&"-f") || confirm(question)
+}
+
+fn cmd_reboot(args: &[&str]) -> Result<(), ShellError> {
+    if !forced_or_confirmed(args, "Reboot?") {
+        return Ok(());
+    }
+    println!("Rebooting system...");
+    crate::keyboard::reset_cpu();
+}
+
+fn cmd_shutdown(args: &[&str]) -> Result<(), ShellError> {
+    if !forced_or_confirmed(args, "Power off?") {
+        return Ok(());
+    }
+    println!("Shutting down...");
+    crate::qemu::poweroff();
+}
+
+fn cmd_bind(args: &[&str]) -> Result<(), ShellError> {
+    let mut shell = SHELL.lock();
+    let Some(key) = args.first() else {
+        for (index, binding) in shell.bindings.iter().enumerate() {
+            if let Some(binding) = binding {
+                println!("  F{:<3} {}", index + 1, binding);
+            }
+        }
+        return Ok(());
+    };
+    let Some(index) = parse_function_key(key) else {
+        return fail!("unknown key '{}' (use F1-F12)", key);
+    };
+    // Unquoted words after the key make up the command
+    let rest = &args[1..];
+    shell.bindings[index] = match rest {
+        [] => None,
+        [text] => Some(Binding::parse(text)),
+        _ => Some(Binding::Command(Cow::Owned(rest.join(" ")))),
+    };
+    Ok(())
+}
+
+/// Builtins an alias can't take the name of, so aliases can always be undone
+const UNALIASABLE: [&str; 2] = ["alias", "unalias"];
+
+fn cmd_alias(args: &[&str]) -> Result<(), ShellError> {
+    let mut shell = SHELL.lock();
+    if args.is_empty() {
+        if shell.aliases.iter().all(Option::is_none) {
+            println!("No aliases");
+        }
+        for alias in shell.aliases.iter().flatten() {
+            println!("  {}={}", alias.name, alias.value);
+        }
+        return Ok(());
+    }
+
+    // Unquoted words after the first are part of the expansion
+    let definition = args.join(" ");
+    let Some((name, value)) = definition.split_once('=') else {
+        return Err(ShellError::Usage);
+    };
+    if name.is_empty() || name.len() > MAX_ALIAS_NAME || name.contains(char::is_whitespace) {
+        return fail!("names must be 1-{} characters without spaces", MAX_ALIAS_NAME);
+    }
+    if UNALIASABLE.iter().any(|builtin| builtin.eq_ignore_ascii_case(name)) {
+        return fail!("can't redefine {}", name);
+    }
+    if value.trim().is_empty() || value.len() >= LINE_BUF_LEN {
+        return fail!("the expansion must be 1-{} characters", LINE_BUF_LEN - 1);
+    }
+
+    // Redefining an alias replaces it in place
+    let aliases = &mut shell.aliases;
+    let Some(index) = alias_index(aliases, name).or_else(|| aliases.iter().position(Option::is_none)) else {
+        return fail!("table full ({} aliases)", MAX_ALIASES);
+    };
+    aliases[index] = Some(Alias { name: String::from(name), value: String::from(value) });
+    Ok(())
+}
+
+fn cmd_unalias(args: &[&str]) -> Result<(), ShellError> {
+    let [name] = args else {
+        return Err(ShellError::Usage);
+    };
+    let mut shell = SHELL.lock();
+    let Some(index) = alias_index(&shell.aliases, name) else {
+        return fail!("no such alias: {}", name);
+    };
+    shell.aliases[index] = None;
+    Ok(())
+}
+
+fn cmd_pause(_args: &[&str]) -> Result<(), ShellError> {
+    print!("Press any key to continue...");
+    crate::keyboard::wait_key();
+    println!();
+    Ok(())
+}
+
+fn cmd_watch(args: &[&str]) -> Result<(), ShellError> {
+    use crate::keyboard;
+    use crate::time::{ms_to_ticks, ticks};
+
+    let seconds = args.first().map(|arg| parse_number(arg));
+    let (Some(Ok(seconds)), Some(&name)) = (seconds, args.get(1)) else {
+        return Err(ShellError::Usage);
+    };
+    if seconds == 0 {
+        return fail!("the interval must be at least one second");
+    }
+    let Some(cmd) = find_command(name) else {
+        return fail!("no such command: {}", name);
+    };
+    // A nested watch would never hand control back to this one
+    if cmd.name == "watch" {
+        return fail!("can't watch watch");
+    }
+    // Without timer ticks the wait between runs would never end
+    if keyboard::polling() {
+        return fail!("needs timer interrupts, but input is being polled");
+    }
+
+    let interval = ms_to_ticks(seconds as u64 * 1000);
+    loop {
+        crate::vga_buffer::clear_screen();
+        print!("Every {}s:", seconds);
+        for arg in &args[1..] {
+            print!(" {}", arg);
+        }
+        println!("    (press any key to stop)");
+        println!();
+        run_command(cmd, &args[2..]);
+
+        let next = ticks() + interval;
+        while ticks() < next {
+            if keyboard::get_key().is_some() {
+                return Ok(());
+            }
+            keyboard::idle();
+        }
+    }
+}
+
+fn cmd_bench(args: &[&str]) -> Result<(), ShellError> {
+    use crate::time::{rdtsc, tsc_per_ms};
+
+    let Some((&name, rest)) = args.split_first() else {
+        return Err(ShellError::Usage);
+    };
+    let Some(cmd) = find_command(name) else {
+        return fail!("no such command: {}", name);
+    };
+    // Measure the counter's rate first so it isn't part of the timing. It
+    // waits on timer ticks, which don't come while input is polled.
+    let per_ms = if crate::keyboard::polling() { None } else { tsc_per_ms() };
+
+    let start = rdtsc();
+    let status = run_command(cmd, rest);
+    let cycles = rdtsc().wrapping_sub(start);
+
+    // A counter that doesn't move (or runs backwards) can't time anything
+    if cycles == 0 || cycles > u64::MAX / 2 {
+        println!("{}: {} cycles", cmd.name, cycles);
+        println!("bench: the TSC looks unreliable on this CPU");
+    } else if let Some(per_ms) = per_ms {
+        let us = cycles as u128 * 1000 / per_ms as u128;
+        println!("{}: {} cycles, {} us", cmd.name, cycles, us);
+    } else {
+        println!("{}: {} cycles (TSC rate unknown)", cmd.name, cycles);
+    }
+    if status != STATUS_OK {
+        return fail!("{} failed", cmd.name);
+    }
+    Ok(())
+}
+
+/// Most runs `repeat` will do; more is almost certainly a typo
+const MAX_REPEAT: u32 = 10_000;
+
+fn cmd_repeat(args: &[&str]) -> Result<(), ShellError> {
+    let count = args.first().map(|arg| parse_number(arg));
+    let (Some(Ok(count)), Some(&name)) = (count, args.get(1)) else {
+        return Err(ShellError::Usage);
+    };
+    if count == 0 || count > MAX_REPEAT {
+        return fail!("the count must be 1-{}", MAX_REPEAT);
+    }
+    let Some(cmd) = find_command(name) else {
+        return fail!("no such command: {}", name);
+    };
+    // Repeats of repeats multiply, and there's no telling how far
+    if cmd.name == "repeat" {
+        return fail!("can't repeat repeat");
+    }
+
+    for run in 1..=count {
+        if run_command(cmd, &args[2..]) != STATUS_OK {
+            return fail!("stopped after run {} of {}", run, count);
+        }
+        // Ctrl+C between runs stops early; other keys are dropped
+        while let Some(key) = crate::keyboard::get_key() {
+            if key == DecodedKey::Unicode('\u{3}') {
+                println_unmuted!("^C");
+                return fail!("interrupted after run {} of {}", run, count);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read a line outside the line editor, e.g. for a command that asks for
+/// input. With `hidden` each character echoes as `*`. Backspace works as
+/// usual. Blocks until Enter and returns the length stored in `buf`, or
+/// `None` if Ctrl+C cancelled the line; input that doesn't fit is dropped.
+/// Nothing read here goes into the history.
+fn read_line(buf: &mut [u8], hidden: bool) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let DecodedKey::Unicode(c) = crate::keyboard::wait_key() else { continue };
+        match c {
+            '\n' => {
+                println_unmuted!();
+                return Some(len);
+            }
+            '\u{3}' => {
+                println_unmuted!("^C");
+                return None;
+            }
+            '\u{8}' | '\u{7f}' => {
+                let Ok(typed) = str::from_utf8(&buf[..len]) else { continue };
+                if let Some(last) = typed.chars().next_back() {
+                    len -= last.len_utf8();
+                    crate::vga_buffer::backspace();
+                }
+            }
+            c if c.is_control() => {}
+            c => {
+                let mut utf8 = [0u8; 4];
+                let encoded = c.encode_utf8(&mut utf8).as_bytes();
+                if len + encoded.len() <= buf.len() {
+                    buf[len..len + encoded.len()].copy_from_slice(encoded);
+                    len += encoded.len();
+                    print_unmuted!("{}", if hidden { '*' } else { c });
+                }
+            }
+        }
+    }
+}
+
+/// Prompt for a hidden line; returns it, or `None` if it was cancelled
+fn prompt_hidden(prompt: &str, buf: &mut [u8; LINE_BUF_LEN]) -> Option<String> {
+    crate::keyboard::flush();
+    print_unmuted!("{}", prompt);
+    let len = read_line(buf, true)?;
+    // Only whole characters are ever stored, so this is always UTF-8
+    Some(String::from(str::from_utf8(&buf[..len]).unwrap_or("")))
+}
+
+fn cmd_lock(args: &[&str]) -> Result<(), ShellError> {
+    let mut buf = [0u8; LINE_BUF_LEN];
+    // `run_line` keeps lines holding a password out of the history
+    let password = match args {
+        [] => {
+            let Some(password) = prompt_hidden("New password: ", &mut buf) else {
+                return fail!("cancelled");
+            };
+            match prompt_hidden("Repeat password: ", &mut buf) {
+                Some(repeated) if repeated == password => {}
+                Some(_) => return fail!("passwords don't match"),
+                None => return fail!("cancelled"),
+            }
+            password
+        }
+        [password] => String::from(*password),
+        _ => return Err(ShellError::Usage),
+    };
+    if password.is_empty() {
+        return fail!("empty password");
+    }
+
+    crate::vga_buffer::clear_screen();
+    println_unmuted!("Console locked.");
+    // Ctrl+C only starts the prompt over; there's no way out but the password
+    loop {
+        match prompt_hidden("Password: ", &mut buf) {
+            Some(typed) if typed == password => break,
+            Some(_) => println_unmuted!("Wrong password."),
+            None => {}
+        }
+    }
+    crate::vga_buffer::clear_screen();
+    Ok(())
+}
+
+fn cmd_history(args: &[&str]) -> Result<(), ShellError> {
+    let mut shell = SHELL.lock();
+    let session = shell.active();
+    let count = match args.first() {
+        None => session.history.len(),
+        Some(&"clear") => {
+            session.history.clear();
+            session.history_browse_index = None;
+            return Ok(());
+        }
+        Some(arg) => arg.parse::<usize>().map_err(|_| ShellError::Usage)?,
+    };
+    if session.history.is_empty() {
+        println!("No command history");
+        return Ok(());
+    }
+
+    // Keep each entry's number the same whether or not the list is trimmed
+    let skip = session.history.len().saturating_sub(count);
+    println!("Command history:");
+    for (i, line) in session.history.iter().enumerate().skip(skip) {
+        println!("  {} {}", i + 1, line);
+    }
+    Ok(())
+}
+
+fn cmd_wrap(args: &[&str]) -> Result<(), ShellError> {
+    use crate::vga_buffer::{set_word_wrap, set_wrap, word_wrap_enabled, wrap_enabled};
+
+    match args.first() {
+        Some(&"on") => {
+            set_wrap(true);
+            set_word_wrap(false);
+        }
+        Some(&"word") => {
+            set_wrap(true);
+            set_word_wrap(true);
+        }
+        Some(&"off") => set_wrap(false),
+        None => {
+            let state = match (wrap_enabled(), word_wrap_enabled()) {
+                (false, _) => "off",
+                (true, false) => "on",
+                (true, true) => "word",
+            };
+            println!("wrap is {}", state);
+        }
+        Some(_) => return Err(ShellError::Usage),
+    }
+    Ok(())
+}
+
+fn cmd_ansi(args: &[&str]) -> Result<(), ShellError> {
+    match args.first() {
+        Some(&"on") => crate::vga_buffer::set_ansi(true),
+        Some(&"off") => crate::vga_buffer::set_ansi(false),
+        None => {
+            let state = if crate::vga_buffer::ansi_enabled() { "on" } else { "off" };
+            println!("ansi is {}", state);
+        }
+        Some(_) => return Err(ShellError::Usage),
+    }
+    Ok(())
+}
+
+fn cmd_blink(args: &[&str]) -> Result<(), ShellError> {
+    use crate::vga_buffer::{set_soft_cursor, soft_cursor_enabled};
+
+    match args.first() {
+        Some(&"on") => set_soft_cursor(true),
+        Some(&"off") => {
+            set_soft_cursor(false);
+            update_cursor_shape(SHELL.lock().overwrite);
+        }
+        None => {
+            let state = if soft_cursor_enabled() { "on" } else { "off" };
+            println!("blink is {}", state);
+        }
+        Some(_) => return Err(ShellError::Usage),
+    }
+    Ok(())
+}
+
+fn cmd_palette(args: &[&str]) -> Result<(), ShellError> {
+    use crate::vga_buffer::{find_palette, load_palette, PALETTES};
+
+    match args.first() {
+        Some(name) => match find_palette(name) {
+            Some(palette) => load_palette(&palette.colors),
+            None => return fail!("unknown palette: {}", name),
+        },
+        None => {
+            println!("Available palettes:");
+            for palette in PALETTES {
+                println!("  {}", palette.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_ascii(args: &[&str]) -> Result<(), ShellError> {
+    use crate::vga_buffer::write_glyph;
+
+    // Shown in place of 0x00..0x1F, which would move the cursor instead
+    const PLACEHOLDER: u8 = b'.';
+
+    let first_row = match args.first() {
+        None => 0x2,
+        Some(&"all") => 0x0,
+        Some(_) => return Err(ShellError::Usage),
+    };
+    println!("     0 1 2 3 4 5 6 7 8 9 A B C D E F");
+    for row in first_row..16u8 {
+        print!("  {:X}0 ", row);
+        for col in 0..16u8 {
+            let byte = row << 4 | col;
+            write_glyph(if byte < 0x20 { PLACEHOLDER } else { byte });
+            print!(" ");
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn cmd_colortest(_args: &[&str]) -> Result<(), ShellError> {
+    use crate::vga_buffer::{cursor_position, muted, put_char, Color};
+
+    // Cells are written straight to the screen, so there's nothing to
+    // show (and nothing to scroll past) while muted
+    if muted() {
+        return Ok(());
+    }
+    println!("bg\\fg  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F");
+    for bg in 0..16u8 {
+        print!("  {:X}   ", bg);
+        let (row, col) = cursor_position();
+        for fg in 0..16u8 {
+            let (Some(fg_color), Some(bg_color)) = (Color::from_u8(fg), Color::from_u8(bg)) else {
+                continue;
+            };
+            // The foreground's own digit, so both colors are visible
+            let glyph = b"0123456789ABCDEF"[fg as usize];
+            let cell = col + fg as usize * 3;
+            put_char(row, cell, b' ', fg_color, bg_color);
+            put_char(row, cell + 1, glyph, fg_color, bg_color);
+            put_char(row, cell + 2, b' ', fg_color, bg_color);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn cmd_ls(_args: &[&str]) -> Result<(), ShellError> {
+    let files = crate::ramfs::list();
+    if files.is_empty() {
+        println!("No files");
+    }
+    for file in files {
+        println!("  {:<w$} {:>6}", file.name, file.size, w = crate::constants::ramfs::MAX_NAME_LEN);
+    }
+    Ok(())
+}
+
+fn cmd_cat(args: &[&str]) -> Result<(), ShellError> {
+    let [name] = args else {
+        return Err(ShellError::Usage);
+    };
+    let Some(data) = crate::ramfs::read(name) else {
+        return fail!("no such file: {}", name);
+    };
+    crate::pager::print_paged(String::from_utf8_lossy(&data).lines());
+    Ok(())
+}
+
+fn cmd_write(args: &[&str]) -> Result<(), ShellError> {
+    let Some((name, words)) = args.split_first() else {
+        return Err(ShellError::Usage);
+    };
+    // Stored as a line of text, like `echo` would print it
+    let mut text = words.join(" ");
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    if let Err(err) = crate::ramfs::create(name, text.as_bytes()) {
+        return fail!("{}", err);
+    }
+    Ok(())
+}
+
+fn cmd_edit(args: &[&str]) -> Result<(), ShellError> {
+    use crate::constants::ramfs::MAX_TOTAL_SIZE;
+
+    let [name] = args else {
+        return Err(ShellError::Usage);
+    };
+    // Check before the text is typed, not after
+    if !crate::ramfs::valid_name(name) {
+        return fail!("{}", crate::ramfs::RamfsError::BadName);
+    }
+
+    println_unmuted!("Enter text for {}; a line with only '.' ends it, Ctrl+C cancels", name);
+    crate::keyboard::flush();
+    let mut buf = [0u8; LINE_BUF_LEN];
+    let mut text = String::new();
+    let mut too_big = false;
+    loop {
+        let Some(len) = read_line(&mut buf, false) else {
+            return fail!("cancelled, {} not changed", name);
+        };
+        let line = str::from_utf8(&buf[..len]).unwrap_or("");
+        if line == "." {
+            break;
+        }
+        // Keep reading to the end marker so the rest of the text isn't
+        // taken as commands, but stop storing it
+        if text.len() + line.len() + 1 > MAX_TOTAL_SIZE {
+            too_big = true;
+        }
+        if !too_big {
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    if too_big {
+        return fail!("text is over {} bytes, not saved", MAX_TOTAL_SIZE);
+    }
+    if let Err(err) = crate::ramfs::create(name, text.as_bytes()) {
+        return fail!("{}, not saved", err);
+    }
+    Ok(())
+}
+
+fn cmd_date(_args: &[&str]) -> Result<(), ShellError> {
+    // The RTC is assumed to keep UTC, as QEMU's does by default
+    println!("{} UTC", crate::rtc::now());
+    Ok(())
+}
+
+fn cmd_time(_args: &[&str]) -> Result<(), ShellError> {
+    let now = crate::rtc::now();
+    println!("{:02}:{:02}:{:02}", now.hour, now.minute, now.second);
+    Ok(())
+}
+
+fn cmd_uptime(_args: &[&str]) -> Result<(), ShellError> {
+    let ticks = crate::time::ticks();
+    let seconds = crate::time::uptime_ms() / 1000;
+    let (days, hours) = (seconds / 86_400, seconds / 3600 % 24);
+    let (minutes, seconds) = (seconds / 60 % 60, seconds % 60);
+    println!("up {}d {:02}:{:02}:{:02} ({} ticks)", days, hours, minutes, seconds, ticks);
+    Ok(())
+}
+
+fn cmd_quiet(args: &[&str]) -> Result<(), ShellError> {
+    use crate::vga_buffer::{muted, set_muted, suppressed_lines};
+
+    match args.first() {
+        Some(&"on") => set_muted(true),
+        Some(&"off") => {
+            if muted() {
+                set_muted(false);
+                println!("{} lines suppressed", suppressed_lines());
+            }
+        }
+        None => {
+            let state = if muted() { "on" } else { "off" };
+            println_unmuted!("quiet is {}", state);
+        }
+        Some(_) => return Err(ShellError::Usage),
+    }
+    Ok(())
+}
+
+fn cmd_bp(_args: &[&str]) -> Result<(), ShellError> {
+    x86_64::instructions::interrupts::int3();
+    println!("Returned from the breakpoint handler");
+    Ok(())
+}
+
+fn cmd_panic(_args: &[&str]) -> Result<(), ShellError> {
+    panic!("test panic");
+}
+
+fn cmd_divzero(_args: &[&str]) -> Result<(), ShellError> {
+    // Rust checks for zero divisors before dividing, so the `div` has to
+    // be written out to reach the CPU's divide error
+    unsafe {
+        core::arch::asm!(
+            "div {divisor:e}",
+            divisor = in(reg) 0u32,
+            inout("eax") 1u32 => _,
+            inout("edx") 0u32 => _,
+        );
+    }
+    Ok(())
+}
+
+fn cmd_overflow(_args: &[&str]) -> Result<(), ShellError> {
+    #[allow(unconditional_recursion)]
+    fn recurse(depth: u64) -> u64 {
+        // black_box keeps the frame from being optimised away
+        let frame = core::hint::black_box([depth; 16]);
+        recurse(frame[0] + 1) + frame[15]
+    }
+
+    println!("Recursing until the guard page is hit...");
+    recurse(0);
+    Ok(())
+}
+
+fn cmd_usertest(_args: &[&str]) -> Result<(), ShellError> {
+    use crate::usermode::UserModeError;
+
+    match crate::usermode::run_usertest() {
+        Ok(status) => println!("usertest: exited with status {}", status),
+        Err(UserModeError::Setup(err)) => return fail!("could not map the program: {:?}", err),
+        Err(UserModeError::Fault) => return fail!("program was stopped after a fault"),
+    }
+    Ok(())
+}
+
+fn cmd_kbstat(_args: &[&str]) -> Result<(), ShellError> {
+    let stats = crate::keyboard::stats();
+    println!("Scancodes received: {}", stats.received);
+    println!("Scancodes dropped:  {}", stats.dropped);
+    println!("Keys decoded:       {}", stats.decoded);
+    if crate::keyboard::polling() {
+        println!("Input mode:         polling");
+    } else {
+        println!("Input mode:         interrupts");
+    }
+    Ok(())
+}
+
+fn cmd_mem(_args: &[&str]) -> Result<(), ShellError> {
+    use crate::constants::keyboard::SCANCODE_QUEUE_SIZE;
+    use crate::constants::usermode::USER_STACK_SIZE;
+    use crate::constants::vga::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+    let heap = crate::allocator::stats();
+    println!("Heap:");
+    println!("  total      {}", Kib(heap.total));
+    println!("  allocated  {}", Kib(heap.allocated));
+    println!("  free       {}", Kib(heap.total - heap.allocated));
+    println!("  peak       {}", Kib(heap.peak));
+
+    // Each console keeps a full screen of 2-byte cells
+    let consoles = NUM_CONSOLES * BUFFER_WIDTH * BUFFER_HEIGHT * 2;
+    println!("Static buffers:");
+    println!("  IST stacks        {} x {}", crate::gdt::IST_STACKS, Kib(crate::gdt::STACK_SIZE));
+    println!("  ring-0 stack      {}", Kib(crate::gdt::STACK_SIZE));
+    println!("  user stack        {}", Kib(USER_STACK_SIZE));
+    println!("  console buffers   {}", Kib(consoles));
+    println!("  scancode queue    {}", Kib(SCANCODE_QUEUE_SIZE));
+    Ok(())
+}
+
+fn cmd_regs(_args: &[&str]) -> Result<(), ShellError> {
+    use crate::cpu::{control_registers, Registers};
+
+    let r = Registers::capture();
+    let c = control_registers();
+    let rows: [[(&str, u64); 3]; 7] = [
+        [("RAX", r.rax), ("RBX", r.rbx), ("RCX", r.rcx)],
+        [("RDX", r.rdx), ("RSI", r.rsi), ("RDI", r.rdi)],
+        [("RBP", r.rbp), ("RSP", r.rsp), ("RIP", r.rip)],
+        [("R8", r.r8), ("R9", r.r9), ("R10", r.r10)],
+        [("R11", r.r11), ("R12", r.r12), ("R13", r.r13)],
+        [("R14", r.r14), ("R15", r.r15), ("RFL", r.rflags)],
+        [("CR0", c.cr0), ("CR2", c.cr2), ("CR3", c.cr3)],
+    ];
+    for row in rows {
+        for (name, value) in row {
+            print!("{:<3}={:016x}  ", name, value);
+        }
+        println!();
+    }
+    println!("{:<3}={:016x}", "CR4", c.cr4);
+    Ok(())
+}
+
+/// Access size for `peek` and `poke`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Width {
+    Byte,
+    Word,
+    Dword,
+}
+
+impl Width {
+    fn from_flag(flag: &str) -> Option<Width> {
+        match flag {
+            "-b" => Some(Width::Byte),
+            "-w" => Some(Width::Word),
+            "-d" => Some(Width::Dword),
+            _ => None,
+        }
+    }
+
+    fn bytes(self) -> u64 {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+            Width::Dword => 4,
+        }
+    }
+
+    fn max(self) -> u32 {
+        match self {
+            Width::Byte => u8::MAX.into(),
+            Width::Word => u16::MAX.into(),
+            Width::Dword => u32::MAX,
+        }
+    }
+
+    /// # Safety
+    /// `addr` must be mapped and aligned to the width
+    unsafe fn read(self, addr: u64) -> u32 {
+        match self {
+            Width::Byte => (addr as *const u8).read_volatile().into(),
+            Width::Word => (addr as *const u16).read_volatile().into(),
+            Width::Dword => (addr as *const u32).read_volatile(),
+        }
+    }
+
+    /// # Safety
+    /// `addr` must be mapped writable, aligned to the width, and not hold
+    /// anything the kernel relies on
+    unsafe fn write(self, addr: u64, value: u32) {
+        match self {
+            Width::Byte => (addr as *mut u8).write_volatile(value as u8),
+            Width::Word => (addr as *mut u16).write_volatile(value as u16),
+            Width::Dword => (addr as *mut u32).write_volatile(value),
+        }
+    }
+}
+
+/// Split an optional leading width flag off `peek`/`poke` arguments
+fn width_and_args<'a, 'b>(args: &'a [&'b str]) -> (Width, &'a [&'b str]) {
+    match args.first().and_then(|flag| Width::from_flag(flag)) {
+        Some(width) => (width, &args[1..]),
+        None => (Width::Byte, args),
+    }
+}
+
+/// Check that `width` bytes at `addr` can be accessed without faulting:
+/// not in the null page, aligned, canonical and mapped
+fn check_access(addr: u64, width: Width, write: bool) -> Result<(), &'static str> {
+    if addr < 0x1000 {
+        return Err("refusing to touch the null page");
+    }
+    if !addr.is_multiple_of(width.bytes()) {
+        return Err("address is not aligned to the width");
+    }
+    let virt = x86_64::VirtAddr::try_new(addr).map_err(|_| "address is not canonical")?;
+    if !crate::memory::is_mapped(virt, write) {
+        return Err(if write { "address is not mapped writable" } else { "address is not mapped" });
+    }
+    Ok(())
+}
+
+fn cmd_peek(args: &[&str]) -> Result<(), ShellError> {
+    let (width, args) = width_and_args(args);
+    let [addr] = args else {
+        return Err(ShellError::Usage);
+    };
+    let Ok(addr) = parse_address(addr) else {
+        return fail!("bad address '{}'", addr);
+    };
+    if let Err(err) = check_access(addr, width, false) {
+        return fail!("{}", err);
+    }
+    // Checked above: mapped and aligned
+    let value = unsafe { width.read(addr) };
+    let digits = width.bytes() as usize * 2;
+    println!("{:#018x}: {:#0w$x}", addr, value, w = digits + 2);
+    Ok(())
+}
+
+fn cmd_poke(args: &[&str]) -> Result<(), ShellError> {
+    let (width, args) = width_and_args(args);
+    let [addr, value] = args else {
+        return Err(ShellError::Usage);
+    };
+    let Ok(addr) = parse_address(addr) else {
+        return fail!("bad address '{}'", addr);
+    };
+    let value = match parse_number(value) {
+        Ok(value) if value <= width.max() => value,
+        _ => return fail!("value must be 0-{:#x}", width.max()),
+    };
+    if let Err(err) = check_access(addr, width, true) {
+        return fail!("{}", err);
+    }
+    // Checked above: mapped writable and aligned. What lives there is the
+    // user's responsibility.
+    unsafe { width.write(addr, value) };
+    Ok(())
+}
+
+fn cmd_showkeys(_args: &[&str]) -> Result<(), ShellError> {
+    use pc_keyboard::{KeyCode, KeyState};
+
+    println!("Press keys to see their scancodes and events; Escape quits");
+    loop {
+        let Some((scancode, event, key)) = crate::keyboard::get_key_debug() else {
+            crate::keyboard::idle();
+            continue;
+        };
+        let Some(event) = event else {
+            // A prefix byte; the event comes with the last byte of the key
+            println!("{:02x}", scancode);
+            continue;
+        };
+        match key {
+            Some(key) => println!("{:02x}  {:?} {:?} -> {:?}", scancode, event.code, event.state, key),
+            None => println!("{:02x}  {:?} {:?}", scancode, event.code, event.state),
+        }
+        if event.code == KeyCode::Escape && event.state == KeyState::Down {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn cmd_remap(args: &[&str]) -> Result<(), ShellError> {
+    use crate::keyboard::{clear_remaps, key_from_name, remap, remaps, KeyName};
+
+    match args {
+        [] => {
+            let mut any = false;
+            for (from, to) in remaps() {
+                println!("{} -> {}", KeyName(from), KeyName(to));
+                any = true;
+            }
+            if !any {
+                println!("No keys remapped");
+            }
+        }
+        ["clear"] => clear_remaps(),
+        [from, to] => {
+            let Some(from_key) = key_from_name(from) else {
+                return fail!("unknown key '{}'", from);
+            };
+            let Some(to_key) = key_from_name(to) else {
+                return fail!("unknown key '{}'", to);
+            };
+            if remap(from_key, to_key).is_err() {
+                return fail!("table full ({} keys)", crate::constants::keyboard::MAX_REMAPS);
+            }
+        }
+        _ => return Err(ShellError::Usage),
+    }
+    Ok(())
+}
+
+/// Longest `sleep`; more is almost certainly a typo
+const MAX_SLEEP_MS: u64 = 60 * 60 * 1000;
+
+/// Milliseconds in a duration like `5` (seconds), `5s` or `500ms`
+fn parse_duration(arg: &str) -> Option<u64> {
+    if let Some(ms) = arg.strip_suffix("ms") {
+        return ms.parse().ok();
+    }
+    let secs: u64 = arg.strip_suffix('s').unwrap_or(arg).parse().ok()?;
+    secs.checked_mul(1000)
+}
+
+fn cmd_sleep(args: &[&str]) -> Result<(), ShellError> {
+    let [arg] = args else {
+        return Err(ShellError::Usage);
+    };
+    let Some(ms) = parse_duration(arg) else {
+        return Err(ShellError::Usage);
+    };
+    if ms > MAX_SLEEP_MS {
+        return fail!("at most {} seconds", MAX_SLEEP_MS / 1000);
+    }
+    // Ctrl+C wakes the sleep early; other keys are dropped
+    let finished = crate::time::sleep_ms_unless(ms, || {
+        core::iter::from_fn(crate::keyboard::get_key).any(|key| key == DecodedKey::Unicode('\u{3}'))
+    });
+    if !finished {
+        println_unmuted!("^C");
+        return fail!("interrupted");
+    }
+    Ok(())
+}
+
+fn cmd_beep(args: &[&str]) -> Result<(), ShellError> {
+    let hz = args.first().map_or(Ok(440), |arg| arg.parse::<u32>());
+    let ms = args.get(1).map_or(Ok(200), |arg| arg.parse::<u64>());
+    let (Ok(hz), Ok(ms)) = (hz, ms) else {
+        return Err(ShellError::Usage);
+    };
+
+    if let Err(err) = crate::speaker::on(hz) {
+        return fail!("{:?}", err);
+    }
+    crate::time::sleep_ms(ms);
+    crate::speaker::off();
+    Ok(())
+}
+
+fn cmd_mouse(args: &[&str]) -> Result<(), ShellError> {
+    use crate::mouse::{cursor_visible, set_cursor_visible};
+
+    match args.first() {
+        Some(&"on") => set_cursor_visible(true),
+        Some(&"off") => set_cursor_visible(false),
+        Some(&"events") => show_mouse_events(),
+        None => {
+            let state = if cursor_visible() { "on" } else { "off" };
+            println!("mouse pointer is {}", state);
+        }
+        Some(_) => return Err(ShellError::Usage),
+    }
+    Ok(())
+}
+
+/// Print mouse events as they arrive until Escape is pressed
+fn show_mouse_events() {
+    use pc_keyboard::{KeyCode, KeyState};
+
+    // Skip anything queued before the command started
+    while crate::mouse::poll_event().is_some() {}
+    println!("Move the mouse to see its events; Escape quits");
+    loop {
+        while let Some(event) = crate::mouse::poll_event() {
+            println!("dx={:<4} dy={:<4} buttons={:03b}", event.dx, event.dy, event.buttons);
+        }
+        crate::mouse::update_cursor();
+        match crate::keyboard::get_key_event() {
+            Some(event) if event.code == KeyCode::Escape && event.state == KeyState::Down => break,
+            Some(_) => {}
+            None => crate::keyboard::idle(),
+        }
+    }
+}
+
+fn cmd_kbinfo(_args: &[&str]) -> Result<(), ShellError> {
+    use crate::keyboard;
+
+    let translation = if keyboard::translation() { "on" } else { "off" };
+    println!("Scancode set: {}", keyboard::code_set().number());
+    println!("Translation:  {}", translation);
+    println!("Layout:       {}", keyboard::layout().description());
+    Ok(())
+}
+
+fn cmd_lspci(_args: &[&str]) -> Result<(), ShellError> {
+    let devices = crate::pci::scan();
+    if devices.is_empty() {
+        return fail!("no PCI devices found");
+    }
+    for dev in &devices {
+        print!(
+            "{:02x}:{:02x}.{} {:04x}:{:04x} {:02x}{:02x}",
+            dev.bus, dev.device, dev.function, dev.vendor_id, dev.device_id, dev.class, dev.subclass
+        );
+        if let Some(class) = dev.class_name() {
+            print!(" {}", class);
+        }
+        match dev.vendor_name() {
+            Some(vendor) => println!(" ({})", vendor),
+            None => println!(),
+        }
+    }
+    Ok(())
+}
+
+fn cmd_lsps2(_args: &[&str]) -> Result<(), ShellError> {
+    use crate::ps2::{Channel, DeviceKind};
+
+    let Some(info) = crate::keyboard::controller_info() else {
+        return fail!("no controller information (probing failed or no controller)");
+    };
+    let channels = if info.dual_channel { "dual" } else { "single" };
+    println!("Controller: {} channel", channels);
+    for channel in Channel::ALL {
+        let port = info.port(channel);
+        print!("Port {}: ", channel.number());
+        match port.test {
+            None => {
+                println!("not present");
+                continue;
             }
+            Some(_) if port.passed() => print!("test passed"),
+            Some(code) => {
+                println!("test failed (code {:#04x})", code);
+                continue;
+            }
+        }
+        let Some(id) = port.id else {
+            println!(", no device");
+            continue;
+        };
+        let kind = match id.kind() {
+            DeviceKind::Keyboard => "keyboard",
+            DeviceKind::Mouse => "mouse",
+            DeviceKind::Unknown => "unknown device",
+        };
+        print!(", {} (ID", kind);
+        if id.bytes().is_empty() {
+            print!(" none");
+        }
+        for byte in id.bytes() {
+            print!(" {:02X}", byte);
+        }
+        let in_use = crate::keyboard::present() && crate::keyboard::channel() == channel;
+        println!("){}", if in_use { ", keyboard input" } else { "" });
+    }
+    Ok(())
+}
+
+fn cmd_kbrate(args: &[&str]) -> Result<(), ShellError> {
+    use crate::constants::keyboard::{
+        TYPEMATIC_DELAY_MAX, TYPEMATIC_DELAY_STEP_MS, TYPEMATIC_MAX_CPS, TYPEMATIC_MIN_CPS,
+    };
+    use crate::keyboard::{set_soft_repeat, set_typematic, typematic_delay_code, typematic_rate_code};
+
+    let (Some(delay), Some(rate)) = (args.first(), args.get(1)) else {
+        return Err(ShellError::Usage);
+    };
+    let delay_ms = delay.parse::<u32>().ok();
+    let cps = rate.parse::<u32>().ok();
+    let delay = delay_ms.and_then(typematic_delay_code);
+    let rate = cps.and_then(typematic_rate_code);
+    let (Some(delay_ms), Some(cps), Some(delay), Some(rate)) = (delay_ms, cps, delay, rate) else {
+        let max_delay = (u32::from(TYPEMATIC_DELAY_MAX) + 1) * TYPEMATIC_DELAY_STEP_MS;
+        return fail!(
+            "delay must be {}-{} ms (in {} ms steps), rate {}-{} per second",
+            TYPEMATIC_DELAY_STEP_MS, max_delay, TYPEMATIC_DELAY_STEP_MS, TYPEMATIC_MIN_CPS, TYPEMATIC_MAX_CPS
+        );
+    };
+    // Polling mode repeats keys itself; keep it in step with the keyboard
+    set_soft_repeat(delay_ms, cps);
+    if let Err(err) = set_typematic(delay, rate) {
+        return fail!("{:?}", err);
+    }
+    Ok(())
+}
+
+/// Why `calc` couldn't produce a result
+#[derive(Debug, PartialEq, Eq)]
+enum CalcError {
+    Empty,
+    BadNumber(String),
+    BadOperator(String),
+    /// An operator at the end of the expression
+    MissingOperand,
+    DivisionByZero,
+    Overflow,
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalcError::Empty => write!(f, "empty expression"),
+            CalcError::BadNumber(token) => write!(f, "bad number '{}'", token),
+            CalcError::BadOperator(token) => write!(f, "bad operator '{}' (use + - * /)", token),
+            CalcError::MissingOperand => write!(f, "missing number after the last operator"),
+            CalcError::DivisionByZero => write!(f, "division by zero"),
+            CalcError::Overflow => write!(f, "overflow"),
+        }
+    }
+}
+
+fn parse_operand(token: &str) -> Result<i64, CalcError> {
+    token.parse().map_err(|_| CalcError::BadNumber(String::from(token)))
+}
+
+/// Evaluate `number (op number)*` strictly left to right: there is no
+/// operator precedence, so `3 + 4 * 2` is 14. Division truncates.
+fn eval_expr(tokens: &[&str]) -> Result<i64, CalcError> {
+    let (first, rest) = tokens.split_first().ok_or(CalcError::Empty)?;
+    let mut value = parse_operand(first)?;
+    for pair in rest.chunks(2) {
+        let op = pair[0];
+        if !matches!(op, "+" | "-" | "*" | "/") {
+            return Err(CalcError::BadOperator(String::from(op)));
+        }
+        let operand = parse_operand(pair.get(1).ok_or(CalcError::MissingOperand)?)?;
+        value = match op {
+            "+" => value.checked_add(operand),
+            "-" => value.checked_sub(operand),
+            "*" => value.checked_mul(operand),
+            _ if operand == 0 => return Err(CalcError::DivisionByZero),
+            _ => value.checked_div(operand),
+        }
+        .ok_or(CalcError::Overflow)?;
+    }
+    Ok(value)
+}
+
+fn cmd_calc(args: &[&str]) -> Result<(), ShellError> {
+    match eval_expr(args) {
+        Ok(value) => println!("{}", value),
+        Err(CalcError::Empty) => return Err(ShellError::Usage),
+        Err(err) => return fail!("{}", err),
+    }
+    Ok(())
+}
+
+fn cmd_rand(args: &[&str]) -> Result<(), ShellError> {
+    let (lo, hi) = match args {
+        [] => {
+            println!("{}", crate::rng::next_u64());
+            return Ok(());
+        }
+        [lo, hi] => (lo.parse::<u64>(), hi.parse::<u64>()),
+        _ => return Err(ShellError::Usage),
+    };
+    let (Ok(lo), Ok(hi)) = (lo, hi) else {
+        return fail!("bounds must be non-negative integers");
+    };
+    if lo > hi {
+        return fail!("lo ({}) is greater than hi ({})", lo, hi);
+    }
+    println!("{}", crate::rng::range(lo, hi));
+    Ok(())
+}
+
+fn cmd_uname(args: &[&str]) -> Result<(), ShellError> {
+    use crate::constants::system::{BUILD_TARGET, OS_NAME, VERSION};
+
+    match args {
+        [] => println!("{}", OS_NAME),
+        ["-a"] => {
+            let vendor = crate::cpu::vendor();
+            let vendor = str::from_utf8(&vendor).unwrap_or("unknown");
+            println!("{} {} {} {}", OS_NAME, VERSION, BUILD_TARGET, vendor);
+        }
+        _ => return Err(ShellError::Usage),
+    }
+    Ok(())
+}
+
+fn cmd_version(_args: &[&str]) -> Result<(), ShellError> {
+    println!("{}", crate::constants::system::VERSION);
+    Ok(())
+}
+
+fn cmd_cpuid(args: &[&str]) -> Result<(), ShellError> {
+    use crate::cpu;
+
+    // With a leaf number, dump that leaf's raw registers
+    if let Some(arg) = args.first() {
+        let Ok(leaf) = parse_number(arg) else {
+            return Err(ShellError::Usage);
+        };
+        let Some(r) = cpu::cpuid(leaf) else {
+            return fail!("unsupported leaf {:#x} (max {:#x})", leaf, cpu::max_leaf());
+        };
+        println!("eax={:#010x} ebx={:#010x} ecx={:#010x} edx={:#010x}", r.eax, r.ebx, r.ecx, r.edx);
+        return Ok(());
+    }
+
+    let vendor = cpu::vendor();
+    println!("Vendor:   {}", str::from_utf8(&vendor).unwrap_or("?"));
+    println!("Max leaf: {:#x}", cpu::max_leaf());
+
+    let Some(leaf1) = cpu::cpuid(1) else {
+        println!("Features: unsupported leaf");
+        return Ok(());
+    };
+    print!("Features:");
+    for feature in cpu::FEATURES {
+        if cpu::has_feature(&leaf1, feature) {
+            print!(" {}", feature.name);
         }
     }
+    println!();
+    Ok(())
+}
+
+fn cmd_layout(args: &[&str]) -> Result<(), ShellError> {
+    use crate::layout::Layout;
+
+    let Some(name) = args.first() else {
+        let current = crate::keyboard::layout();
+        for &layout in Layout::ALL {
+            let marker = if layout == current { '*' } else { ' ' };
+            println!("{} {:<8} {}", marker, layout.name(), layout.description());
+        }
+        return Ok(());
+    };
+    let Some(layout) = Layout::from_name(name) else {
+        return fail!("unknown layout '{}' (run 'layout' to list them)", name);
+    };
+    crate::keyboard::set_layout(layout);
+    Ok(())
 }
 
 // ============================================================================
 // Utilities
 // ============================================================================
 
-/// Simple whitespace splitter that returns a fixed array of &str
-fn split_whitespace(s: &str) -> [&str; 8] {
-    let mut out: [&str; 8] = [""; 8];
-    let mut idx = 0usize;
-    let bytes = s.as_bytes();
-    let mut i = 0usize;
+/// Parse a decimal number, or hex with a `0x` prefix
+fn parse_number(s: &str) -> Result<u32, core::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+fn parse_address(s: &str) -> Result<u64, core::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// A byte count shown in KiB with one decimal
+struct Kib(usize);
+
+impl fmt::Display for Kib {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tenths = self.0 * 10 / 1024;
+        write!(f, "{}.{} KiB", tenths / 10, tenths % 10)
+    }
+}
+
+/// One hexdump row: offset, bytes in hex, then printable ASCII
+pub struct HexLine<'a> {
+    pub offset: usize,
+    pub bytes: &'a [u8],
+}
+
+impl fmt::Display for HexLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}: ", self.offset)?;
+        for byte in self.bytes {
+            write!(f, "{:02x} ", byte)?;
+        }
+        write!(f, " ")?;
+        for &byte in self.bytes {
+            let c = if (0x20..0x7f).contains(&byte) { byte as char } else { '.' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maximum number of tokens `tokenize` produces per line
+/// (the command name counts as one)
+const MAX_ARGS: usize = 16;
+
+/// Tokens of one command line, borrowed from the scratch string
+/// `tokenize` wrote them to
+struct Tokens<'a> {
+    parts: [&'a str; MAX_ARGS],
+    /// How many of `parts` are filled in
+    count: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn as_slice(&self) -> &[&'a str] {
+        &self.parts[..self.count]
+    }
+}
+
+/// A command line `tokenize` can't split
+#[derive(Debug, PartialEq, Eq)]
+enum TokenError {
+    /// A `"` or `'` with no closing match; holds the quote
+    UnterminatedQuote(char),
+    /// More than `MAX_ARGS` tokens, command name included
+    TooManyArguments,
+}
 
-    while i < bytes.len() && idx < 8 {
-        // Skip whitespace
-        while i < bytes.len() && (bytes[i] == b' ' || bytes[i] == b'\t') {
-            i += 1;
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenError::UnterminatedQuote(quote) => write!(f, "unterminated {} quote", quote),
+            TokenError::TooManyArguments => write!(f, "too many arguments (max {})", MAX_ARGS - 1),
         }
-        if i >= bytes.len() {
+    }
+}
+
+/// Split a command line into whitespace-separated tokens, shell style:
+///
+/// - `"..."` keeps the whitespace inside it; `\"` and `\\` are the only
+///   escapes there
+/// - `'...'` is taken literally, with no escapes
+/// - outside quotes a backslash escapes a quote, a backslash or whitespace
+///   (`a\ b` is one token); before anything else it is just a backslash
+///
+/// Quoted and unquoted pieces with no space between them make one token,
+/// so `""` is an empty argument. The token text, with quotes and escapes
+/// removed, is written to `scratch` and the tokens borrow from it.
+fn tokenize<'a>(line: &str, scratch: &'a mut String) -> Result<Tokens<'a>, TokenError> {
+    scratch.clear();
+    let mut ends = [0usize; MAX_ARGS];
+    let mut count = 0;
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+        if chars.peek().is_none() {
             break;
         }
-        let start = i;
-        while i < bytes.len() && bytes[i] != b' ' && bytes[i] != b'\t' {
-            i += 1;
+        if count == MAX_ARGS {
+            return Err(TokenError::TooManyArguments);
+        }
+        while let Some(c) = chars.next_if(|&c| c != ' ' && c != '\t') {
+            match c {
+                '"' => loop {
+                    match chars.next() {
+                        None => return Err(TokenError::UnterminatedQuote('"')),
+                        Some('"') => break,
+                        Some('\\') => match chars.next_if(|&c| c == '"' || c == '\\') {
+                            Some(escaped) => scratch.push(escaped),
+                            None => scratch.push('\\'),
+                        },
+                        Some(c) => scratch.push(c),
+                    }
+                },
+                '\'' => loop {
+                    match chars.next() {
+                        None => return Err(TokenError::UnterminatedQuote('\'')),
+                        Some('\'') => break,
+                        Some(c) => scratch.push(c),
+                    }
+                },
+                '\\' => match chars.next_if(|&c| matches!(c, '"' | '\'' | '\\' | ' ' | '\t')) {
+                    Some(escaped) => scratch.push(escaped),
+                    None => scratch.push('\\'),
+                },
+                c => scratch.push(c),
+            }
+        }
+        ends[count] = scratch.len();
+        count += 1;
+    }
+
+    let text: &'a String = scratch;
+    let mut parts = [""; MAX_ARGS];
+    let mut start = 0;
+    for (part, &end) in parts.iter_mut().zip(&ends[..count]) {
+        *part = &text[start..end];
+        start = end;
+    }
+    Ok(Tokens { parts, count })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn split_plain_words() {
+        let mut scratch = String::new();
+        let tokens = tokenize("  echo hello\tworld ", &mut scratch).unwrap();
+        assert_eq!(tokens.as_slice(), &["echo", "hello", "world"]);
+    }
+
+    #[test_case]
+    fn split_quoted_token() {
+        let mut scratch = String::new();
+        let tokens = tokenize("echo \"a   b\" c 'd \\ e' \"\"", &mut scratch).unwrap();
+        assert_eq!(tokens.as_slice(), &["echo", "a   b", "c", "d \\ e", ""]);
+    }
+
+    #[test_case]
+    fn split_backslash_escapes() {
+        let mut scratch = String::new();
+        let tokens = tokenize(r#"a\ b "say \"hi\"" c:\dir \\"#, &mut scratch).unwrap();
+        assert_eq!(tokens.as_slice(), &["a b", "say \"hi\"", "c:\\dir", "\\"]);
+    }
+
+    #[test_case]
+    fn split_unterminated_quote_is_an_error() {
+        let mut scratch = String::new();
+        assert_eq!(tokenize("echo \"a b c", &mut scratch).err(), Some(TokenError::UnterminatedQuote('"')));
+        assert_eq!(tokenize("echo it's", &mut scratch).err(), Some(TokenError::UnterminatedQuote('\'')));
+    }
+
+    #[test_case]
+    fn split_rejects_too_many_arguments() {
+        let mut scratch = String::new();
+        let tokens = tokenize("a b c d e f g h i j k l m n o p", &mut scratch).unwrap();
+        assert_eq!(tokens.count, MAX_ARGS);
+        let result = tokenize("a b c d e f g h i j k l m n o p q", &mut scratch);
+        assert_eq!(result.err(), Some(TokenError::TooManyArguments));
+    }
+
+    #[test_case]
+    fn split_empty_line() {
+        let mut scratch = String::new();
+        assert!(tokenize("   ", &mut scratch).unwrap().as_slice().is_empty());
+    }
+
+    #[test_case]
+    fn set_line_cuts_at_char_boundary() {
+        let mut session = Session::new();
+        // 126 ASCII bytes leave room for one more byte, not all of 'é'
+        let mut line = "a".repeat(LINE_BUF_LEN - 2);
+        line.push('é');
+        session.set_line(&line);
+        assert_eq!(session.line(), &line[..LINE_BUF_LEN - 2]);
+    }
+
+    #[test_case]
+    fn history_recall_matches_the_typed_prefix() {
+        let mut session = Session::new();
+        for line in ["echo one", "date", "echo two", "time"] {
+            session.add_to_history(line);
+        }
+        session.set_line("ec");
+        session.history_prev();
+        assert_eq!(session.line(), "echo two");
+        session.history_prev();
+        assert_eq!(session.line(), "echo one");
+        // No older match: stay put
+        session.history_prev();
+        assert_eq!(session.line(), "echo one");
+        session.history_next();
+        session.history_next();
+        assert_eq!((session.line(), session.history_browse_index), ("ec", None));
+
+        // Editing drops the prefix; an empty line recalls everything
+        session.backspace();
+        session.backspace();
+        session.history_prev();
+        assert_eq!(session.line(), "time");
+    }
+
+    #[test_case]
+    fn reverse_search_matches_substrings() {
+        let mut session = Session::new();
+        for line in ["echo alpha", "date", "echo beta", "time"] {
+            session.add_to_history(line);
+        }
+        let found = |session: &Session| session.search.as_ref().and_then(|search| search.found);
+
+        session.set_line("typed");
+        session.start_search();
+        session.search_push('c');
+        session.search_push('h');
+        assert_eq!(found(&session), Some(2));
+        session.search_older();
+        assert_eq!(found(&session), Some(0));
+        // Nothing older matches, so the match stays
+        session.search_older();
+        assert_eq!(found(&session), Some(0));
+        session.end_search(false);
+        assert_eq!(session.line(), "typed");
+
+        session.start_search();
+        session.search_push('m');
+        session.end_search(true);
+        assert_eq!(session.line(), "time");
+    }
+
+    #[test_case]
+    fn edits_at_the_cursor() {
+        let mut session = Session::new();
+        for c in "hllo".chars() {
+            session.push_char(c);
+        }
+        for _ in 0..3 {
+            session.cursor_left();
+        }
+        session.push_char('e');
+        assert_eq!((session.line(), session.cursor), ("hello", 2));
+
+        session.cursor_right();
+        session.backspace();
+        assert_eq!((session.line(), session.cursor), ("helo", 2));
+
+        // Both ends stop the cursor
+        for _ in 0..5 {
+            session.cursor_left();
+        }
+        session.backspace();
+        assert_eq!((session.line(), session.cursor), ("helo", 0));
+        session.set_line("é");
+        session.cursor_right();
+        assert_eq!(session.cursor, 2);
+
+        session.set_line("abc");
+        session.cursor_left();
+        session.overwrite_char('x');
+        session.overwrite_char('d');
+        assert_eq!((session.line(), session.cursor), ("abxd", 4));
+        session.cursor_home();
+        session.overwrite_char('é');
+        assert_eq!((session.line(), session.cursor), ("ébxd", 2));
+
+        session.set_line("abc");
+        session.delete_char();
+        assert_eq!(session.line(), "abc");
+        session.cursor_home();
+        session.delete_char();
+        assert_eq!((session.line(), session.cursor), ("bc", 0));
+        session.cursor_to_end();
+        assert_eq!(session.cursor, 2);
+        session.clear_current_line();
+    }
+
+    #[test_case]
+    fn word_start_skips_trailing_spaces() {
+        assert_eq!(word_start("echo hello"), 5);
+        assert_eq!(word_start("echo hello  "), 5);
+        assert_eq!(word_start("echo"), 0);
+        assert_eq!(word_start(""), 0);
+    }
+
+    #[test_case]
+    fn function_key_names() {
+        assert_eq!(parse_function_key("F1"), Some(0));
+        assert_eq!(parse_function_key("f12"), Some(11));
+        assert_eq!(parse_function_key("F13"), None);
+        assert_eq!(parse_function_key("F0"), None);
+        assert_eq!(parse_function_key("5"), None);
+    }
+
+    fn history_of(lines: &[&str]) -> VecDeque<String> {
+        lines.iter().map(|&line| String::from(line)).collect()
+    }
+
+    #[test_case]
+    fn expand_bang_bang_and_number() {
+        let history = history_of(&["echo one", "date"]);
+        assert_eq!(expand_history(&history, "!!"), Ok(Some(String::from("date"))));
+        assert_eq!(expand_history(&history, "!1 two"), Ok(Some(String::from("echo one two"))));
+        assert_eq!(expand_history(&history, "echo !!"), Ok(None));
+    }
+
+    #[test_case]
+    fn width_flag_is_optional() {
+        assert_eq!(width_and_args(&["-w", "0xb8000"]), (Width::Word, &["0xb8000"][..]));
+        assert_eq!(width_and_args(&["0xb8000"]), (Width::Byte, &["0xb8000"][..]));
+    }
+
+    #[test_case]
+    fn completes_command_prefixes() {
+        assert_eq!(complete("ech"), (1, "echo"));
+        assert_eq!(complete("EC"), (1, "echo"));
+        assert_eq!(complete("kb").1, "kb");
+        assert!(complete("kb").0 > 1);
+        assert_eq!(complete("zz"), (0, ""));
+    }
+
+    #[test_case]
+    fn edit_distances() {
+        assert_eq!(edit_distance("reboot", "reboot"), 0);
+        assert_eq!(edit_distance("rebot", "reboot"), 1);
+        assert_eq!(edit_distance("ECHO", "echo"), 0);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "help"), 4);
+        assert_eq!(suggest_command("rebot"), Some("reboot"));
+        assert_eq!(suggest_command("x"), None);
+    }
+
+    #[test_case]
+    fn lock_lines_are_spotted() {
+        assert!(runs_lock("lock secret"));
+        assert!(runs_lock("repeat 3 LOCK secret"));
+        assert!(!runs_lock("locks"));
+        assert!(!runs_lock("echo hello"));
+    }
+
+    #[test_case]
+    fn find_command_ignores_case() {
+        assert_eq!(find_command("ECHO").map(|cmd| cmd.name), Some("echo"));
+        assert_eq!(find_command("Help").map(|cmd| cmd.name), Some("help"));
+        assert!(find_command("nosuchcommand").is_none());
+    }
+
+    #[test_case]
+    fn register_rejects_duplicates() {
+        fn nothing(_args: &[&str]) -> Result<(), ShellError> {
+            Ok(())
         }
-        let token = &s[start..i];
-        out[idx] = token;
-        idx += 1;
+        let command = |name| Command { name, help: "", usage: "", func: nothing };
+        // A registry of its own, so later tests don't see "regtest"
+        let mut registry = [None; MAX_REGISTERED_COMMANDS];
+        assert_eq!(register_in(&mut registry, command("HELP")), Err(RegisterError::Duplicate));
+        assert_eq!(register_in(&mut registry, command("regtest")), Ok(()));
+        assert_eq!(register_in(&mut registry, command("REGTEST")), Err(RegisterError::Duplicate));
+        assert_eq!(registry.iter().flatten().count(), 1);
+    }
+
+    #[test_case]
+    fn commands_set_the_status() {
+        let status = |line| {
+            execute_command(line);
+            SHELL.lock().active().last_status
+        };
+        assert_eq!(status("rand 5 1"), STATUS_FAILED);
+        assert_eq!(status("rand 5"), STATUS_USAGE);
+        assert_eq!(status("echo \"unterminated"), STATUS_USAGE);
+        assert_eq!(status("nosuchcommand"), STATUS_UNKNOWN_COMMAND);
+        assert_eq!(status("rand 1 5"), STATUS_OK);
+    }
+
+    #[test_case]
+    fn repeat_checks_its_arguments() {
+        assert_eq!(cmd_repeat(&["0", "echo"]), fail!("the count must be 1-{}", MAX_REPEAT));
+        assert_eq!(cmd_repeat(&["3"]), Err(ShellError::Usage));
+        assert_eq!(cmd_repeat(&["2", "repeat", "2", "echo"]), fail!("can't repeat repeat"));
+        assert_eq!(cmd_repeat(&["2", "rand", "1", "5"]), Ok(()));
+    }
+
+    #[test_case]
+    fn sleep_parses_durations() {
+        assert_eq!(parse_duration("5"), Some(5000));
+        assert_eq!(parse_duration("5s"), Some(5000));
+        assert_eq!(parse_duration("500ms"), Some(500));
+        assert_eq!(parse_duration("1.5"), None);
+        assert_eq!(parse_duration("ms"), None);
+        assert_eq!(parse_duration("18446744073709551615"), None);
+        assert_eq!(cmd_sleep(&["3601"]), fail!("at most {} seconds", MAX_SLEEP_MS / 1000));
+        assert_eq!(cmd_sleep(&["2h"]), Err(ShellError::Usage));
+        assert_eq!(cmd_sleep(&["10ms"]), Ok(()));
     }
 
-    out
+    #[test_case]
+    fn aliases_expand_once() {
+        let alias = |name: &str, value: &str| {
+            Some(Alias { name: String::from(name), value: String::from(value) })
+        };
+        let aliases = [alias("ll", "history 5"), alias("ls", "ls -l"), alias("a", "b"), alias("b", "a x")];
+        assert_eq!(expand_alias(&aliases, "LL"), Ok(Some(String::from("history 5"))));
+        assert_eq!(expand_alias(&aliases, " ll clear"), Ok(Some(String::from("history 5 clear"))));
+        assert_eq!(expand_alias(&aliases, "ls"), Ok(Some(String::from("ls -l"))));
+        assert_eq!(expand_alias(&aliases, "echo ll"), Ok(None));
+        let nested = NestedAlias { name: String::from("a"), inner: String::from("b") };
+        assert_eq!(expand_alias(&aliases, "a"), Err(nested));
+    }
+
+    #[test_case]
+    fn calc_left_to_right() {
+        assert_eq!(eval_expr(&["3", "+", "4", "*", "2"]), Ok(14));
+        assert_eq!(eval_expr(&["-7", "/", "2"]), Ok(-3));
+        assert_eq!(eval_expr(&["42"]), Ok(42));
+    }
+
+    #[test_case]
+    fn calc_errors() {
+        assert_eq!(eval_expr(&[]), Err(CalcError::Empty));
+        assert_eq!(eval_expr(&["1", "/", "0"]), Err(CalcError::DivisionByZero));
+        assert_eq!(eval_expr(&["9223372036854775807", "+", "1"]), Err(CalcError::Overflow));
+        assert_eq!(eval_expr(&["1", "+", "x"]), Err(CalcError::BadNumber(String::from("x"))));
+        assert_eq!(eval_expr(&["1", "%", "2"]), Err(CalcError::BadOperator(String::from("%"))));
+        assert_eq!(eval_expr(&["1", "+"]), Err(CalcError::MissingOperand));
+    }
+
+    #[test_case]
+    fn expand_missing_entries() {
+        assert_eq!(expand_history(&history_of(&[]), "!!"), Err(HistoryError::Empty));
+        let history = history_of(&["date"]);
+        assert_eq!(expand_history(&history, "!0"), Err(HistoryError::NoEntry(0)));
+        assert_eq!(expand_history(&history, "!2"), Err(HistoryError::NoEntry(2)));
+        assert_eq!(expand_history(&history, "!"), Err(HistoryError::BadReference(String::from("!"))));
+        assert_eq!(expand_history(&history, "!da"), Err(HistoryError::BadReference(String::from("!da"))));
+    }
 }
@@ -0,0 +1,53 @@
+//! PC speaker driven by PIT channel 2
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use x86_64::instructions::port::Port;
+use crate::constants::pit::{
+    BASE_FREQUENCY, CHANNEL2_PORT, CMD_CHANNEL2_SQUARE, COMMAND_PORT, SPEAKER_GATE_BITS, SPEAKER_PORT,
+};
+
+/// Gate bits of port 0x61 from before the speaker was turned on
+static SAVED_GATE: AtomicU8 = AtomicU8::new(0);
+static SOUNDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeakerError {
+    /// The frequency needs a PIT divisor outside 1..=65535
+    InvalidFrequency(u32),
+}
+
+/// Start a tone of `hz` Hz
+pub fn on(hz: u32) -> Result<(), SpeakerError> {
+    let divisor = BASE_FREQUENCY.checked_div(hz).unwrap_or(0);
+    if divisor == 0 || divisor > u16::MAX as u32 {
+        return Err(SpeakerError::InvalidFrequency(hz));
+    }
+
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel2: Port<u8> = Port::new(CHANNEL2_PORT);
+    let mut control: Port<u8> = Port::new(SPEAKER_PORT);
+    unsafe {
+        command.write(CMD_CHANNEL2_SQUARE);
+        channel2.write(divisor as u8);
+        channel2.write((divisor >> 8) as u8);
+
+        let value = control.read();
+        if !SOUNDING.swap(true, Ordering::Relaxed) {
+            SAVED_GATE.store(value & SPEAKER_GATE_BITS, Ordering::Relaxed);
+        }
+        control.write(value | SPEAKER_GATE_BITS);
+    }
+    Ok(())
+}
+
+/// Stop the tone, putting the gate bits back the way `on` found them
+pub fn off() {
+    if !SOUNDING.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let mut control: Port<u8> = Port::new(SPEAKER_PORT);
+    unsafe {
+        let value = control.read();
+        control.write((value & !SPEAKER_GATE_BITS) | SAVED_GATE.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,39 @@
+//! Status line on the bottom screen row, outside the scroll region
+
+use alloc::format;
+use spin::Mutex;
+use crate::constants::vga::{BUFFER_WIDTH, STATUS_ROW};
+use crate::keyboard::{self, Locks};
+use crate::vga_buffer::{self, Color};
+
+/// Lock state last drawn, so `update` only redraws on a change
+static SHOWN: Mutex<Option<Locks>> = Mutex::new(None);
+
+/// Redraw the status bar if anything on it changed
+pub fn update() {
+    let locks = keyboard::locks();
+    let mut shown = SHOWN.lock();
+    if *shown != Some(locks) {
+        *shown = Some(locks);
+        render(locks);
+    }
+}
+
+/// Redraw unconditionally, e.g. after switching consoles
+pub fn draw() {
+    let locks = keyboard::locks();
+    *SHOWN.lock() = Some(locks);
+    render(locks);
+}
+
+fn render(locks: Locks) {
+    let indicator = |on: bool, name: &'static str| if on { name } else { "    " };
+    let text = format!(
+        "{} {} {} ",
+        indicator(locks.caps, "CAPS"),
+        indicator(locks.num, "NUM "),
+        indicator(locks.scroll, "SCRL"),
+    );
+    vga_buffer::print_right(Some(STATUS_ROW), &text);
+    vga_buffer::set_attr_region(STATUS_ROW, 0, BUFFER_WIDTH, Color::Black, Color::LightGray);
+}
@@ -0,0 +1,115 @@
+//! System tick counter driven by the PIT timer interrupt (IRQ0)
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::constants::pit::{
+    BASE_FREQUENCY, CHANNEL0_PORT, CMD_CHANNEL0_RATE, COMMAND_PORT, IO_WAIT_READS_PER_MS, TICK_HZ,
+    TSC_CALIBRATION_TICKS,
+};
+
+/// Cycle counter for timing short stretches of code; `tsc_per_ms` gives
+/// its rate
+pub use crate::cpu::rdtsc;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Measured TSC rate, or 0 until `tsc_per_ms` has run
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Program PIT channel 0 to interrupt `TICK_HZ` times a second
+pub fn init() {
+    let divisor = (BASE_FREQUENCY / TICK_HZ) as u16;
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel0: Port<u8> = Port::new(CHANNEL0_PORT);
+    unsafe {
+        command.write(CMD_CHANNEL0_RATE);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called from the timer interrupt handler
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Timer interrupts since boot (`TICK_HZ` per second)
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer started, to the nearest tick
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICK_HZ as u64
+}
+
+/// Ticks covering at least `ms` milliseconds
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * TICK_HZ as u64).div_ceil(1000)
+}
+
+/// Halt until `ms` milliseconds have passed
+pub fn sleep_ms(ms: u64) {
+    sleep_ms_unless(ms, || false);
+}
+
+/// Like `sleep_ms`, but checks `cancel` after every interrupt (or every
+/// millisecond when busy-waiting) and stops as soon as it returns true.
+/// Returns false if the sleep was cut short.
+pub fn sleep_ms_unless(ms: u64, mut cancel: impl FnMut() -> bool) -> bool {
+    // With interrupts off, or a timer that never started ticking, `hlt`
+    // would never wake up, so count port 0x80 reads instead. That is only
+    // roughly as long.
+    if !x86_64::instructions::interrupts::are_enabled() || crate::keyboard::polling() {
+        let mut wait_port: Port<u8> = Port::new(0x80);
+        for _ in 0..ms {
+            for _ in 0..IO_WAIT_READS_PER_MS {
+                unsafe { wait_port.read() };
+            }
+            if cancel() {
+                return false;
+            }
+        }
+        return true;
+    }
+    let end = ticks() + ms_to_ticks(ms);
+    while ticks() < end {
+        x86_64::instructions::hlt();
+        if cancel() {
+            return false;
+        }
+    }
+    true
+}
+
+/// Time-stamp counter cycles per millisecond, measured against the PIT the
+/// first time it's asked for (which takes `TSC_CALIBRATION_TICKS` ticks).
+/// Needs the timer interrupt to be running. `None` if the counter didn't
+/// move, as happens on some virtual CPUs, or went backwards.
+pub fn tsc_per_ms() -> Option<u64> {
+    let known = TSC_PER_MS.load(Ordering::Relaxed);
+    if known != 0 {
+        return Some(known);
+    }
+    // Start on a tick boundary so the whole interval is counted
+    let first = ticks();
+    while ticks() == first {
+        x86_64::instructions::hlt();
+    }
+    let start = ticks();
+    let start_tsc = rdtsc();
+    while ticks() < start + TSC_CALIBRATION_TICKS {
+        x86_64::instructions::hlt();
+    }
+    let cycles = rdtsc().wrapping_sub(start_tsc);
+    // A counter that ran backwards wraps to a huge delta
+    if cycles > u64::MAX / 2 {
+        return None;
+    }
+    let per_ms = (cycles as u128 * TICK_HZ as u128 / (TSC_CALIBRATION_TICKS * 1000) as u128) as u64;
+    if per_ms == 0 {
+        return None;
+    }
+    TSC_PER_MS.store(per_ms, Ordering::Relaxed);
+    Some(per_ms)
+}
@@ -0,0 +1,223 @@
+//! Ring 3 support: a tiny user program, the `int 0x80` system call entry,
+//! and the way back into the kernel when the program exits or faults
+
+use core::arch::global_asm;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::FlagUpdateError;
+use x86_64::VirtAddr;
+use crate::constants::usermode::*;
+use crate::print;
+
+#[repr(C, align(4096))]
+struct UserStack([u8; USER_STACK_SIZE]);
+
+static mut USER_STACK: UserStack = UserStack([0; USER_STACK_SIZE]);
+
+/// Kernel stack pointer saved by `usermode_enter`, restored by `usermode_return`
+static mut KERNEL_RSP: u64 = 0;
+
+/// Set while a user program is running
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static EXIT_STATUS: AtomicU64 = AtomicU64::new(0);
+static FAULTED: AtomicBool = AtomicBool::new(false);
+
+// The user program sits alone in its own page(s) so only it has to be made
+// user accessible. It writes a message and exits.
+global_asm!(
+    r#"
+.pushsection .text.usermode, "ax"
+.balign 4096
+.global usertest_start
+usertest_start:
+    mov rax, {sys_write}
+    lea rdi, [rip + usertest_message]
+    mov rsi, usertest_message_end - usertest_message
+    int {vector}
+    mov rax, {sys_exit}
+    mov rdi, 42
+    int {vector}
+    ud2
+usertest_message:
+    .ascii "Hello from ring 3!\n"
+usertest_message_end:
+.balign 4096
+.global usertest_end
+usertest_end:
+.popsection
+"#,
+    sys_write = const SYS_WRITE,
+    sys_exit = const SYS_EXIT,
+    vector = const SYSCALL_VECTOR,
+);
+
+// usermode_enter(entry, user_rsp, user_cs, user_ss) saves the callee-saved
+// registers and flags, then irets into ring 3. usermode_return unwinds to
+// that point from any kernel stack, so `usermode_enter` appears to return.
+//
+// syscall_entry is the `int 0x80` handler: number in RAX, arguments in RDI
+// and RSI, result in RAX. Everything else is preserved.
+global_asm!(
+    r#"
+.pushsection .text
+.global usermode_enter
+usermode_enter:
+    pushfq
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rip + {kernel_rsp}], rsp
+    push rcx
+    push rsi
+    push 0x202
+    push rdx
+    push rdi
+    iretq
+
+.global usermode_return
+usermode_return:
+    mov rsp, [rip + {kernel_rsp}]
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    popfq
+    ret
+
+.global syscall_entry
+syscall_entry:
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    mov rdx, rsi
+    mov rsi, rdi
+    mov rdi, rax
+    sub rsp, 8
+    call {dispatch}
+    add rsp, 8
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    iretq
+.popsection
+"#,
+    kernel_rsp = sym KERNEL_RSP,
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn usertest_start();
+    fn usertest_end();
+    fn usermode_enter(entry: u64, user_rsp: u64, user_cs: u64, user_ss: u64);
+    fn usermode_return() -> !;
+    fn syscall_entry();
+}
+
+#[derive(Debug)]
+pub enum UserModeError {
+    /// The program's pages couldn't be made user accessible
+    Setup(FlagUpdateError),
+    /// The program faulted and was stopped
+    Fault,
+}
+
+/// Address to install in the IDT for the system call vector
+pub fn syscall_entry_addr() -> VirtAddr {
+    VirtAddr::new(syscall_entry as *const () as u64)
+}
+
+/// True while a user program is running (including inside its syscalls)
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Abandon the running user program and resume the kernel where it
+/// entered user mode. Called from fault handlers.
+pub fn abort() -> ! {
+    FAULTED.store(true, Ordering::Relaxed);
+    unsafe { usermode_return() }
+}
+
+/// The code page and the user stack, as (start, end) addresses
+fn user_regions() -> [(u64, u64); 2] {
+    let stack_start = addr_of!(USER_STACK) as u64;
+    [
+        (usertest_start as *const () as u64, usertest_end as *const () as u64),
+        (stack_start, stack_start + USER_STACK_SIZE as u64),
+    ]
+}
+
+/// Run the built-in test program in ring 3; returns its exit status
+pub fn run_usertest() -> Result<u64, UserModeError> {
+    for (start, end) in user_regions() {
+        crate::memory::set_user_accessible(VirtAddr::new(start), end - start)
+            .map_err(UserModeError::Setup)?;
+    }
+
+    let (code_selector, data_selector) = crate::gdt::user_selectors();
+    let stack_top = user_regions()[1].1;
+
+    FAULTED.store(false, Ordering::Relaxed);
+    ACTIVE.store(true, Ordering::Relaxed);
+    unsafe {
+        usermode_enter(
+            usertest_start as *const () as u64,
+            stack_top,
+            code_selector.0 as u64,
+            data_selector.0 as u64,
+        );
+    }
+    ACTIVE.store(false, Ordering::Relaxed);
+
+    if FAULTED.load(Ordering::Relaxed) {
+        Err(UserModeError::Fault)
+    } else {
+        Ok(EXIT_STATUS.load(Ordering::Relaxed))
+    }
+}
+
+/// `len` bytes at `ptr`, if they lie entirely inside user memory
+fn user_bytes(ptr: u64, len: u64) -> Option<&'static [u8]> {
+    let end = ptr.checked_add(len)?;
+    user_regions()
+        .iter()
+        .any(|&(start, region_end)| ptr >= start && end <= region_end)
+        .then(|| unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+extern "C" fn syscall_dispatch(number: u64, arg0: u64, arg1: u64) -> u64 {
+    match number {
+        SYS_WRITE => sys_write(arg0, arg1),
+        SYS_EXIT if active() => {
+            EXIT_STATUS.store(arg0, Ordering::Relaxed);
+            unsafe { usermode_return() }
+        }
+        _ => u64::MAX,
+    }
+}
+
+/// Print `len` bytes of UTF-8 at `ptr`; returns the count or u64::MAX
+fn sys_write(ptr: u64, len: u64) -> u64 {
+    match user_bytes(ptr, len).map(core::str::from_utf8) {
+        Some(Ok(s)) => {
+            print!("{}", s);
+            len
+        }
+        _ => u64::MAX,
+    }
+}
@@ -1,11 +1,12 @@
 use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 use volatile::Volatile;
 use spin::Mutex;
 use lazy_static::lazy_static;
-use crate::constants::vga::{BUFFER_HEIGHT, BUFFER_WIDTH, BUFFER_ADDR};
+use crate::constants::vga::{BUFFER_HEIGHT, BUFFER_WIDTH, BUFFER_ADDR, NUM_CONSOLES};
 
 #[allow(dead_code)]
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Color {
     Black = 0,
@@ -26,41 +27,410 @@ pub enum Color {
     White = 15,
 }
 
+/// VGA colors for the ANSI color numbers 0-7 (SGR 30-37 and 40-47)
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+
+/// Bright variants, for SGR 90-97 and 100-107
+const ANSI_BRIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray,
+    Color::LightRed,
+    Color::LightGreen,
+    Color::Yellow,
+    Color::LightBlue,
+    Color::Pink,
+    Color::LightCyan,
+    Color::White,
+];
+
+/// Names accepted by `Color::from_name`, in attribute order
+const COLOR_NAMES: [(&str, Color); 16] = [
+    ("black", Color::Black),
+    ("blue", Color::Blue),
+    ("green", Color::Green),
+    ("cyan", Color::Cyan),
+    ("red", Color::Red),
+    ("magenta", Color::Magenta),
+    ("brown", Color::Brown),
+    ("lightgray", Color::LightGray),
+    ("darkgray", Color::DarkGray),
+    ("lightblue", Color::LightBlue),
+    ("lightgreen", Color::LightGreen),
+    ("lightcyan", Color::LightCyan),
+    ("lightred", Color::LightRed),
+    ("pink", Color::Pink),
+    ("yellow", Color::Yellow),
+    ("white", Color::White),
+];
+
+impl Color {
+    /// Look up a color by name, ignoring case, e.g. "lightblue"
+    pub fn from_name(name: &str) -> Option<Color> {
+        COLOR_NAMES
+            .iter()
+            .find(|(candidate, _)| candidate.eq_ignore_ascii_case(name))
+            .map(|&(_, color)| color)
+    }
+
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        COLOR_NAMES.iter().map(|&(name, _)| name)
+    }
+
+    /// The color with attribute value `value` (0-15)
+    pub fn from_u8(value: u8) -> Option<Color> {
+        COLOR_NAMES.get(value as usize).map(|&(_, color)| color)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 struct ColorCode(u8);
 
+impl ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
+        ColorCode((background as u8) << 4 | (foreground as u8))
+    }
+
+    /// Swap foreground and background
+    const fn inverted(self) -> ColorCode {
+        ColorCode(self.0.rotate_left(4))
+    }
+
+    const fn with_foreground(self, foreground: Color) -> ColorCode {
+        ColorCode(self.0 & 0xF0 | foreground as u8)
+    }
+
+    const fn with_background(self, background: Color) -> ColorCode {
+        ColorCode((background as u8) << 4 | self.0 & 0x0F)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
-struct ScreenChar {
+pub struct ScreenChar {
     ascii_character: u8,
     color_code: ColorCode,
 }
 
+/// Where the `Writer` sends the cells of the visible console
+pub trait TextBuffer {
+    fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar);
+
+    /// Show the cursor at (`row`, `col`), if the buffer has one
+    fn move_cursor(&mut self, _row: usize, _col: usize) {}
+}
+
+/// The VGA text-mode memory at `BUFFER_ADDR`
 #[repr(transparent)]
-struct Buffer {
+pub struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
-pub struct Writer {
-    pub column_position: usize,
-    pub row_position: usize,
-    pub color_code: ColorCode,
-    pub buffer: &'static mut Buffer,
+impl TextBuffer for &'static mut Buffer {
+    fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.chars[row][col].write(character);
+    }
+
+    /// Program the CRT controller's cursor location registers
+    fn move_cursor(&mut self, row: usize, col: usize) {
+        use x86_64::instructions::port::Port;
+        use crate::constants::vga::{COMMAND_PORT, CURSOR_LOCATION_HIGH, CURSOR_LOCATION_LOW, DATA_PORT};
+
+        let position = (row * BUFFER_WIDTH + col) as u16;
+        let mut index: Port<u8> = Port::new(COMMAND_PORT);
+        let mut data: Port<u8> = Port::new(DATA_PORT);
+        unsafe {
+            index.write(CURSOR_LOCATION_LOW);
+            data.write(position as u8);
+            index.write(CURSOR_LOCATION_HIGH);
+            data.write((position >> 8) as u8);
+        }
+    }
+}
+
+/// Off-screen contents of one virtual console
+///
+/// The cursor fields are only meaningful while the console is parked; the
+/// console currently receiving output keeps its live cursor in `Writer`.
+struct Console {
+    chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    column_position: usize,
+    row_position: usize,
+    color_code: ColorCode,
+    /// Parked along with the cursor so a switch in the middle of a long
+    /// line or an escape sequence doesn't carry it over to the next console
+    overflow: usize,
+    escape: EscapeParser,
+}
+
+impl Console {
+    const fn new(color_code: ColorCode) -> Self {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code,
+        };
+        Console {
+            chars: [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            column_position: 0,
+            row_position: 0,
+            color_code,
+            overflow: 0,
+            escape: EscapeParser::new(),
+        }
+    }
 }
 
-impl Writer {
+pub struct Writer<B: TextBuffer = &'static mut Buffer> {
+    column_position: usize,
+    row_position: usize,
+    color_code: ColorCode,
+    consoles: [Console; NUM_CONSOLES],
+    /// Console mirrored to the VGA buffer
+    active: usize,
+    /// Console receiving output (normally the active one)
+    target: usize,
+    /// Wrap long lines onto the next row; when off the excess is dropped
+    wrap: bool,
+    /// When wrapping, carry a word cut by the right edge over to the next
+    /// row instead of splitting it
+    word_wrap: bool,
+    /// Characters dropped past the right edge since the last newline
+    overflow: usize,
+    /// First and last row (inclusive) that `new_line` scrolls
+    scroll_top: usize,
+    scroll_bottom: usize,
+    escape: EscapeParser,
+    /// Drop output from `print!`; see `set_muted`
+    muted: bool,
+    /// Lines dropped since muting was last turned on
+    suppressed_lines: usize,
+    /// Interpret ANSI escape sequences; when off ESC shows as a block
+    ansi: bool,
+    /// Draw a blinking cursor in the text instead of relying on the
+    /// hardware one; see `blink`
+    soft_cursor: bool,
+    /// Screen cell showing the software cursor. Only the VGA copy is
+    /// changed, so the console's own copy of the cell restores it.
+    blink_drawn: Option<(usize, usize)>,
+    /// Screen cell under the mouse pointer, shown inverted. Like the
+    /// software cursor it only changes the VGA copy; see `visible`.
+    pointer: Option<(usize, usize)>,
+    buffer: B,
+}
+
+/// Maximum number of numeric parameters kept from one CSI sequence
+const MAX_ESCAPE_PARAMS: usize = 4;
+
+/// Bytes after `ESC [` before a sequence is given up on as malformed
+const MAX_ESCAPE_LEN: usize = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    /// Plain text
+    Ground,
+    /// Saw ESC, waiting for `[`
+    Escape,
+    /// Inside `ESC [`, collecting parameters until a final byte
+    Csi,
+}
+
+/// Minimal VT100-style parser for `ESC [ params final` sequences
+#[derive(Clone, Copy)]
+struct EscapeParser {
+    state: EscapeState,
+    params: [u16; MAX_ESCAPE_PARAMS],
+    count: usize,
+    /// Bytes seen since `ESC [`
+    len: usize,
+}
+
+impl EscapeParser {
+    const fn new() -> Self {
+        EscapeParser {
+            state: EscapeState::Ground,
+            params: [0; MAX_ESCAPE_PARAMS],
+            count: 0,
+            len: 0,
+        }
+    }
+
+    /// Parameter `index`, or `default` if it was omitted or zero
+    fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params[..self.count].get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+}
+
+impl<B: TextBuffer> Writer<B> {
+    fn new(buffer: B) -> Self {
+        Writer {
+            column_position: 0,
+            row_position: 0,
+            color_code: DEFAULT_COLOR,
+            consoles: [const { Console::new(DEFAULT_COLOR) }; NUM_CONSOLES],
+            active: 0,
+            target: 0,
+            wrap: true,
+            word_wrap: false,
+            overflow: 0,
+            scroll_top: 0,
+            scroll_bottom: BUFFER_HEIGHT - 1,
+            escape: EscapeParser::new(),
+            muted: false,
+            suppressed_lines: 0,
+            ansi: true,
+            soft_cursor: false,
+            blink_drawn: None,
+            pointer: None,
+            buffer,
+        }
+    }
+
+    /// Store a cell in the target console, mirroring it to VGA if visible
+    fn put(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.consoles[self.target].chars[row][col] = character;
+        if self.target == self.active {
+            self.buffer.write_cell(row, col, self.visible(row, col));
+        }
+    }
+
+    /// What the VGA cell at (`row`, `col`) should show: the active
+    /// console's cell, inverted if the mouse pointer is over it
+    fn visible(&self, row: usize, col: usize) -> ScreenChar {
+        let mut character = self.consoles[self.active].chars[row][col];
+        if self.pointer == Some((row, col)) {
+            character.color_code = character.color_code.inverted();
+        }
+        character
+    }
+
+    /// Move the mouse pointer to `cell`, or remove it with `None`
+    fn set_pointer(&mut self, cell: Option<(usize, usize)>) {
+        let old = core::mem::replace(&mut self.pointer, cell);
+        for (row, col) in old.into_iter().chain(cell) {
+            self.buffer.write_cell(row, col, self.visible(row, col));
+        }
+    }
+
+    fn get(&self, row: usize, col: usize) -> ScreenChar {
+        self.consoles[self.target].chars[row][col]
+    }
+
+    /// Make `index` the console that receives output, parking the cursor
+    /// state of the previous one
+    fn select(&mut self, index: usize) {
+        let current = &mut self.consoles[self.target];
+        current.column_position = self.column_position;
+        current.row_position = self.row_position;
+        current.color_code = self.color_code;
+        current.overflow = self.overflow;
+        current.escape = self.escape;
+
+        self.target = index;
+        let next = &self.consoles[index];
+        self.column_position = next.column_position;
+        self.row_position = next.row_position;
+        self.color_code = next.color_code;
+        self.overflow = next.overflow;
+        self.escape = next.escape;
+    }
+
+    /// Show console `index`, repainting the VGA buffer from its contents.
+    /// Returns false if the index is out of range or already active.
+    pub fn switch_console(&mut self, index: usize) -> bool {
+        if index >= NUM_CONSOLES || index == self.active {
+            return false;
+        }
+        self.select(index);
+        self.active = index;
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                self.buffer.write_cell(row, col, self.visible(row, col));
+            }
+        }
+        // The repaint covered the software cursor
+        self.blink_drawn = None;
+        self.show_cursor();
+        true
+    }
+
+    /// Put the hardware cursor where the next character will go, and move
+    /// the software cursor along if it is showing
+    fn show_cursor(&mut self) {
+        if self.target == self.active {
+            let col = self.column_position.min(BUFFER_WIDTH - 1);
+            self.buffer.move_cursor(self.row_position, col);
+            if self.blink_drawn.is_some_and(|cell| cell != (self.row_position, col)) {
+                self.blink(false);
+                self.blink(true);
+            }
+        }
+    }
+
+    /// Show (`on`) or hide the software cursor by flipping the attribute
+    /// of the cursor cell on screen. Does nothing while it is disabled, or
+    /// while output goes to a background console.
+    fn blink(&mut self, on: bool) {
+        use crate::constants::vga::SOFT_CURSOR_XOR;
+
+        if let Some((row, col)) = self.blink_drawn.take() {
+            self.buffer.write_cell(row, col, self.visible(row, col));
+        }
+        if on && self.soft_cursor && self.target == self.active {
+            let (row, col) = (self.row_position, self.column_position.min(BUFFER_WIDTH - 1));
+            let mut character = self.visible(row, col);
+            character.color_code = ColorCode(character.color_code.0 ^ SOFT_CURSOR_XOR);
+            self.buffer.write_cell(row, col, character);
+            self.blink_drawn = Some((row, col));
+        }
+    }
+
+    /// Move the cursor `n` cells back through the text, up onto earlier
+    /// rows where it wrapped. Characters dropped past the right edge are
+    /// stepped over first. Stops at the top of the screen.
+    pub fn cursor_back(&mut self, n: usize) {
+        let hidden = n.min(self.overflow);
+        self.overflow -= hidden;
+        let n = n - hidden;
+        if n > 0 {
+            let position = (self.row_position * BUFFER_WIDTH + self.column_position).saturating_sub(n);
+            self.row_position = position / BUFFER_WIDTH;
+            self.column_position = position % BUFFER_WIDTH;
+        }
+        self.show_cursor();
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
             b'\r' => { /* ignore carriage return */ }
             _ => {
                 if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
+                    if !self.wrap {
+                        // Drop the rest of the line, but remember how much
+                        // so backspace can account for it
+                        self.overflow += 1;
+                        return;
+                    }
+                    if self.word_wrap && byte != b' ' {
+                        self.wrap_word();
+                    } else {
+                        self.new_line();
+                    }
                 }
                 let row = self.row_position;
                 let col = self.column_position;
-                self.buffer.chars[row][col].write(ScreenChar {
+                self.put(row, col, ScreenChar {
                     ascii_character: byte,
                     color_code: self.color_code,
                 });
@@ -69,20 +439,68 @@ impl Writer {
         }
     }
 
+    /// Start a new line, moving the word the full row ends in along with
+    /// it. A row with no space is one word wider than the screen, so it is
+    /// split where it is.
+    fn wrap_word(&mut self) {
+        let row = self.row_position;
+        let last_space = (0..BUFFER_WIDTH)
+            .rev()
+            .find(|&col| self.get(row, col).ascii_character == b' ');
+        let Some(space) = last_space else {
+            self.new_line();
+            return;
+        };
+        let mut word = [self.get(row, 0); BUFFER_WIDTH];
+        let len = BUFFER_WIDTH - space - 1;
+        for (i, cell) in word[..len].iter_mut().enumerate() {
+            *cell = self.get(row, space + 1 + i);
+        }
+        self.erase(row, space + 1, BUFFER_WIDTH);
+        self.new_line();
+        let row = self.row_position;
+        for (col, &cell) in word[..len].iter().enumerate() {
+            self.put(row, col, cell);
+        }
+        self.column_position = len;
+    }
+
     fn new_line(&mut self) {
-        if self.row_position < BUFFER_HEIGHT - 1 {
-            self.row_position += 1;
-        } else {
-            // Scroll: move everything up
-            for row in 1..BUFFER_HEIGHT {
+        if self.row_position == self.scroll_bottom {
+            // Scroll: move the rows of the region up
+            for row in self.scroll_top + 1..=self.scroll_bottom {
                 for col in 0..BUFFER_WIDTH {
-                    let character = self.buffer.chars[row][col].read();
-                    self.buffer.chars[row - 1][col].write(character);
+                    let character = self.get(row, col);
+                    self.put(row - 1, col, character);
                 }
             }
-            self.clear_row(BUFFER_HEIGHT - 1);
+            self.clear_row(self.scroll_bottom);
+        } else if self.row_position < BUFFER_HEIGHT - 1 {
+            // Inside the region, or outside it where the cursor just moves
+            self.row_position += 1;
         }
         self.column_position = 0;
+        self.overflow = 0;
+    }
+
+    /// Restrict scrolling to rows `top..=bottom`; rows outside the region
+    /// are never moved. Invalid ranges are ignored.
+    pub fn set_scroll_region(&mut self, top: usize, bottom: usize) {
+        if top >= bottom || bottom >= BUFFER_HEIGHT {
+            return;
+        }
+        self.scroll_top = top;
+        self.scroll_bottom = bottom;
+        if self.row_position < top || self.row_position > bottom {
+            self.row_position = top;
+            self.column_position = 0;
+        }
+    }
+
+    /// Scroll the whole screen again
+    pub fn reset_scroll_region(&mut self) {
+        self.scroll_top = 0;
+        self.scroll_bottom = BUFFER_HEIGHT - 1;
     }
 
     fn clear_row(&mut self, row: usize) {
@@ -91,12 +509,16 @@ impl Writer {
             color_code: self.color_code,
         };
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            self.put(row, col, blank);
         }
     }
 
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
+            if self.ansi && (byte == 0x1b || self.escape.state != EscapeState::Ground) {
+                self.escape_byte(byte);
+                continue;
+            }
             match byte {
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
                 _ => self.write_byte(0xfe),
@@ -104,40 +526,269 @@ impl Writer {
         }
     }
 
+    /// Feed one byte of an escape sequence to the parser
+    fn escape_byte(&mut self, byte: u8) {
+        match self.escape.state {
+            EscapeState::Ground => {
+                self.escape.state = EscapeState::Escape;
+            }
+            EscapeState::Escape => {
+                if byte == b'[' {
+                    self.escape.state = EscapeState::Csi;
+                    self.escape.params = [0; MAX_ESCAPE_PARAMS];
+                    self.escape.count = 0;
+                    self.escape.len = 0;
+                } else {
+                    // Not a CSI sequence; drop it
+                    self.escape.state = EscapeState::Ground;
+                }
+            }
+            EscapeState::Csi if self.escape.len == MAX_ESCAPE_LEN => {
+                // Too long to be anything we handle: give up on it, so a
+                // stray ESC can't swallow the text that follows
+                self.escape.state = EscapeState::Ground;
+            }
+            EscapeState::Csi => {
+                self.escape.len += 1;
+                match byte {
+                    b'0'..=b'9' => {
+                        if self.escape.count == 0 {
+                            self.escape.count = 1;
+                        }
+                        if let Some(param) = self.escape.params.get_mut(self.escape.count - 1) {
+                            *param = param.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                        }
+                    }
+                    b';' => {
+                        if self.escape.count == 0 {
+                            self.escape.count = 1;
+                        }
+                        self.escape.count += 1;
+                    }
+                    0x40..=0x7e => {
+                        self.escape.count = self.escape.count.min(MAX_ESCAPE_PARAMS);
+                        self.escape.state = EscapeState::Ground;
+                        self.run_csi(byte);
+                    }
+                    _ => {
+                        // Intermediate bytes are accepted but ignored
+                    }
+                }
+            }
+        }
+    }
+
+    /// Execute a complete CSI sequence ending in `command`
+    fn run_csi(&mut self, command: u8) {
+        match command {
+            // Cursor position, 1-based and clamped to the screen
+            b'H' | b'f' => {
+                let row = self.escape.param(0, 1) as usize;
+                let col = self.escape.param(1, 1) as usize;
+                self.row_position = row.min(BUFFER_HEIGHT) - 1;
+                self.column_position = col.min(BUFFER_WIDTH) - 1;
+                self.overflow = 0;
+            }
+            // Erase in display: 0 = to end, 1 = to cursor, 2 = all
+            b'J' => {
+                let row = self.row_position;
+                let col = self.column_position.min(BUFFER_WIDTH - 1);
+                match self.escape.params[0] {
+                    0 => {
+                        self.erase(row, col, BUFFER_WIDTH);
+                        for r in row + 1..BUFFER_HEIGHT {
+                            self.clear_row(r);
+                        }
+                    }
+                    1 => {
+                        for r in 0..row {
+                            self.clear_row(r);
+                        }
+                        self.erase(row, 0, col + 1);
+                    }
+                    2 => {
+                        for r in 0..BUFFER_HEIGHT {
+                            self.clear_row(r);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // Erase in line: 0 = to end, 1 = to cursor, 2 = whole line
+            b'K' => {
+                let row = self.row_position;
+                let col = self.column_position.min(BUFFER_WIDTH - 1);
+                match self.escape.params[0] {
+                    0 => self.erase(row, col, BUFFER_WIDTH),
+                    1 => self.erase(row, 0, col + 1),
+                    2 => self.clear_row(row),
+                    _ => {}
+                }
+            }
+            b'm' => self.select_graphic_rendition(),
+            // Anything else is consumed without effect
+            _ => {}
+        }
+    }
+
+    /// SGR: apply each color parameter in turn. No parameters means reset.
+    fn select_graphic_rendition(&mut self) {
+        let count = self.escape.count.max(1);
+        for i in 0..count {
+            let param = self.escape.params[i] as usize;
+            self.color_code = match param {
+                0 => DEFAULT_COLOR,
+                30..=37 => self.color_code.with_foreground(ANSI_COLORS[param - 30]),
+                39 => self.color_code.with_foreground(Color::White),
+                40..=47 => self.color_code.with_background(ANSI_COLORS[param - 40]),
+                49 => self.color_code.with_background(Color::Black),
+                90..=97 => self.color_code.with_foreground(ANSI_BRIGHT_COLORS[param - 90]),
+                100..=107 => self.color_code.with_background(ANSI_BRIGHT_COLORS[param - 100]),
+                // Bold, underline, blink and the rest have no effect
+                _ => self.color_code,
+            };
+        }
+    }
+
+    /// Rewrite the colors of `len` cells from (`row`, `col`), clipped to
+    /// the end of the row; the characters are left alone
+    fn recolor_region(&mut self, row: usize, col: usize, len: usize, f: impl Fn(ColorCode) -> ColorCode) {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return;
+        }
+        let end = col.saturating_add(len).min(BUFFER_WIDTH);
+        for c in col..end {
+            let mut character = self.get(row, c);
+            character.color_code = f(character.color_code);
+            self.put(row, c, character);
+        }
+    }
+
+    /// Blank columns `start..end` of `row`
+    fn erase(&mut self, row: usize, start: usize, end: usize) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        for col in start..end {
+            self.put(row, col, blank);
+        }
+    }
+
     // remove last printed character (for backspace)
     pub fn backspace(&mut self) {
-        if self.column_position > 0 {
+        if self.overflow > 0 {
+            // The character being erased was never displayed
+            self.overflow -= 1;
+        } else if self.column_position > 0 {
             self.column_position -= 1;
             let row = self.row_position;
             let col = self.column_position;
-            self.buffer.chars[row][col].write(ScreenChar {
+            self.put(row, col, ScreenChar {
                 ascii_character: b' ',
                 color_code: self.color_code,
             });
         }
+        self.show_cursor();
+    }
+
+    /// Make `fg` on `bg` the colors for all further output, then clear
+    pub fn clear_screen_with(&mut self, fg: Color, bg: Color) {
+        self.color_code = ColorCode::new(fg, bg);
+        self.clear_screen();
     }
 
+    /// Clear the scroll region (the whole screen unless one is set)
     pub fn clear_screen(&mut self) {
-        for row in 0..BUFFER_HEIGHT {
+        for row in self.scroll_top..=self.scroll_bottom {
             self.clear_row(row);
         }
         self.column_position = 0;
-        self.row_position = 0;
+        self.row_position = self.scroll_top;
+        self.overflow = 0;
+        self.show_cursor();
+    }
+
+    /// Write `s` on a single row, padded according to `align`.
+    ///
+    /// With `row == None` the text goes on the current line (starting a new
+    /// one first if the cursor isn't at column 0) and ends with a newline, so
+    /// it scrolls like `println!`. With `Some(row)` that row is overwritten
+    /// and the cursor is left where it was.
+    fn write_aligned(&mut self, row: Option<usize>, s: &str, align: Align) {
+        let len = s.len().min(BUFFER_WIDTH);
+        let pad = match align {
+            Align::Center => (BUFFER_WIDTH - len) / 2,
+            Align::Right => BUFFER_WIDTH - len,
+        };
+
+        let saved = (self.row_position, self.column_position);
+        match row {
+            None => {
+                if self.column_position > 0 {
+                    self.new_line();
+                }
+            }
+            Some(row) => {
+                if row >= BUFFER_HEIGHT {
+                    return;
+                }
+                self.row_position = row;
+                self.column_position = 0;
+            }
+        }
+        self.clear_row(self.row_position);
+
+        self.column_position = pad;
+        self.write_truncated(s);
+
+        match row {
+            None => self.new_line(),
+            Some(_) => (self.row_position, self.column_position) = saved,
+        }
+    }
+
+    /// Write `s`, cutting it short with an ellipsis if it is wider than a row
+    fn write_truncated(&mut self, s: &str) {
+        let limit = if s.len() > BUFFER_WIDTH {
+            BUFFER_WIDTH - ELLIPSIS.len()
+        } else {
+            s.len()
+        };
+        // Byte-wise so a multi-byte character at the cut can't split a &str
+        for byte in s.bytes().take(limit) {
+            match byte {
+                0x20..=0x7e => self.write_byte(byte),
+                _ => self.write_byte(0xfe),
+            }
+        }
+        if limit < s.len() {
+            self.write_string(ELLIPSIS);
+        }
     }
 }
 
+/// Horizontal alignment for `print_centered` / `print_right`
+#[derive(Clone, Copy)]
+enum Align {
+    Center,
+    Right,
+}
+
+/// Marker appended to text that was too wide for the screen
+const ELLIPSIS: &str = "...";
+
+const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::White, Color::Black);
+
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        row_position: 0,
-        color_code: ColorCode((Color::White as u8) | ((Color::Black as u8) << 4)),
-        buffer: unsafe { &mut *(BUFFER_ADDR as *mut Buffer) },
-    });
+    pub static ref WRITER: Mutex<Writer> =
+        Mutex::new(Writer::new(unsafe { &mut *(BUFFER_ADDR as *mut Buffer) }));
 }
 
-impl fmt::Write for Writer {
+impl<B: TextBuffer> fmt::Write for Writer<B> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         self.write_string(s);
+        self.show_cursor();
         Ok(())
     }
 }
@@ -153,17 +804,605 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Like `print!`, but shown even while output is muted. Meant for the
+/// shell's own prompt, echo and errors, and for panics and fatal
+/// exceptions.
+#[macro_export]
+macro_rules! print_unmuted {
+    ($($arg:tt)*) => ($crate::vga_buffer::_print_unmuted(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println_unmuted {
+    () => ($crate::print_unmuted!("\n"));
+    ($($arg:tt)*) => ($crate::print_unmuted!("{}\n", format_args!($($arg)*)));
+}
+
+/// Counts the newlines in output that is being dropped
+struct LineCounter(usize);
+
+impl fmt::Write for LineCounter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0 += s.bytes().filter(|&byte| byte == b'\n').count();
+        Ok(())
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    if writer.muted {
+        let mut counter = LineCounter(0);
+        let _ = counter.write_fmt(args);
+        writer.suppressed_lines += counter.0;
+        return;
+    }
+    writer.write_fmt(args).unwrap();
+}
+
+#[doc(hidden)]
+pub fn _print_unmuted(args: core::fmt::Arguments) {
     use core::fmt::Write;
     WRITER.lock().write_fmt(args).unwrap();
 }
 
+/// Like `print_unmuted!`, but in `fg` on the current background
+pub fn print_colored(fg: Color, args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    let saved = writer.color_code;
+    writer.color_code = saved.with_foreground(fg);
+    writer.write_fmt(args).unwrap();
+    writer.color_code = saved;
+}
+
+/// Write to a console that may be in the background; it keeps the text
+/// and shows it when switched to.
+#[allow(dead_code)]
+pub fn print_to_console(index: usize, args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    if index >= NUM_CONSOLES {
+        return;
+    }
+    let mut writer = WRITER.lock();
+    let previous = writer.target;
+    writer.select(index);
+    writer.write_fmt(args).unwrap();
+    writer.select(previous);
+}
+
+/// Tick the software cursor was last shown solid from; see `reset_blink`
+static BLINK_START: AtomicU64 = AtomicU64::new(0);
+
+/// Called from the timer interrupt: show or hide the software cursor for
+/// the current half of the blink. If the writer is in use the tick is
+/// skipped (taking the lock could deadlock against the code holding it),
+/// and a later one catches up.
+pub fn blink_tick(ticks: u64) {
+    use crate::constants::vga::BLINK_INTERVAL_MS;
+
+    let elapsed = ticks.saturating_sub(BLINK_START.load(Ordering::Relaxed));
+    let on = (elapsed / crate::time::ms_to_ticks(BLINK_INTERVAL_MS)).is_multiple_of(2);
+    if let Some(mut writer) = WRITER.try_lock() {
+        if writer.soft_cursor {
+            writer.blink(on);
+        }
+    }
+}
+
+/// Start the blink over with the cursor shown, so it stays solid while
+/// keys are being typed
+pub fn reset_blink() {
+    BLINK_START.store(crate::time::ticks(), Ordering::Relaxed);
+}
+
+/// Switch between the software cursor and the hardware one. The hardware
+/// cursor is hidden while the software one is on.
+pub fn set_soft_cursor(enabled: bool) {
+    reset_blink();
+    let mut writer = WRITER.lock();
+    writer.soft_cursor = enabled;
+    writer.blink(enabled);
+    drop(writer);
+    if enabled {
+        disable_cursor();
+    }
+}
+
+pub fn soft_cursor_enabled() -> bool {
+    WRITER.lock().soft_cursor
+}
+
+/// Hide the hardware cursor; `enable_cursor` brings it back
+pub fn disable_cursor() {
+    use x86_64::instructions::port::Port;
+    use crate::constants::vga::{COMMAND_PORT, CURSOR_DISABLE, CURSOR_START_REG, DATA_PORT};
+
+    let mut index: Port<u8> = Port::new(COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        index.write(CURSOR_START_REG);
+        let old = data.read();
+        data.write(old | CURSOR_DISABLE);
+    }
+}
+
+/// Show the hardware cursor covering scanlines `start..=end` of the
+/// character cell, e.g. `CURSOR_UNDERLINE` or `CURSOR_BLOCK`
+pub fn enable_cursor(start: u8, end: u8) {
+    use x86_64::instructions::port::Port;
+    use crate::constants::vga::{COMMAND_PORT, CURSOR_DISABLE, CURSOR_END_REG, CURSOR_START_REG, DATA_PORT};
+
+    let mut index: Port<u8> = Port::new(COMMAND_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        // The bits above the scanline fields belong to other settings
+        index.write(CURSOR_START_REG);
+        let old = data.read();
+        data.write(old & 0xC0 & !CURSOR_DISABLE | start);
+        index.write(CURSOR_END_REG);
+        let old = data.read();
+        data.write(old & 0xE0 | end);
+    }
+}
+
+/// A set of RGB values (8 bits per channel) for the 16 text colors
+pub struct Palette {
+    pub name: &'static str,
+    pub colors: [(u8, u8, u8); 16],
+}
+
+/// Built-in palettes for `load_palette`
+pub const PALETTES: &[Palette] = &[
+    Palette {
+        name: "default",
+        colors: [
+            (0x00, 0x00, 0x00), (0x00, 0x00, 0xAA), (0x00, 0xAA, 0x00), (0x00, 0xAA, 0xAA),
+            (0xAA, 0x00, 0x00), (0xAA, 0x00, 0xAA), (0xAA, 0x55, 0x00), (0xAA, 0xAA, 0xAA),
+            (0x55, 0x55, 0x55), (0x55, 0x55, 0xFF), (0x55, 0xFF, 0x55), (0x55, 0xFF, 0xFF),
+            (0xFF, 0x55, 0x55), (0xFF, 0x55, 0xFF), (0xFF, 0xFF, 0x55), (0xFF, 0xFF, 0xFF),
+        ],
+    },
+    Palette {
+        name: "solarized",
+        colors: [
+            (0x00, 0x2B, 0x36), (0x26, 0x8B, 0xD2), (0x85, 0x99, 0x00), (0x2A, 0xA1, 0x98),
+            (0xDC, 0x32, 0x2F), (0xD3, 0x36, 0x82), (0xB5, 0x89, 0x00), (0x93, 0xA1, 0xA1),
+            (0x58, 0x6E, 0x75), (0x6C, 0x71, 0xC4), (0xA8, 0xC0, 0x23), (0x5F, 0xD7, 0xC8),
+            (0xCB, 0x4B, 0x16), (0xE0, 0x70, 0xA8), (0xE0, 0xC0, 0x40), (0xFD, 0xF6, 0xE3),
+        ],
+    },
+    Palette {
+        name: "amber",
+        colors: [
+            (0x00, 0x00, 0x00), (0x60, 0x42, 0x00), (0x60, 0x42, 0x00), (0x70, 0x4D, 0x00),
+            (0x60, 0x42, 0x00), (0x70, 0x4D, 0x00), (0x80, 0x58, 0x00), (0xAA, 0x75, 0x00),
+            (0x55, 0x3A, 0x00), (0xC0, 0x84, 0x00), (0xC0, 0x84, 0x00), (0xD0, 0x8F, 0x00),
+            (0xC0, 0x84, 0x00), (0xD0, 0x8F, 0x00), (0xE0, 0x9A, 0x00), (0xFF, 0xB0, 0x00),
+        ],
+    },
+];
+
+/// Change the RGB value (8 bits per channel) shown for text color `index`
+pub fn set_palette_entry(index: u8, r: u8, g: u8, b: u8) {
+    use x86_64::instructions::port::Port;
+    use crate::constants::vga::{ATTRIBUTE_TO_DAC, DAC_WRITE_INDEX_PORT, DAC_DATA_PORT};
+
+    let Some(&dac_index) = ATTRIBUTE_TO_DAC.get(index as usize) else {
+        return;
+    };
+    let mut index_port: Port<u8> = Port::new(DAC_WRITE_INDEX_PORT);
+    let mut data_port: Port<u8> = Port::new(DAC_DATA_PORT);
+    unsafe {
+        index_port.write(dac_index);
+        // The DAC takes 6 bits per channel
+        data_port.write(r >> 2);
+        data_port.write(g >> 2);
+        data_port.write(b >> 2);
+    }
+}
+
+/// Program all 16 text colors at once
+pub fn load_palette(colors: &[(u8, u8, u8); 16]) {
+    for (index, &(r, g, b)) in colors.iter().enumerate() {
+        set_palette_entry(index as u8, r, g, b);
+    }
+}
+
+/// Look up a built-in palette by name
+pub fn find_palette(name: &str) -> Option<&'static Palette> {
+    PALETTES.iter().find(|palette| palette.name == name)
+}
+
 /// helpers used by the shell
 pub fn clear_screen() {
     WRITER.lock().clear_screen();
 }
 
+pub fn clear_screen_with(fg: Color, bg: Color) {
+    WRITER.lock().clear_screen_with(fg, bg);
+}
+
 pub fn backspace() {
     WRITER.lock().backspace();
 }
+
+/// Step the cursor back `n` cells, e.g. over text echoed after it
+pub fn cursor_back(n: usize) {
+    WRITER.lock().cursor_back(n);
+}
+
+/// Enable or disable wrapping of lines longer than the screen
+pub fn set_wrap(enabled: bool) {
+    WRITER.lock().wrap = enabled;
+}
+
+pub fn wrap_enabled() -> bool {
+    WRITER.lock().wrap
+}
+
+/// Interpret ANSI color and cursor sequences, or print them raw
+pub fn set_ansi(enabled: bool) {
+    WRITER.lock().ansi = enabled;
+}
+
+pub fn ansi_enabled() -> bool {
+    WRITER.lock().ansi
+}
+
+/// Wrap at spaces instead of mid-word (only matters while wrapping is on)
+pub fn set_word_wrap(enabled: bool) {
+    WRITER.lock().word_wrap = enabled;
+}
+
+pub fn word_wrap_enabled() -> bool {
+    WRITER.lock().word_wrap
+}
+
+/// Drop everything printed with `print!`/`println!` until unmuted.
+/// Turning muting on resets the suppressed line count.
+pub fn set_muted(muted: bool) {
+    let mut writer = WRITER.lock();
+    if muted && !writer.muted {
+        writer.suppressed_lines = 0;
+    }
+    writer.muted = muted;
+}
+
+pub fn muted() -> bool {
+    WRITER.lock().muted
+}
+
+/// Lines dropped since muting was last turned on
+pub fn suppressed_lines() -> usize {
+    WRITER.lock().suppressed_lines
+}
+
+/// Swap foreground and background of `len` cells starting at (`row`, `col`)
+pub fn invert_region(row: usize, col: usize, len: usize) {
+    WRITER.lock().recolor_region(row, col, len, ColorCode::inverted);
+}
+
+/// Write `byte` at the cursor as its CP437 glyph, without the substitution
+/// `write_string` does for bytes outside printable ASCII. Control bytes
+/// (newline included) still act as controls.
+pub fn write_glyph(byte: u8) {
+    let mut writer = WRITER.lock();
+    if !writer.muted {
+        writer.write_byte(byte);
+        writer.show_cursor();
+    }
+}
+
+/// Show the mouse pointer over the cell at (`row`, `col`) by inverting
+/// its colors on screen, or remove it with `None`. The text underneath is
+/// left alone and keeps showing through scrolling and console switches.
+pub fn set_pointer(cell: Option<(usize, usize)>) {
+    let cell = cell.filter(|&(row, col)| row < BUFFER_HEIGHT && col < BUFFER_WIDTH);
+    WRITER.lock().set_pointer(cell);
+}
+
+/// Write `byte` in `fg` on `bg` at (`row`, `col`), leaving the cursor and
+/// the current colors alone
+pub fn put_char(row: usize, col: usize, byte: u8, fg: Color, bg: Color) {
+    if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+        return;
+    }
+    let character = ScreenChar {
+        ascii_character: byte,
+        color_code: ColorCode::new(fg, bg),
+    };
+    WRITER.lock().put(row, col, character);
+}
+
+/// Give `len` cells starting at (`row`, `col`) the colors `fg` on `bg`
+pub fn set_attr_region(row: usize, col: usize, len: usize, fg: Color, bg: Color) {
+    let color_code = ColorCode::new(fg, bg);
+    WRITER.lock().recolor_region(row, col, len, |_| color_code);
+}
+
+/// Current cursor position as (row, column)
+pub fn cursor_position() -> (usize, usize) {
+    let writer = WRITER.lock();
+    (writer.row_position, writer.column_position)
+}
+
+/// Size of the text area output scrolls in, as (columns, rows). With a
+/// scroll region set this is the region's height, not the whole screen.
+pub fn dimensions() -> (usize, usize) {
+    let writer = WRITER.lock();
+    (BUFFER_WIDTH, writer.scroll_bottom - writer.scroll_top + 1)
+}
+
+/// Limit scrolling to rows `top..=bottom` (ignored if invalid)
+pub fn set_scroll_region(top: usize, bottom: usize) {
+    WRITER.lock().set_scroll_region(top, bottom);
+}
+
+/// Let the whole screen scroll again
+#[allow(dead_code)]
+pub fn reset_scroll_region() {
+    WRITER.lock().reset_scroll_region();
+}
+
+/// Switch the visible virtual console; false if nothing changed
+pub fn switch_console(index: usize) -> bool {
+    WRITER.lock().switch_console(index)
+}
+
+/// Index of the console currently shown on screen
+pub fn active_console() -> usize {
+    WRITER.lock().active
+}
+
+/// Print `s` centered on `row`, or on the current line if `row` is `None`
+pub fn print_centered(row: Option<usize>, s: &str) {
+    WRITER.lock().write_aligned(row, s, Align::Center);
+}
+
+/// Print `s` right-aligned on `row`, or on the current line if `row` is `None`
+pub fn print_right(row: Option<usize>, s: &str) {
+    WRITER.lock().write_aligned(row, s, Align::Right);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plain in-memory screen standing in for VGA memory
+    struct TestBuffer {
+        chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    }
+
+    impl TextBuffer for TestBuffer {
+        fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+            self.chars[row][col] = character;
+        }
+    }
+
+    const BLANK: ScreenChar = ScreenChar {
+        ascii_character: b' ',
+        color_code: DEFAULT_COLOR,
+    };
+
+    fn writer() -> Writer<TestBuffer> {
+        Writer::new(TestBuffer {
+            chars: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        })
+    }
+
+    fn char_at(writer: &Writer<TestBuffer>, row: usize, col: usize) -> u8 {
+        writer.buffer.chars[row][col].ascii_character
+    }
+
+    #[test_case]
+    fn soft_cursor_follows_and_restores() {
+        use crate::constants::vga::SOFT_CURSOR_XOR;
+
+        let mut writer = writer();
+        writer.soft_cursor = true;
+        writer.write_string("ab");
+        writer.blink(true);
+        let flipped = ColorCode(DEFAULT_COLOR.0 ^ SOFT_CURSOR_XOR);
+        assert_eq!(writer.buffer.chars[0][2].color_code, flipped);
+
+        // Typing moves it along and puts the old cell back
+        writer.write_string("c");
+        writer.show_cursor();
+        assert_eq!(writer.buffer.chars[0][2].color_code, DEFAULT_COLOR);
+        assert_eq!(writer.buffer.chars[0][3].color_code, flipped);
+
+        writer.blink(false);
+        assert_eq!(writer.buffer.chars[0][3], BLANK);
+        assert_eq!(writer.blink_drawn, None);
+    }
+
+    #[test_case]
+    fn pointer_overlays_the_text() {
+        let mut writer = writer();
+        writer.write_string("ab");
+        writer.set_pointer(Some((0, 0)));
+        let inverted = ColorCode(DEFAULT_COLOR.0.rotate_left(4));
+        assert_eq!(writer.buffer.chars[0][0].color_code, inverted);
+        assert_eq!(char_at(&writer, 0, 0), b'a');
+
+        // Text scrolling under the pointer shows through it
+        writer.write_string("\nc");
+        for _ in 0..BUFFER_HEIGHT - 1 {
+            writer.write_byte(b'\n');
+        }
+        assert_eq!(char_at(&writer, 0, 0), b'c');
+        assert_eq!(writer.buffer.chars[0][0].color_code, inverted);
+
+        writer.set_pointer(None);
+        assert_eq!(writer.buffer.chars[0][0].color_code, DEFAULT_COLOR);
+        assert_eq!(char_at(&writer, 0, 0), b'c');
+    }
+
+    #[test_case]
+    fn newline_at_last_row_scrolls() {
+        let mut writer = writer();
+        writer.write_string("top");
+        for _ in 0..BUFFER_HEIGHT - 1 {
+            writer.write_byte(b'\n');
+        }
+        writer.write_string("bottom\n");
+
+        assert_eq!(char_at(&writer, 0, 0), b' ');
+        assert_eq!(char_at(&writer, BUFFER_HEIGHT - 2, 0), b'b');
+        assert_eq!(char_at(&writer, BUFFER_HEIGHT - 1, 0), b' ');
+        assert_eq!((writer.row_position, writer.column_position), (BUFFER_HEIGHT - 1, 0));
+    }
+
+    #[test_case]
+    fn backspace_at_column_zero_does_nothing() {
+        let mut writer = writer();
+        writer.write_string("x\n");
+        writer.backspace();
+
+        assert_eq!(char_at(&writer, 0, 0), b'x');
+        assert_eq!((writer.row_position, writer.column_position), (1, 0));
+    }
+
+    #[test_case]
+    fn word_wrap_moves_cut_word() {
+        let mut writer = writer();
+        writer.word_wrap = true;
+        for _ in 0..BUFFER_WIDTH - 3 {
+            writer.write_byte(b'x');
+        }
+        writer.write_string(" hello");
+
+        assert_eq!(char_at(&writer, 0, BUFFER_WIDTH - 3), b' ');
+        assert_eq!(char_at(&writer, 0, BUFFER_WIDTH - 1), b' ');
+        assert_eq!(char_at(&writer, 1, 0), b'h');
+        assert_eq!(char_at(&writer, 1, 4), b'o');
+        assert_eq!((writer.row_position, writer.column_position), (1, 5));
+    }
+
+    #[test_case]
+    fn word_wrap_splits_overlong_word() {
+        let mut writer = writer();
+        writer.word_wrap = true;
+        for _ in 0..BUFFER_WIDTH + 1 {
+            writer.write_byte(b'x');
+        }
+
+        assert_eq!(char_at(&writer, 0, BUFFER_WIDTH - 1), b'x');
+        assert_eq!((writer.row_position, writer.column_position), (1, 1));
+    }
+
+    #[test_case]
+    fn consoles_keep_their_own_screen() {
+        let mut writer = writer();
+        writer.write_string("one\x1b[");
+        assert!(writer.switch_console(1));
+        // The unfinished sequence on console 0 doesn't eat this text
+        writer.write_string("two");
+        assert_eq!(char_at(&writer, 0, 0), b't');
+        assert_eq!(writer.column_position, 3);
+
+        assert!(writer.switch_console(0));
+        assert!(!writer.switch_console(0));
+        assert_eq!(char_at(&writer, 0, 0), b'o');
+        // Console 0 is still inside its sequence: this ends it
+        writer.write_string("mx");
+        assert_eq!((writer.column_position, char_at(&writer, 0, 3)), (4, b'x'));
+    }
+
+    #[test_case]
+    fn cursor_back_crosses_rows() {
+        let mut writer = writer();
+        writer.write_string("ab\ncd");
+        writer.cursor_back(1);
+        assert_eq!((writer.row_position, writer.column_position), (1, 1));
+
+        let mut writer = self::writer();
+        for _ in 0..BUFFER_WIDTH {
+            writer.write_byte(b'x');
+        }
+        // "z" wraps onto row 1; stepping back over it and "y" ends on row 0
+        writer.cursor_back(1);
+        writer.write_string("yz");
+        writer.cursor_back(2);
+        assert_eq!((writer.row_position, writer.column_position), (0, BUFFER_WIDTH - 1));
+    }
+
+    #[test_case]
+    fn sgr_sets_colors() {
+        let mut writer = writer();
+        writer.write_string("\x1b[31;44mr\x1b[mn\x1b[92mg");
+
+        let red_on_blue = ColorCode::new(Color::Red, Color::Blue);
+        assert_eq!(writer.buffer.chars[0][0], ScreenChar { ascii_character: b'r', color_code: red_on_blue });
+        assert_eq!(writer.buffer.chars[0][1].color_code, DEFAULT_COLOR);
+        assert_eq!(writer.buffer.chars[0][2].color_code, ColorCode::new(Color::LightGreen, Color::Black));
+        assert_eq!(writer.column_position, 3);
+    }
+
+    #[test_case]
+    fn overlong_escape_is_abandoned() {
+        let mut writer = writer();
+        writer.write_string("\x1b[");
+        for _ in 0..MAX_ESCAPE_LEN {
+            writer.write_string("0");
+        }
+        // The byte past the limit ends the sequence; the next one prints
+        writer.write_string("ab");
+
+        assert!(writer.escape.state == EscapeState::Ground);
+        assert_eq!(char_at(&writer, 0, 0), b'b');
+    }
+
+    #[test_case]
+    fn raw_mode_shows_escape() {
+        let mut writer = writer();
+        writer.ansi = false;
+        writer.write_string("\x1b[31m");
+
+        assert_eq!(char_at(&writer, 0, 0), 0xfe);
+        assert_eq!(char_at(&writer, 0, 1), b'[');
+        assert_eq!(writer.color_code, DEFAULT_COLOR);
+    }
+
+    #[test_case]
+    fn non_ascii_bytes_become_blocks() {
+        let mut writer = writer();
+        // 'é' is two bytes of UTF-8, each shown as a block
+        writer.write_string("aé");
+
+        assert_eq!(char_at(&writer, 0, 0), b'a');
+        assert_eq!(char_at(&writer, 0, 1), 0xfe);
+        assert_eq!(char_at(&writer, 0, 2), 0xfe);
+        assert_eq!(writer.column_position, 3);
+    }
+
+    #[test_case]
+    fn clear_screen_blanks_and_homes() {
+        let mut writer = writer();
+        writer.write_string("hello\nworld");
+        writer.clear_screen();
+
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(writer.buffer.chars[row][col], BLANK);
+            }
+        }
+        assert_eq!((writer.row_position, writer.column_position), (0, 0));
+    }
+
+    #[test_case]
+    fn clear_screen_with_colors_later_output() {
+        let mut writer = writer();
+        writer.clear_screen_with(Color::Yellow, Color::Blue);
+        writer.write_string("x");
+
+        let blue_blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: ColorCode::new(Color::Yellow, Color::Blue),
+        };
+        assert_eq!(writer.buffer.chars[5][5], blue_blank);
+        assert_eq!(writer.buffer.chars[0][0].color_code, blue_blank.color_code);
+    }
+}